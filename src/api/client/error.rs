@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+
+// body of an error response from the server, see crate::api::server::AppError
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Problem {
+	#[serde(default, rename = "type")]
+	pub problem_type: Option<String>,
+	#[serde(default)]
+	pub title: Option<String>,
+	#[serde(default)]
+	pub status: Option<u16>,
+	#[serde(default)]
+	pub detail: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+	#[error("server answered {status}: {}", problem_message(.problem))]
+	Problem { status: u16, problem: Problem },
+	#[error("request timed out")]
+	Timeout,
+	#[error("invalid url: {0}")]
+	Url(#[from] url::ParseError),
+	#[error("could not decode response: {0}")]
+	Decode(#[from] serde_json::Error),
+	#[error(transparent)]
+	Http(reqwest::Error),
+	#[error("could not store the outbox: {0}")]
+	Outbox(#[from] std::io::Error),
+	// the message was taken out of the outbox before it was sent
+	#[error("cancelled")]
+	Cancelled,
+}
+
+fn problem_message(problem: &Problem) -> String {
+	match (&problem.title, &problem.detail) {
+		(Some(title), Some(detail)) => format!("{}: {}", title, detail),
+		(Some(x), None) | (None, Some(x)) => x.clone(),
+		(None, None) => "no details".into(),
+	}
+}
+
+impl From<reqwest::Error> for ClientError {
+	fn from(value: reqwest::Error) -> Self {
+		if value.is_timeout() {
+			Self::Timeout
+		} else {
+			Self::Http(value)
+		}
+	}
+}
+
+impl ClientError {
+	// status code of the error response, if the server sent one
+	pub fn status(&self) -> Option<u16> {
+		match self {
+			Self::Problem { status, .. } => Some(*status),
+			Self::Http(e) => e.status().map(|x| x.as_u16()),
+			_ => None,
+		}
+	}
+
+	// errors that can go away by trying again; anything else the server said is final
+	pub(crate) fn is_transient(&self) -> bool {
+		match self {
+			Self::Timeout | Self::Http(_) => true,
+			Self::Problem { status, .. } => {
+				matches!(status, 429 | 502 | 503 | 504)
+			}
+			_ => false,
+		}
+	}
+
+	// reads an error response; bodies that aren't problem details end up as the detail
+	pub(crate) async fn from_response(
+		response: reqwest::Response,
+	) -> Self {
+		let status = response.status().as_u16();
+
+		match response.bytes().await {
+			Ok(body) => Self::Problem {
+				status,
+				problem: serde_json::from_slice(&body).unwrap_or_else(
+					|_| Problem {
+						status: Some(status),
+						detail: Some(
+							String::from_utf8_lossy(&body).into_owned(),
+						)
+						.filter(|x| !x.is_empty()),
+						..Default::default()
+					},
+				),
+			},
+			Err(e) => e.into(),
+		}
+	}
+}
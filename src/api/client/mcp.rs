@@ -0,0 +1,393 @@
+use super::super::server::rpc_id;
+use crate::mcp::service::Service;
+
+use anyhow::{Result, anyhow};
+use eventsource_stream::Eventsource;
+use futures_util::{Stream, StreamExt};
+use rmcp::ServiceExt;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+use tokio::{
+	io::{
+		AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt,
+		BufReader,
+	},
+	sync::{
+		mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel},
+		oneshot,
+	},
+};
+
+// NOTE: the client relays JSON-RPC messages from the proxy to an MCP server without looking into
+// them much. The server is either the crate's own Service running in-process, or one the
+// integrator already has: a subprocess speaking MCP over stdio, or a local server reached over
+// SSE or streamable HTTP. Every transport is driven by one task fed through an McpPipe.
+
+const SESSION_HEADER: &str = "Mcp-Session-Id";
+
+type Reply = oneshot::Sender<Result<String>>;
+
+// JSON-RPC messages for the MCP server, each with where its response goes. Notifications have no
+// response.
+pub(crate) type McpPipe = UnboundedSender<(String, Option<Reply>)>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "transport")]
+pub enum McpServer {
+	// a subprocess speaking MCP on its stdin and stdout
+	#[serde(rename = "stdio")]
+	Stdio {
+		command: String,
+		#[serde(default)]
+		args: Vec<String>,
+		#[serde(default)]
+		env: HashMap<String, String>,
+	},
+	// a server with the older SSE transport, url is the SSE endpoint
+	#[serde(rename = "sse")]
+	Sse { url: url::Url },
+	#[serde(rename = "streamable_http")]
+	StreamableHttp { url: url::Url },
+}
+
+// sends a request and waits for its response
+pub(crate) async fn call(
+	pipe: &McpPipe, command: &str,
+) -> Result<String> {
+	let (s, r) = oneshot::channel();
+	pipe.send((command.to_string(), Some(s)))
+		.map_err(|_| anyhow!("MCP server stopped"))?;
+	r.await.map_err(|_| anyhow!("MCP server stopped"))?
+}
+
+fn notify(pipe: &McpPipe, method: &str) -> Result<()> {
+	pipe.send((
+		serde_json::json!({"jsonrpc": "2.0", "method": method})
+			.to_string(),
+		None,
+	))
+	.map_err(|_| anyhow!("MCP server stopped"))
+}
+
+// MCP sessions open with a handshake before anything else can be called
+async fn initialize(pipe: McpPipe) -> Result<McpPipe> {
+	let request = serde_json::json!({
+		"jsonrpc": "2.0",
+		"id": "initialize",
+		"method": "initialize",
+		"params": rmcp::model::ClientInfo::default(),
+	});
+
+	let response: serde_json::Value = serde_json::from_str(
+		&call(&pipe, &request.to_string()).await?,
+	)?;
+	if let Some(error) = response.get("error") {
+		return Err(anyhow!(
+			"MCP server refused to initialize: {}",
+			error
+		));
+	}
+
+	notify(&pipe, "notifications/initialized")?;
+	Ok(pipe)
+}
+
+// every tool the server offers, following pagination
+pub(crate) async fn list_tools(
+	pipe: &McpPipe,
+) -> Result<Vec<rmcp::model::Tool>> {
+	let mut tools = Vec::new();
+	let mut cursor: Option<String> = None;
+
+	loop {
+		let request = serde_json::json!({
+			"jsonrpc": "2.0",
+			"id": format!("tools/list {}", tools.len()),
+			"method": "tools/list",
+			"params": {"cursor": cursor},
+		});
+
+		let response: serde_json::Value = serde_json::from_str(
+			&call(pipe, &request.to_string()).await?,
+		)?;
+		if let Some(error) = response.get("error") {
+			return Err(anyhow!("could not list tools: {}", error));
+		}
+
+		let result: rmcp::model::ListToolsResult =
+			serde_json::from_value(
+				response.get("result").cloned().unwrap_or_default(),
+			)?;
+		tools.extend(result.tools);
+
+		match result.next_cursor {
+			Some(x) => cursor = Some(x),
+			None => return Ok(tools),
+		}
+	}
+}
+
+pub(crate) async fn start_service(service: Service) -> Result<McpPipe> {
+	let (stdin_r, stdin_w) = tokio::io::simplex(4096);
+	let (stdout_r, stdout_w) = tokio::io::simplex(4096);
+
+	tokio::spawn(async move {
+		if let Ok(service) = service.serve((stdin_r, stdout_w)).await {
+			let _ = service.waiting().await;
+		}
+	});
+
+	initialize(spawn_stream(stdout_r, stdin_w, ())).await
+}
+
+pub(crate) async fn start(server: &McpServer) -> Result<McpPipe> {
+	let pipe = match server {
+		McpServer::Stdio { command, args, env } => {
+			let mut child = tokio::process::Command::new(command)
+				.args(args)
+				.envs(env)
+				.stdin(std::process::Stdio::piped())
+				.stdout(std::process::Stdio::piped())
+				.kill_on_drop(true)
+				.spawn()?;
+
+			let stdin = child.stdin.take().ok_or_else(|| {
+				anyhow!("no stdin for MCP server {}", command)
+			})?;
+			let stdout = child.stdout.take().ok_or_else(|| {
+				anyhow!("no stdout for MCP server {}", command)
+			})?;
+
+			spawn_stream(stdout, stdin, child)
+		}
+		McpServer::Sse { url } => spawn_sse(url.clone()).await?,
+		McpServer::StreamableHttp { url } => spawn_http(url.clone()),
+	};
+
+	initialize(pipe).await
+}
+
+// newline-delimited JSON-RPC over a pair of streams; guard lives as long as the task, which is
+// how a subprocess gets killed once the client is gone
+fn spawn_stream(
+	r: impl AsyncRead + Unpin + Send + 'static,
+	mut w: impl AsyncWrite + Unpin + Send + 'static,
+	guard: impl Send + 'static,
+) -> McpPipe {
+	let (s, mut commands) =
+		unbounded_channel::<(String, Option<Reply>)>();
+	let mut lines = BufReader::new(r).lines();
+
+	// one call at a time; the proxy's tool loop doesn't make more than that anyway
+	tokio::spawn(async move {
+		let _guard = guard;
+
+		while let Some((command, reply)) = commands.recv().await {
+			let wants_reply = reply.is_some();
+			let result: Result<String> = async {
+				w.write_all(command.trim_end().as_bytes()).await?;
+				w.write_all(b"\n").await?;
+				w.flush().await?;
+
+				if !wants_reply {
+					return Ok(String::new());
+				}
+
+				let id = rpc_id(&command)?;
+				while let Some(line) = lines.next_line().await? {
+					if is_response(&line, &id) {
+						return Ok(line);
+					}
+				}
+				Err(anyhow!("MCP server stopped"))
+			}
+			.await;
+
+			if let Some(reply) = reply {
+				let _ = reply.send(result);
+			}
+		}
+	});
+
+	s
+}
+
+// streamable HTTP: every message is POSTed, the response comes back as JSON or as an SSE stream
+fn spawn_http(url: url::Url) -> McpPipe {
+	let (s, mut commands) =
+		unbounded_channel::<(String, Option<Reply>)>();
+	let client = reqwest::Client::new();
+
+	tokio::spawn(async move {
+		let mut session: Option<String> = None;
+
+		while let Some((command, reply)) = commands.recv().await {
+			let wants_reply = reply.is_some();
+			let result: Result<String> = async {
+				let mut request = client
+					.post(url.clone())
+					.header(
+						reqwest::header::CONTENT_TYPE,
+						"application/json",
+					)
+					.header(
+						reqwest::header::ACCEPT,
+						"application/json, text/event-stream",
+					)
+					.body(command.clone());
+				if let Some(session) = &session {
+					request = request.header(SESSION_HEADER, session);
+				}
+
+				let response =
+					request.send().await?.error_for_status()?;
+				if let Some(x) = response.headers().get(SESSION_HEADER)
+				{
+					session = Some(x.to_str()?.to_string());
+				}
+
+				if !wants_reply {
+					return Ok(String::new());
+				}
+
+				let id = rpc_id(&command)?;
+				let is_sse = response
+					.headers()
+					.get(reqwest::header::CONTENT_TYPE)
+					.and_then(|x| x.to_str().ok())
+					.is_some_and(|x| {
+						x.starts_with("text/event-stream")
+					});
+
+				if is_sse {
+					let mut events =
+						Box::pin(response.bytes_stream().eventsource());
+					while let Some(event) = events.next().await {
+						let event = event?;
+						if is_response(&event.data, &id) {
+							return Ok(event.data);
+						}
+					}
+					Err(anyhow!("MCP server closed the stream"))
+				} else {
+					Ok(response.text().await?)
+				}
+			}
+			.await;
+
+			if let Some(reply) = reply {
+				let _ = reply.send(result);
+			}
+		}
+	});
+
+	s
+}
+
+// the older SSE transport: the server names an endpoint to POST to in the first event, and
+// answers on the SSE stream
+async fn spawn_sse(url: url::Url) -> Result<McpPipe> {
+	let client = reqwest::Client::new();
+	let response = client
+		.get(url.clone())
+		.header(reqwest::header::ACCEPT, "text/event-stream")
+		.send()
+		.await?
+		.error_for_status()?;
+	let mut events = Box::pin(response.bytes_stream().eventsource());
+
+	let endpoint = loop {
+		match events.next().await {
+			Some(Ok(event)) if event.event == "endpoint" => {
+				break url.join(event.data.trim())?;
+			}
+			Some(Ok(_)) => {}
+			Some(Err(e)) => return Err(e.into()),
+			None => {
+				return Err(anyhow!("MCP server closed the stream"));
+			}
+		}
+	};
+
+	let pending: Arc<Mutex<HashMap<String, Reply>>> =
+		Default::default();
+	tokio::spawn(sse_responses(events, pending.clone()));
+
+	let (s, commands) = unbounded_channel::<(String, Option<Reply>)>();
+	tokio::spawn(sse_requests(client, endpoint, commands, pending));
+	Ok(s)
+}
+
+async fn sse_requests(
+	client: reqwest::Client, endpoint: url::Url,
+	mut commands: UnboundedReceiver<(String, Option<Reply>)>,
+	pending: Arc<Mutex<HashMap<String, Reply>>>,
+) {
+	while let Some((command, reply)) = commands.recv().await {
+		let id = match reply.as_ref().map(|_| rpc_id(&command)) {
+			None => None,
+			Some(Ok(id)) => Some(id),
+			Some(Err(e)) => {
+				if let Some(reply) = reply {
+					let _ = reply.send(Err(e));
+				}
+				continue;
+			}
+		};
+
+		if let (Some(id), Some(reply)) = (&id, reply) {
+			pending.lock().unwrap().insert(id.clone(), reply);
+		}
+
+		let result = client
+			.post(endpoint.clone())
+			.header(reqwest::header::CONTENT_TYPE, "application/json")
+			.body(command)
+			.send()
+			.await
+			.and_then(|x| x.error_for_status());
+
+		if let (Err(e), Some(id)) = (result, id)
+			&& let Some(reply) = pending.lock().unwrap().remove(&id)
+		{
+			let _ = reply.send(Err(e.into()));
+		}
+	}
+}
+
+async fn sse_responses<E>(
+	mut events: impl Stream<
+		Item = std::result::Result<eventsource_stream::Event, E>,
+	> + Unpin
+	+ Send
+	+ 'static,
+	pending: Arc<Mutex<HashMap<String, Reply>>>,
+) {
+	while let Some(Ok(event)) = events.next().await {
+		let Ok(id) = rpc_id(&event.data) else {
+			continue;
+		};
+
+		if is_response(&event.data, &id)
+			&& let Some(reply) = pending.lock().unwrap().remove(&id)
+		{
+			let _ = reply.send(Ok(event.data));
+		}
+	}
+
+	// the stream is gone; nothing pending will be answered
+	for (_, reply) in pending.lock().unwrap().drain() {
+		let _ =
+			reply.send(Err(anyhow!("MCP server closed the stream")));
+	}
+}
+
+// a response to the request with this id, as opposed to a request or notification from the server
+fn is_response(message: &str, id: &str) -> bool {
+	serde_json::from_str::<serde_json::Value>(message)
+		.is_ok_and(|x| x.get("method").is_none())
+		&& rpc_id(message).is_ok_and(|x| x == id)
+}
@@ -1,42 +1,154 @@
 use super::server::{
-	Input, McpResponse, Metrics, Prompt, SearchResults, Status,
+	Confirmation, Input, McpRequest, McpResponse, Prompt,
+	SearchResults, SearchUpload, Status, rpc_id,
 };
 #[cfg(test)]
 use crate::api::server::QueryType;
-use crate::{api::server::Search, mcp::service::Service};
-
-use anyhow::{Result, anyhow};
-use futures_util::StreamExt;
-use reqwest_eventsource::Event;
-use rmcp::ServiceExt;
-use std::{ops::Deref, sync::Arc};
-use tokio::{
-	io::{AsyncReadExt, AsyncWriteExt},
-	sync::mpsc::{
-		UnboundedReceiver, UnboundedSender, unbounded_channel,
-	},
+use crate::{
+	api::server::Search,
+	mcp::{search::documents, service::Service, store::DataStore},
 };
 
-type McpPipe = (UnboundedSender<Vec<u8>>, UnboundedReceiver<Vec<u8>>);
+mod error;
+mod mcp;
+mod outbox;
+mod stream;
+pub use error::{ClientError, Problem};
+pub use mcp::McpServer;
+pub use outbox::{Outbox, OutboxItem, OutboxMessage};
+pub use stream::{PromptEvent, PromptStream};
+
+use anyhow::Result;
+use mcp::McpPipe;
+use outbox::IDEMPOTENCY_KEY;
+use serde::{Serialize, de::DeserializeOwned};
+use std::{sync::Arc, time::Duration};
+use stream::Backoff;
+
+// documents per /search/index request, to stay well under the server's body limit
+const INDEX_BATCH_SIZE: usize = 500;
+
+// NOTE: this is the phone's end of the proxy. The proxy sends the model's tool calls as
+// PromptResponse::McpRequest events on the prompt stream; the client runs them against its MCP
+// server and posts the result to /mcp_response. The caller of prompt only ever sees the other
+// events, see stream::PromptStream.
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+	// for each API call; the prompt stream stays open as long as the server keeps it
+	pub timeout: Duration,
+	pub connect_timeout: Duration,
+	// sent with every API call, e.g. ("Authorization", "Bearer ...")
+	pub headers: Vec<(String, String)>,
+	// bounds of the wait before the prompt stream reconnects
+	pub reconnect_min_delay: Duration,
+	pub reconnect_max_delay: Duration,
+}
+
+impl Default for ClientOptions {
+	fn default() -> Self {
+		Self {
+			timeout: Duration::from_secs(30),
+			connect_timeout: Duration::from_secs(10),
+			headers: Vec::new(),
+			reconnect_min_delay: Duration::from_millis(250),
+			reconnect_max_delay: Duration::from_secs(30),
+		}
+	}
+}
+
+impl ClientOptions {
+	pub fn with_bearer_token(mut self, token: &str) -> Self {
+		self.headers.push((
+			reqwest::header::AUTHORIZATION.to_string(),
+			format!("Bearer {}", token),
+		));
+		self
+	}
+
+	fn backoff(&self) -> Backoff {
+		Backoff {
+			min: self.reconnect_min_delay,
+			max: self.reconnect_max_delay,
+		}
+	}
+
+	fn http_client(&self) -> Result<reqwest::Client> {
+		Ok(self.client_builder()?.timeout(self.timeout).build()?)
+	}
+
+	// a prompt stream is open for as long as the prompt runs, so only a stall between two reads
+	// times it out; the server sends keep-alives well within the timeout
+	fn stream_client(&self) -> Result<reqwest::Client> {
+		Ok(self.client_builder()?.read_timeout(self.timeout).build()?)
+	}
+
+	fn client_builder(&self) -> Result<reqwest::ClientBuilder> {
+		let mut headers = reqwest::header::HeaderMap::new();
+		for (name, value) in &self.headers {
+			headers.insert(
+				reqwest::header::HeaderName::from_bytes(
+					name.as_bytes(),
+				)?,
+				reqwest::header::HeaderValue::from_str(value)?,
+			);
+		}
+
+		Ok(reqwest::Client::builder()
+			.connect_timeout(self.connect_timeout)
+			.default_headers(headers))
+	}
+}
 
 #[derive(Debug, Clone)]
 pub struct Client {
 	base_url: url::Url,
+	http: reqwest::Client,
+	stream: reqwest::Client,
+	backoff: Backoff,
 	#[cfg(test)]
 	#[allow(dead_code)]
 	query_type: Option<QueryType>,
-	mcp: Arc<McpPipe>,
+	mcp: McpPipe,
+	// what the MCP server offers, as it answered tools/list
+	tools: Arc<Vec<rmcp::model::Tool>>,
+	outbox: Option<Outbox>,
 }
 
-pub type SseResult = Result<UnboundedReceiver<Result<Event>>>;
-
 impl Client {
 	pub async fn new(base_url: url::Url) -> Result<Self> {
+		Self::with_service(base_url, Service::default()).await
+	}
+
+	// answers tool calls with the given service, i.e. the app's data store and privacy policy
+	pub async fn with_service(
+		base_url: url::Url, service: Service,
+	) -> Result<Self> {
+		Self::with_pipe(base_url, mcp::start_service(service).await?)
+			.await
+	}
+
+	// answers tool calls with an MCP server of the integrator's own
+	pub async fn with_mcp_server(
+		base_url: url::Url, server: &McpServer,
+	) -> Result<Self> {
+		Self::with_pipe(base_url, mcp::start(server).await?).await
+	}
+
+	async fn with_pipe(
+		base_url: url::Url, mcp: McpPipe,
+	) -> Result<Self> {
+		let options = ClientOptions::default();
 		Ok(Self {
 			base_url,
+			http: options.http_client()?,
+			stream: options.stream_client()?,
+			backoff: options.backoff(),
 			#[cfg(test)]
 			query_type: None,
-			mcp: Arc::new(Self::init_mcp().await?),
+			tools: Arc::new(mcp::list_tools(&mcp).await?),
+			mcp,
+			outbox: None,
 		})
 	}
 
@@ -46,37 +158,202 @@ impl Client {
 		base_url: url::Url, query_type: QueryType,
 	) -> Result<Self> {
 		Ok(Self {
-			base_url,
 			query_type: Some(query_type),
-			mcp: Arc::new(Self::init_mcp().await?),
+			..Self::new(base_url).await?
+		})
+	}
+
+	// timeouts, headers and reconnection for the API calls
+	pub fn with_options(self, options: &ClientOptions) -> Result<Self> {
+		Ok(Self {
+			http: options.http_client()?,
+			stream: options.stream_client()?,
+			backoff: options.backoff(),
+			..self
 		})
 	}
 
+	// queues prompts and tool call responses until the server has taken them, see outbox::Outbox
+	pub fn with_outbox(self, outbox: Outbox) -> Self {
+		Self {
+			outbox: Some(outbox),
+			..self
+		}
+	}
+
+	pub fn tools(&self) -> &[rmcp::model::Tool] {
+		&self.tools
+	}
+
+	pub fn outbox(&self) -> Option<&Outbox> {
+		self.outbox.as_ref()
+	}
+
+	// sends what an earlier run left in the outbox, in order. Prompts come back as streams, keyed
+	// by their outbox item.
+	pub fn resume_outbox(
+		&self,
+	) -> std::result::Result<Vec<(uuid::Uuid, PromptStream)>, ClientError>
+	{
+		let Some(outbox) = &self.outbox else {
+			return Ok(Vec::new());
+		};
+
+		let mut streams = Vec::new();
+		for item in outbox.take_inactive() {
+			match item.message {
+				OutboxMessage::Prompt(prompt) => streams.push((
+					item.id,
+					PromptStream::open(
+						self.clone(),
+						self.prompt_url()?,
+						prompt,
+						Some(item.id),
+					),
+				)),
+				OutboxMessage::McpResponse(response) => {
+					let client = self.clone();
+					tokio::spawn(async move {
+						client.deliver(item.id, response).await
+					});
+				}
+			}
+		}
+
+		Ok(streams)
+	}
+
+	// the server has taken the outbox item, or refused it for good
+	fn delivered(&self, key: Option<uuid::Uuid>) {
+		if let (Some(key), Some(outbox)) = (key, &self.outbox)
+			&& let Err(e) = outbox.remove(key)
+		{
+			tracing::warn!("could not update the outbox: {}", e);
+		}
+	}
+
+	async fn send(
+		&self, request: reqwest::RequestBuilder,
+	) -> std::result::Result<Vec<u8>, ClientError> {
+		let response = request.send().await?;
+		if !response.status().is_success() {
+			return Err(ClientError::from_response(response).await);
+		}
+		Ok(response.bytes().await?.to_vec())
+	}
+
+	fn request(
+		&self, method: reqwest::Method, path: &str,
+		body: Option<&impl Serialize>,
+	) -> std::result::Result<reqwest::RequestBuilder, ClientError> {
+		let mut request =
+			self.http.request(method, self.base_url.join(path)?);
+		if let Some(body) = body {
+			request = request
+				.header(
+					reqwest::header::CONTENT_TYPE,
+					"application/json",
+				)
+				.body(serde_json::to_vec(body)?);
+		}
+		Ok(request)
+	}
+
+	async fn call<T: DeserializeOwned>(
+		&self, method: reqwest::Method, path: &str,
+		body: Option<&impl Serialize>,
+	) -> std::result::Result<T, ClientError> {
+		let body = self.send(self.request(method, path, body)?).await?;
+		// handlers without a result answer with an empty body
+		Ok(serde_json::from_slice(if body.is_empty() {
+			b"null".as_slice()
+		} else {
+			body.as_slice()
+		})?)
+	}
+
 	pub async fn mcp_response(
-		&self, _input: McpResponse,
-	) -> Result<()> {
-		return Err(anyhow!("unimplemented"));
+		&self, input: McpResponse,
+	) -> std::result::Result<(), ClientError> {
+		self.call(reqwest::Method::POST, "/mcp_response", Some(&input))
+			.await
+	}
+
+	// answers a PromptResponse::ConfirmAction or RequestConsent; the call goes through only if approved
+	pub async fn confirm(
+		&self, input: Confirmation,
+	) -> std::result::Result<(), ClientError> {
+		self.call(reqwest::Method::POST, "/confirm", Some(&input))
+			.await
 	}
 
 	pub async fn search(
-		&self, _input: Search,
-	) -> Result<SearchResults> {
-		return Err(anyhow!("unimplemented"));
+		&self, input: Search,
+	) -> std::result::Result<SearchResults, ClientError> {
+		self.call(reqwest::Method::POST, "/search", Some(&input))
+			.await
 	}
 
-	pub async fn input(&self, _input: Input) -> Result<bool> {
-		return Err(anyhow!("unimplemented"));
+	pub async fn index(
+		&self, input: SearchUpload,
+	) -> std::result::Result<usize, ClientError> {
+		self.call(reqwest::Method::PUT, "/search/index", Some(&input))
+			.await
 	}
 
-	pub async fn metrics(&self) -> Result<Metrics> {
-		return Err(anyhow!("unimplemented"));
+	// replaces what the session can search with everything in the store
+	pub async fn index_store(
+		&self, connection_id: uuid::Uuid, store: &dyn DataStore,
+	) -> std::result::Result<usize, ClientError> {
+		let mut indexed = self
+			.index(SearchUpload {
+				connection_id,
+				documents: Vec::new(),
+				replace: true,
+			})
+			.await?;
+
+		for batch in documents(store).chunks(INDEX_BATCH_SIZE) {
+			indexed = self
+				.index(SearchUpload {
+					connection_id,
+					documents: batch.to_vec(),
+					replace: false,
+				})
+				.await?;
+		}
+		Ok(indexed)
+	}
+
+	pub async fn input(
+		&self, input: Input,
+	) -> std::result::Result<bool, ClientError> {
+		self.call(reqwest::Method::PUT, "/input", Some(&input))
+			.await
+	}
+
+	// in Prometheus text format
+	pub async fn metrics(
+		&self,
+	) -> std::result::Result<String, ClientError> {
+		let body = self
+			.send(self.request(
+				reqwest::Method::GET,
+				"/metrics",
+				None::<&()>,
+			)?)
+			.await?;
+		Ok(String::from_utf8_lossy(&body).into_owned())
 	}
 
-	pub async fn status(&self) -> Result<Status> {
-		return Err(anyhow!("unimplemented"));
+	pub async fn status(
+		&self,
+	) -> std::result::Result<Status, ClientError> {
+		self.call(reqwest::Method::GET, "/status", None::<&()>)
+			.await
 	}
 
-	pub async fn prompt(&self, input: Prompt) -> SseResult {
+	fn prompt_url(&self) -> std::result::Result<url::Url, ClientError> {
 		#[cfg(test)]
 		let mut url = self.base_url.join("/prompt")?;
 		#[cfg(not(test))]
@@ -88,79 +365,129 @@ impl Client {
 			}
 			_ => {}
 		}
+		Ok(url)
+	}
 
-		let mut es = reqwest_eventsource::EventSource::post(
-			url.to_string(),
-			"application/json",
-			reqwest::Body::from(serde_json::to_vec(&input)?),
-		);
-
-		let (s, r) = unbounded_channel();
-		let (_mcp_in, _mcp_out) = self.mcp.clone().deref();
-
-		tokio::spawn(async move {
-			while let Some(event) = es.next().await {
-				match event {
-					Ok(m) => match s.send(Ok(m)) {
-						// try to send an error if we get one trying to send. We probably won't
-						// succeed, so don't try to handle further errors.
-						Err(e) => {
-							let _ = s.send(Err(e.into()));
-							return;
-						}
-						_ => {}
-					},
-					Err(e) => {
-						// if we already have an error, just try to fire off the event before
-						// dying, don't try to process any trouble with firing it to avoid
-						// complexity.
-						let _ = s.send(Err(e.into()));
-						return;
-					}
-				}
-			}
-		});
+	// starts or resumes a prompt; the stream reconnects by itself until the session ends. New
+	// prompts go through the outbox if there is one.
+	pub fn prompt(
+		&self, input: Prompt,
+	) -> std::result::Result<PromptStream, ClientError> {
+		// registers the MCP server's tools on the session, new or resumed
+		let input = Prompt {
+			tools: input.tools.or_else(|| Some(self.tools.to_vec())),
+			..input
+		};
 
-		Ok(r)
+		let key = match &self.outbox {
+			Some(outbox) if input.prompt.is_some() => Some(
+				outbox.push(OutboxMessage::Prompt(input.clone()))?,
+			),
+			_ => None,
+		};
+
+		Ok(PromptStream::open(
+			self.clone(),
+			self.prompt_url()?,
+			input,
+			key,
+		))
 	}
 
-	async fn init_mcp() -> Result<McpPipe> {
-		let (in_s, mut in_r) = unbounded_channel::<Vec<u8>>();
-		let (out_s, out_r) = unbounded_channel();
+	// runs a tools/call from the proxy and posts the response. Failures are posted as JSON-RPC
+	// errors so the model hears about them instead of waiting for the call to time out.
+	async fn answer_mcp(&self, request: McpRequest) {
+		let response = match self.call_mcp(&request.command).await {
+			Ok(response) => response,
+			Err(e) => serde_json::json!({
+				"jsonrpc": "2.0",
+				"id": rpc_id(&request.command).unwrap_or_default(),
+				"error": {"code": -32603, "message": e.to_string()},
+			})
+			.to_string(),
+		};
 
-		let (stdin_r, mut stdin_w) = tokio::io::simplex(4096);
-		let (mut stdout_r, stdout_w) = tokio::io::simplex(4096);
+		let response = McpResponse {
+			connection_id: request.connection_id,
+			response,
+			traceparent: request.traceparent,
+		};
 
-		tokio::spawn(async move {
-			if let Ok(service) =
-				Service::default().serve((stdin_r, stdout_w)).await
-			{
-				let _ = service.waiting().await;
+		let Some(outbox) = &self.outbox else {
+			if let Err(e) = self.mcp_response(response).await {
+				tracing::warn!("could not send MCP response: {}", e);
 			}
-		});
+			return;
+		};
 
-		tokio::spawn(async move {
-			while let Some(x) = in_r.recv().await {
-				if let Err(_) = stdin_w.write_all(x.as_slice()).await {
-					return;
+		match outbox.push(OutboxMessage::McpResponse(response.clone()))
+		{
+			Ok(key) => self.deliver(key, response).await,
+			Err(e) => {
+				tracing::warn!("could not queue MCP response: {}", e);
+				if let Err(e) = self.mcp_response(response).await {
+					tracing::warn!(
+						"could not send MCP response: {}",
+						e
+					);
 				}
 			}
-		});
-
-		tokio::spawn(async move {
-			loop {
-				let mut dst = String::new();
-				if let Err(_) = stdout_r.read_to_string(&mut dst).await
-				{
-					return;
+		}
+	}
+
+	// sends an MCP response from the outbox once it's its turn, trying again while offline
+	async fn deliver(&self, key: uuid::Uuid, response: McpResponse) {
+		let Some(outbox) = &self.outbox else {
+			return;
+		};
+		if !outbox.wait_turn(key).await {
+			return;
+		}
+
+		let mut attempt = 0;
+		loop {
+			let result = match self.request(
+				reqwest::Method::POST,
+				"/mcp_response",
+				Some(&response),
+			) {
+				Ok(request) => {
+					self.send(
+						request
+							.header(IDEMPOTENCY_KEY, key.to_string()),
+					)
+					.await
 				}
+				Err(e) => Err(e),
+			};
 
-				if let Err(_) = out_s.send(dst.as_bytes().to_vec()) {
-					return;
+			match result {
+				Err(e) if e.is_transient() => {
+					tokio::time::sleep(self.backoff.delay(attempt))
+						.await;
+					attempt = attempt.saturating_add(1);
+				}
+				Err(e) => {
+					tracing::warn!(
+						"could not send MCP response: {}",
+						e
+					);
+					break;
 				}
+				Ok(_) => break,
 			}
-		});
 
-		Ok((in_s, out_r))
+			// cancelled while offline
+			if !outbox.wait_turn(key).await {
+				return;
+			}
+		}
+
+		self.delivered(Some(key));
+	}
+
+	// sends a JSON-RPC request to the MCP server and waits for its response
+	pub async fn call_mcp(&self, command: &str) -> Result<String> {
+		mcp::call(&self.mcp, command).await
 	}
 }
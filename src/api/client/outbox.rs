@@ -0,0 +1,237 @@
+use crate::api::server::{McpResponse, Prompt};
+
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{HashSet, VecDeque},
+	path::PathBuf,
+	sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+// NOTE: the outbox holds what the phone wants to send until the server has taken it: new prompts,
+// and responses to tool calls. Items are written to disk before the first attempt, go out strictly
+// in the order they were queued, and each carries an id that is sent as its idempotency key, so a
+// message that reached the server before the connection dropped isn't acted on twice. Items left
+// over from an earlier run stay queued, and hold up everything behind them, until
+// Client::resume_outbox sends them or they are cancelled.
+
+pub(crate) const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "message")]
+pub enum OutboxMessage {
+	#[serde(rename = "prompt")]
+	Prompt(Prompt),
+	#[serde(rename = "mcp_response")]
+	McpResponse(McpResponse),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxItem {
+	// also the idempotency key
+	pub id: uuid::Uuid,
+	pub queued_at: chrono::DateTime<chrono::Utc>,
+	pub message: OutboxMessage,
+}
+
+#[derive(Debug, Default)]
+struct State {
+	items: VecDeque<OutboxItem>,
+	// items this process is sending, as opposed to ones loaded from disk
+	active: HashSet<uuid::Uuid>,
+}
+
+// a handle to the queue; clones share it
+#[derive(Debug, Clone, Default)]
+pub struct Outbox {
+	path: Option<PathBuf>,
+	state: Arc<Mutex<State>>,
+	changed: Arc<Notify>,
+}
+
+impl Outbox {
+	// an outbox kept in the file at path, with whatever an earlier run left in it
+	pub fn open(path: impl Into<PathBuf>) -> std::io::Result<Self> {
+		let path = path.into();
+		let items = match std::fs::read(&path) {
+			Ok(x) => serde_json::from_slice(&x)?,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+				Default::default()
+			}
+			Err(e) => return Err(e),
+		};
+
+		Ok(Self {
+			path: Some(path),
+			state: Arc::new(Mutex::new(State {
+				items,
+				active: Default::default(),
+			})),
+			changed: Default::default(),
+		})
+	}
+
+	// what is still waiting to be sent, oldest first
+	pub fn items(&self) -> Vec<OutboxItem> {
+		self.state.lock().unwrap().items.iter().cloned().collect()
+	}
+
+	// takes an item out of the queue; a prompt stream waiting to send it ends with
+	// ClientError::Cancelled. False if the item was sent already.
+	pub fn cancel(&self, id: uuid::Uuid) -> std::io::Result<bool> {
+		self.remove(id)
+	}
+
+	pub(crate) fn push(
+		&self, message: OutboxMessage,
+	) -> std::io::Result<uuid::Uuid> {
+		let id = uuid::Uuid::new_v4();
+		let mut state = self.state.lock().unwrap();
+
+		state.items.push_back(OutboxItem {
+			id,
+			queued_at: chrono::Utc::now(),
+			message,
+		});
+		if let Err(e) = self.save(&state.items) {
+			state.items.pop_back();
+			return Err(e);
+		}
+
+		state.active.insert(id);
+		Ok(id)
+	}
+
+	pub(crate) fn remove(
+		&self, id: uuid::Uuid,
+	) -> std::io::Result<bool> {
+		let mut state = self.state.lock().unwrap();
+		let Some(i) = state.items.iter().position(|x| x.id == id)
+		else {
+			return Ok(false);
+		};
+
+		let item = state.items.remove(i);
+		state.active.remove(&id);
+		let result = self.save(&state.items);
+		drop(state);
+
+		self.changed.notify_waiters();
+		result.map(|_| item.is_some())
+	}
+
+	// marks the items loaded from disk as being sent by this process, and returns them
+	pub(crate) fn take_inactive(&self) -> Vec<OutboxItem> {
+		let mut state = self.state.lock().unwrap();
+		let items: Vec<_> = state
+			.items
+			.iter()
+			.filter(|x| !state.active.contains(&x.id))
+			.cloned()
+			.collect();
+		state.active.extend(items.iter().map(|x| x.id));
+		items
+	}
+
+	// waits until everything queued before the item is gone; false if the item is gone itself
+	pub(crate) async fn wait_turn(&self, id: uuid::Uuid) -> bool {
+		loop {
+			let changed = self.changed.notified();
+
+			let turn = {
+				let state = self.state.lock().unwrap();
+				if !state.items.iter().any(|x| x.id == id) {
+					return false;
+				}
+				state.items.front().is_some_and(|x| x.id == id)
+			};
+			if turn {
+				return true;
+			}
+
+			changed.await;
+		}
+	}
+
+	// the whole queue is rewritten and renamed into place, so a crash leaves the old or the new one
+	fn save(
+		&self, items: &VecDeque<OutboxItem>,
+	) -> std::io::Result<()> {
+		let Some(path) = &self.path else {
+			return Ok(());
+		};
+
+		let tmp = path.with_extension("tmp");
+		std::fs::write(&tmp, serde_json::to_vec(items)?)?;
+		std::fs::rename(&tmp, path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn prompt(text: &str) -> OutboxMessage {
+		OutboxMessage::Prompt(Prompt {
+			prompt: Some(text.into()),
+			..Default::default()
+		})
+	}
+
+	#[test]
+	fn test_outbox_persistence() {
+		let path = std::env::temp_dir().join(format!(
+			"allelo-outbox-{}.json",
+			uuid::Uuid::new_v4()
+		));
+
+		let outbox = Outbox::open(&path).unwrap();
+		let first = outbox.push(prompt("first")).unwrap();
+		let second = outbox
+			.push(OutboxMessage::McpResponse(McpResponse {
+				connection_id: "1".into(),
+				response: "{}".into(),
+				..Default::default()
+			}))
+			.unwrap();
+		outbox.push(prompt("third")).unwrap();
+
+		assert!(outbox.cancel(second).unwrap());
+		assert!(!outbox.cancel(second).unwrap());
+
+		// a new run finds what is left, in order, and nothing of it is being sent yet
+		let outbox = Outbox::open(&path).unwrap();
+		let items = outbox.items();
+		assert_eq!(items.len(), 2);
+		assert_eq!(items[0].id, first);
+		assert!(matches!(
+			&items[1].message,
+			OutboxMessage::Prompt(x) if x.prompt.as_deref() == Some("third")
+		));
+		assert_eq!(outbox.take_inactive().len(), 2);
+		assert!(outbox.take_inactive().is_empty());
+
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_outbox_order() {
+		let outbox = Outbox::default();
+		let first = outbox.push(prompt("first")).unwrap();
+		let second = outbox.push(prompt("second")).unwrap();
+
+		assert!(outbox.wait_turn(first).await);
+
+		let o = outbox.clone();
+		let waiting =
+			tokio::spawn(async move { o.wait_turn(second).await });
+		tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+		assert!(!waiting.is_finished());
+
+		outbox.remove(first).unwrap();
+		assert!(waiting.await.unwrap());
+
+		outbox.cancel(second).unwrap();
+		assert!(!outbox.wait_turn(second).await);
+	}
+}
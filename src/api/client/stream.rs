@@ -0,0 +1,309 @@
+use super::outbox::IDEMPOTENCY_KEY;
+use super::{Client, ClientError, Problem};
+use crate::api::server::{Prompt, PromptResponse};
+
+use eventsource_stream::Eventsource;
+use futures_util::StreamExt;
+use std::{
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+use tokio::sync::mpsc::{
+	UnboundedReceiver, UnboundedSender, unbounded_channel,
+};
+
+// NOTE: a prompt stream outlives the SSE connections it is read from. When one drops, the stream
+// connects again with the session's connection_id and the id of the last event it saw, and the
+// server sends whatever was missed in between (see crate::api::server::broker::Replay). Attempts
+// back off exponentially with jitter, so phones that lost the network together don't all come
+// back in the same instant. A prompt queued in the outbox waits for its turn before the first
+// attempt, and leaves the outbox once the server has accepted it.
+
+#[derive(Debug, Clone)]
+pub enum PromptEvent {
+	Response(PromptResponse),
+	// the connection dropped; the next attempt is made after retry_in
+	Disconnected { error: String, retry_in: Duration },
+	// connected again, missed events follow
+	Reconnected,
+}
+
+type Sender = UnboundedSender<Result<PromptEvent, ClientError>>;
+
+// where the stream would resume from
+#[derive(Debug, Clone, Copy, Default)]
+struct Position {
+	connection_id: Option<uuid::Uuid>,
+	cursor: Option<u64>,
+}
+
+// events of a prompt, with tool calls already answered. An error ends the stream: the session is
+// gone or the server refused it.
+#[derive(Debug)]
+pub struct PromptStream {
+	receiver: UnboundedReceiver<Result<PromptEvent, ClientError>>,
+	position: Arc<Mutex<Position>>,
+	task: tokio::task::JoinHandle<()>,
+}
+
+impl PromptStream {
+	pub(crate) fn open(
+		client: Client, url: url::Url, prompt: Prompt,
+		key: Option<uuid::Uuid>,
+	) -> Self {
+		let (s, receiver) = unbounded_channel();
+		let position = Arc::new(Mutex::new(Position {
+			connection_id: prompt.connection_id,
+			cursor: prompt.cursor,
+		}));
+
+		let task = tokio::spawn(run(
+			client,
+			url,
+			prompt,
+			key,
+			s,
+			position.clone(),
+		));
+
+		Self {
+			receiver,
+			position,
+			task,
+		}
+	}
+
+	pub async fn next(
+		&mut self,
+	) -> Option<Result<PromptEvent, ClientError>> {
+		self.receiver.recv().await
+	}
+
+	// the session this stream belongs to, once the server has named it
+	pub fn connection_id(&self) -> Option<uuid::Uuid> {
+		self.position.lock().unwrap().connection_id
+	}
+
+	// id of the last event received; with connection_id, resumes the prompt on another stream
+	pub fn cursor(&self) -> Option<u64> {
+		self.position.lock().unwrap().cursor
+	}
+}
+
+impl Drop for PromptStream {
+	fn drop(&mut self) {
+		self.task.abort();
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Backoff {
+	pub(crate) min: Duration,
+	pub(crate) max: Duration,
+}
+
+impl Backoff {
+	// doubles with every attempt up to max; the upper half of it is random
+	pub(crate) fn delay(&self, attempt: u32) -> Duration {
+		let delay = self
+			.min
+			.saturating_mul(2u32.saturating_pow(attempt))
+			.min(self.max);
+		delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+	}
+}
+
+async fn run(
+	client: Client, url: url::Url, mut prompt: Prompt,
+	key: Option<uuid::Uuid>, s: Sender, position: Arc<Mutex<Position>>,
+) {
+	let mut attempt = 0;
+	let mut connected = false;
+	let mut disconnected = false;
+
+	loop {
+		if !connected
+			&& let (Some(key), Some(outbox)) = (key, &client.outbox)
+			&& !outbox.wait_turn(key).await
+		{
+			let _ = s.send(Err(ClientError::Cancelled));
+			return;
+		}
+
+		let key = key.filter(|_| !connected);
+		let error = match connect(&client, &url, &prompt, key).await {
+			Ok(response) => {
+				// the prompt is on its way; resuming must not send it again
+				prompt.prompt = None;
+				client.delivered(key);
+				attempt = 0;
+
+				if std::mem::take(&mut disconnected)
+					&& s.send(Ok(PromptEvent::Reconnected)).is_err()
+				{
+					return;
+				}
+
+				let resumed = std::mem::replace(&mut connected, true);
+				read(
+					&client,
+					response,
+					&mut prompt,
+					resumed,
+					&s,
+					&position,
+				)
+				.await
+			}
+			Err(e) if e.is_transient() => e.to_string(),
+			Err(e) => {
+				client.delivered(key);
+				let _ = s.send(Err(e));
+				return;
+			}
+		};
+
+		if s.is_closed() {
+			return;
+		}
+
+		let retry_in = client.backoff.delay(attempt);
+		attempt = attempt.saturating_add(1);
+		disconnected = true;
+		tracing::debug!("prompt stream dropped: {}", error);
+
+		if s.send(Ok(PromptEvent::Disconnected { error, retry_in }))
+			.is_err()
+		{
+			return;
+		}
+		tokio::time::sleep(retry_in).await;
+	}
+}
+
+async fn connect(
+	client: &Client, url: &url::Url, prompt: &Prompt,
+	key: Option<uuid::Uuid>,
+) -> Result<reqwest::Response, ClientError> {
+	let mut request = client
+		.stream
+		.post(url.clone())
+		.header(reqwest::header::CONTENT_TYPE, "application/json")
+		.header(reqwest::header::ACCEPT, "text/event-stream")
+		.body(serde_json::to_vec(prompt)?);
+	if let Some(key) = key {
+		request = request.header(IDEMPOTENCY_KEY, key.to_string());
+	}
+
+	let response = request.send().await?;
+
+	if !response.status().is_success() {
+		return Err(ClientError::from_response(response).await);
+	}
+	Ok(response)
+}
+
+// relays events until the connection drops, and says why it did
+async fn read(
+	client: &Client, response: reqwest::Response, prompt: &mut Prompt,
+	resumed: bool, s: &Sender, position: &Mutex<Position>,
+) -> String {
+	let mut events = Box::pin(response.bytes_stream().eventsource());
+
+	while let Some(event) = events.next().await {
+		let event = match event {
+			Ok(event) => event,
+			Err(e) => return e.to_string(),
+		};
+
+		// the server ended the stream, e.g. at its longest duration; it is resumed like a dropped one
+		if event.event == "error" {
+			let problem: Problem =
+				serde_json::from_str(&event.data).unwrap_or_default();
+			return ClientError::Problem {
+				status: problem.status.unwrap_or_default(),
+				problem,
+			}
+			.to_string();
+		}
+
+		if let Ok(id) = event.id.parse() {
+			prompt.cursor = Some(id);
+			position.lock().unwrap().cursor = Some(id);
+		}
+
+		let response = match serde_json::from_str(&event.data) {
+			Ok(response) => response,
+			Err(e) => {
+				tracing::warn!("could not decode prompt event: {}", e);
+				continue;
+			}
+		};
+
+		match response {
+			// the same session when resuming; the caller has heard of it already
+			PromptResponse::Connection(id) => {
+				prompt.connection_id = Some(id);
+				position.lock().unwrap().connection_id = Some(id);
+				if resumed {
+					continue;
+				}
+			}
+			PromptResponse::McpRequest(request) => {
+				let client = client.clone();
+				tokio::spawn(async move {
+					client.answer_mcp(request).await
+				});
+				continue;
+			}
+			_ => {}
+		}
+
+		if s.send(Ok(PromptEvent::Response(response))).is_err() {
+			return "stream closed".into();
+		}
+	}
+
+	"server closed the stream".into()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_backoff() {
+		let backoff = Backoff {
+			min: Duration::from_millis(100),
+			max: Duration::from_secs(1),
+		};
+
+		for attempt in 0..4 {
+			let delay = backoff.delay(attempt);
+			let full = Duration::from_millis(100 * 2u64.pow(attempt));
+			assert!(delay >= full / 2 && delay <= full);
+		}
+
+		let delay = backoff.delay(u32::MAX);
+		assert!(delay >= backoff.max / 2 && delay <= backoff.max);
+	}
+
+	#[test]
+	fn test_is_transient() {
+		assert!(ClientError::Timeout.is_transient());
+		assert!(
+			ClientError::Problem {
+				status: 503,
+				problem: Default::default(),
+			}
+			.is_transient()
+		);
+		assert!(
+			!ClientError::Problem {
+				status: 500,
+				problem: Default::default(),
+			}
+			.is_transient()
+		);
+	}
+}
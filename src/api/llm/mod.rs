@@ -1,18 +1,21 @@
 use anyhow::Result;
+use futures_util::Stream;
 use futures_util::StreamExt;
 use llm::chat::Tool;
 use llm::{
+	FunctionCall, ToolCall,
 	builder::LLMBuilder,
-	chat::{ChatMessageBuilder, ToolChoice},
+	chat::{ChatMessage, ChatMessageBuilder, ChatRole, ToolChoice},
 };
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{pin::Pin, sync::Arc, time::Instant};
 use tokio::sync::{
 	Mutex,
 	mpsc::{UnboundedReceiver, unbounded_channel},
 };
+use tracing::Instrument;
 
-use crate::api::server::PromptResponse;
+use crate::api::server::{PromptResponse, metrics};
 
 // NOTE: the underlying LLM client's abstraction is not much different than this one. I chose to
 // NIH this so I'd have control of the inner workings. Don't get mad, modifying it to support new
@@ -107,20 +110,126 @@ impl LLMClientType {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct LLMClientParams {
 	pub base_url: String,
+	#[serde(default)]
 	pub api_key: Option<String>,
+	#[serde(default)]
 	pub timeout: Option<std::time::Duration>,
+	#[serde(default)]
 	pub force_tools: bool,
 	// FIXME: json schema response support
 }
 
 pub type LLMProvider = Arc<Mutex<Box<dyn llm::LLMProvider>>>;
 
+// the model gets this many rounds of tool calls before it has to answer
+pub(crate) const MAX_TOOL_ROUNDS: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ToolError {
+	#[error("the user denied this action")]
+	Denied,
+	#[error("{0} is not allowed by the user's privacy settings")]
+	NotPermitted(String),
+	#[error("timed out waiting for {0}")]
+	Timeout(String),
+	#[error("{0}")]
+	Failed(String),
+}
+
+// NOTE: carries the tool calls requested by the model to wherever the tools live; for the proxy
+// that is the phone, see crate::api::server::SessionTools. Errors are handed back to the model as
+// the result of the call, so it can explain what happened or try something else.
+#[async_trait::async_trait]
+pub trait ToolDispatcher: Send + Sync {
+	// tools to offer the model instead of the built-in ones
+	fn tools(&self) -> Option<Vec<Tool>> {
+		None
+	}
+
+	async fn call(
+		&self, name: &str, arguments: &str,
+	) -> std::result::Result<String, ToolError>;
+
+	// what the user said since the last turn; it goes into the conversation before the next one
+	async fn interjections(&self) -> Vec<String> {
+		Vec::new()
+	}
+
+	// resolves once the user said something urgent, which cuts the current turn short
+	async fn interrupted(&self) {
+		std::future::pending().await
+	}
+}
+
+// NOTE: a turn is one request to the model: a round of tool calls, or the streamed answer. Input
+// the user sends while a prompt runs is picked up between turns. Urgent input doesn't wait: the
+// request in flight is abandoned, or the answer is cut off where it is and kept in the
+// conversation, and the model goes again with the new input.
+
+type AnswerStream = Pin<Box<dyn Stream<Item = String> + Send>>;
+
+async fn interrupted(dispatcher: Option<&Arc<dyn ToolDispatcher>>) {
+	match dispatcher {
+		Some(x) => x.interrupted().await,
+		None => std::future::pending().await,
+	}
+}
+
+// timing of a streamed answer, recorded as it goes and when it ends
+struct AnswerStats {
+	model: String,
+	started: Instant,
+	first: Option<Instant>,
+	chunks: u64,
+}
+
+impl AnswerStats {
+	fn chunk(&mut self) {
+		let labels = [("model", self.model.as_str())];
+		if self.first.is_none() {
+			self.first = Some(Instant::now());
+			metrics::LLM_FIRST_TOKEN
+				.observe(&labels, self.started.elapsed().as_secs_f64());
+		}
+		self.chunks += 1;
+		metrics::LLM_CHUNKS.inc(&labels);
+	}
+}
+
+impl Drop for AnswerStats {
+	fn drop(&mut self) {
+		// the rate after the first chunk, which waited on the prompt and the tool calls
+		let Some(first) = self.first else {
+			return;
+		};
+		let secs = first.elapsed().as_secs_f64();
+		if self.chunks > 1 && secs > 0.0 {
+			metrics::LLM_CHUNK_RATE.observe(
+				&[("model", self.model.as_str())],
+				(self.chunks - 1) as f64 / secs,
+			);
+		}
+	}
+}
+
+fn default_tools() -> Vec<Tool> {
+	#[cfg(not(test))]
+	let tools = crate::mcp::tool::tool_list();
+	#[cfg(test)]
+	let tools = crate::mcp::test_service::test_tool_list();
+
+	tools.0.iter().map(|x| x.clone().into()).collect()
+}
+
 #[derive(Clone)]
 pub struct LLMClient {
 	params: LLMClientParams,
 	client: LLMProvider,
+	// for metrics
+	model: String,
 }
 
 impl std::fmt::Debug for LLMClient {
@@ -135,6 +244,7 @@ impl LLMClient {
 	) -> Result<Self> {
 		Ok(Self {
 			params: params.clone(),
+			model: client_type.to_model(),
 			client: Arc::new(Mutex::new(Self::build_client(
 				client_type,
 				params,
@@ -146,65 +256,220 @@ impl LLMClient {
 		self.client.clone()
 	}
 
+	// one embedding per input, for backends that can make them
+	pub async fn embed(
+		&self, input: Vec<String>,
+	) -> Result<Vec<Vec<f32>>> {
+		Ok(self.client.lock().await.embed(input).await?)
+	}
+
 	pub async fn prompt(
 		&self, prompt: String,
 	) -> Result<UnboundedReceiver<PromptResponse>> {
-		let lock = self.client.lock().await;
-
-		#[cfg(not(test))]
-		let tools: Vec<Tool> = crate::mcp::tool::tool_list()
-			.0
-			.iter()
-			.map(|x| x.clone().into())
-			.collect();
-		#[cfg(test)]
-		let tools: Vec<Tool> = crate::mcp::test_service::test_tool_list()
-			.0
-			.iter()
-			.map(|x| x.clone().into())
-			.collect();
-
-		let response = lock
-			.chat_with_tools(
-				&[ChatMessageBuilder::new(llm::chat::ChatRole::User)
-					.content(prompt.clone())
-					.build()],
-				Some(&tools),
-			)
-			.await?;
+		self.prompt_with_tools(prompt, None).await
+	}
 
-		if let Some(tools) = (*response).tool_calls() {
-			for tool in tools {
-				eprintln!(
-					"tool name: {} args: {}",
-					tool.function.name, tool.function.arguments
-				)
-			}
-		}
+	pub async fn prompt_with_tools(
+		&self, prompt: String,
+		dispatcher: Option<Arc<dyn ToolDispatcher>>,
+	) -> Result<UnboundedReceiver<PromptResponse>> {
+		let tools = dispatcher
+			.as_ref()
+			.and_then(|x| x.tools())
+			.unwrap_or_else(default_tools);
+
+		let mut stats = AnswerStats {
+			model: self.model.clone(),
+			started: Instant::now(),
+			first: None,
+			chunks: 0,
+		};
+		let mut messages = vec![
+			ChatMessageBuilder::new(ChatRole::User)
+				.content(prompt)
+				.build(),
+		];
+
+		let mut stream = Self::answer(
+			&self.client,
+			&mut messages,
+			&tools,
+			dispatcher.as_ref(),
+		)
+		.await?;
 
-		drop(response);
+		let (s, r) = unbounded_channel();
+		let client = self.client.clone();
+
+		tokio::spawn(
+			async move {
+				loop {
+					let mut partial = String::new();
+
+					let cut_short = loop {
+						tokio::select! {
+							item = stream.next() => match item {
+								Some(item) => {
+									stats.chunk();
+									partial.push_str(&item);
+									if s
+										.send(PromptResponse::PromptResponse(item))
+										.is_err()
+									{
+										return;
+									}
+								}
+								None => break false,
+							},
+							_ = interrupted(dispatcher.as_ref()) => break true,
+						}
+					};
+
+					if !cut_short {
+						return;
+					}
+
+					messages.push(
+						ChatMessageBuilder::new(ChatRole::Assistant)
+							.content(partial)
+							.build(),
+					);
+
+					stream = match Self::answer(
+						&client,
+						&mut messages,
+						&tools,
+						dispatcher.as_ref(),
+					)
+					.await
+					{
+						Ok(stream) => stream,
+						Err(e) => {
+							tracing::error!(
+								"could not answer after interjection: {}",
+								e
+							);
+							return;
+						}
+					};
+				}
+			}
+			.instrument(tracing::Span::current()),
+		);
 
-		let mut stream = lock
-			.chat_stream(&[ChatMessageBuilder::new(
-				llm::chat::ChatRole::User,
-			)
-			.content(prompt)
-			.build()])
-			.await?;
+		Ok(r)
+	}
 
-		let (s, r) = unbounded_channel();
+	// runs rounds of tool calls until the model is ready to answer, then streams the answer
+	async fn answer(
+		client: &LLMProvider, messages: &mut Vec<ChatMessage>,
+		tools: &[Tool], dispatcher: Option<&Arc<dyn ToolDispatcher>>,
+	) -> Result<AnswerStream> {
+		let lock = client.lock().await;
+
+		let mut round = 0;
+		while round < MAX_TOOL_ROUNDS {
+			let turn = tracing::info_span!("turn", round);
+			Self::interject(messages, dispatcher).await;
+
+			// an abandoned request doesn't use up a round
+			let response = tokio::select! {
+				response = lock
+					.chat_with_tools(messages, Some(tools))
+					.instrument(tracing::info_span!(parent: &turn, "llm.chat")) => response?,
+				_ = interrupted(dispatcher) => continue,
+			};
+			round += 1;
+			let calls = (*response).tool_calls().unwrap_or_default();
+			drop(response);
+
+			if calls.is_empty() {
+				break;
+			}
 
-		tokio::spawn(async move {
-			while let Some(Ok(item)) = stream.next().await {
-				if let Err(_) =
-					s.send(PromptResponse::PromptResponse(item))
-				{
-					return;
+			let Some(dispatcher) = dispatcher else {
+				for tool in calls {
+					tracing::debug!(
+						arguments = %tool.function.arguments,
+						"no dispatcher for tool call: {}",
+						tool.function.name
+					)
 				}
+				break;
+			};
+
+			let mut results = Vec::new();
+			for call in &calls {
+				let output = match dispatcher
+					.call(&call.function.name, &call.function.arguments)
+					.instrument(tracing::info_span!(
+						parent: &turn,
+						"tool.call",
+						tool = %call.function.name,
+					))
+					.await
+				{
+					Ok(output) => output,
+					Err(e) => format!("error: {}", e),
+				};
+				tracing::debug!(
+					arguments = %call.function.arguments,
+					result = %output,
+					"called tool: {}",
+					call.function.name
+				);
+
+				results.push(ToolCall {
+					id: call.id.clone(),
+					call_type: call.call_type.clone(),
+					function: FunctionCall {
+						name: call.function.name.clone(),
+						arguments: output,
+					},
+				});
 			}
-		});
 
-		Ok(r)
+			messages.push(
+				ChatMessageBuilder::new(ChatRole::Assistant)
+					.tool_use(calls)
+					.build(),
+			);
+			messages.push(
+				ChatMessageBuilder::new(ChatRole::User)
+					.tool_result(results)
+					.build(),
+			);
+		}
+
+		Self::interject(messages, dispatcher).await;
+
+		// like before, the answer ends at the first error
+		let stream = lock
+			.chat_stream(messages)
+			.instrument(tracing::info_span!("llm.stream"))
+			.await?;
+		Ok(Box::pin(
+			stream
+				.take_while(|x| std::future::ready(x.is_ok()))
+				.filter_map(|x| std::future::ready(x.ok())),
+		))
+	}
+
+	async fn interject(
+		messages: &mut Vec<ChatMessage>,
+		dispatcher: Option<&Arc<dyn ToolDispatcher>>,
+	) {
+		let Some(dispatcher) = dispatcher else {
+			return;
+		};
+
+		for text in dispatcher.interjections().await {
+			messages.push(
+				ChatMessageBuilder::new(ChatRole::User)
+					.content(text)
+					.build(),
+			);
+		}
 	}
 
 	fn build_client(
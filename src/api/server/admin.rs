@@ -0,0 +1,96 @@
+use super::CorsConfig;
+use super::broker::GLOBAL_BROKER;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+// NOTE: with admin set, the operators' routes, the ones behind ServiceAuth (/status, /metrics and
+// /admin/*), are served on a listener of their own, e.g. on an address only reachable from inside
+// the cluster, and the public listener doesn't have them. The admin listener speaks plain HTTP and
+// wants token as a bearer token when one is set. The probes, /healthz and /readyz, stay on the
+// public listener. Both listeners stop together, on a signal or when either fails. Without admin,
+// /status and /metrics are on the public listener and /admin/* isn't served at all.
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+	pub listen: SocketAddr,
+	// wanted in "Authorization: Bearer"; without one, whoever reaches listen can use the routes
+	#[serde(default)]
+	pub token: Option<String>,
+	// for dashboards served from another origin
+	#[serde(default)]
+	pub cors: CorsConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+	pub id: Uuid,
+	// who opened it, see super::quota
+	pub principal: Option<String>,
+	// since the model or the phone last sent anything on the session
+	pub idle_secs: u64,
+	// events waiting to be taken off the prompt pipe
+	pub queued_events: usize,
+	// tool calls waiting to be taken off the MCP pipe
+	pub queued_tool_calls: usize,
+	// tool calls sent to the phone that it hasn't answered yet
+	pub pending_tool_calls: usize,
+	// actions waiting for the user's approval
+	pub pending_confirmations: usize,
+	// tools the phone registered, if it did
+	pub registered_tools: Option<usize>,
+}
+
+// the sessions on this node, longest idle first
+pub(crate) async fn sessions() -> Vec<SessionInfo> {
+	// the sessions are locked by prompt streams for a moment at a time, so they are looked at after
+	// the broker is released; the pipes aren't locked at all
+	let handles: Vec<_> = {
+		let broker = GLOBAL_BROKER.lock().await;
+		broker
+			.handles()
+			.into_iter()
+			.map(|x| (broker.owner(x.0), x))
+			.collect()
+	};
+
+	let mut sessions = Vec::new();
+	for (principal, (id, prompt, mcp, session)) in handles {
+		let session = session.lock().await;
+		sessions.push(SessionInfo {
+			id,
+			principal,
+			idle_secs: prompt
+				.last_message()
+				.max(mcp.last_message())
+				.elapsed()
+				.as_secs(),
+			queued_events: prompt.depth(),
+			queued_tool_calls: mcp.depth(),
+			pending_tool_calls: session.calls.len(),
+			pending_confirmations: session.confirmations.len(),
+			registered_tools: session.tools.as_ref().map(|x| x.len()),
+		});
+	}
+	sessions.sort_by_key(|x| std::cmp::Reverse(x.idle_secs));
+	sessions
+}
+
+// ends a session: its prompt streams close, tool calls and approvals waiting on the phone fail, and
+// it can't be resumed; false if there is no such session
+pub(crate) async fn close(id: Uuid) -> bool {
+	let mut broker = GLOBAL_BROKER.lock().await;
+	let Some(session) = broker.get_session(id) else {
+		return false;
+	};
+	broker.expire(id);
+	drop(broker);
+
+	let mut session = session.lock().await;
+	session.closed = true;
+	session.calls.clear();
+	session.confirmations.clear();
+	tracing::info!("session closed by an operator: {}", id);
+	true
+}
@@ -1,18 +1,24 @@
 use super::broker::BrokerPipe;
+use super::health::HealthCache;
+use super::quota::Quotas;
 use crate::api::{
 	llm::LLMClient,
-	server::{Config, PromptResponse},
+	server::{Config, ConfigLoader, PromptResponse, SessionTools},
 };
 use anyhow::Result;
 use axum::{
 	extract::FromRequestParts,
-	http::request::Parts,
+	http::{
+		HeaderMap, HeaderValue, StatusCode,
+		header::{AUTHORIZATION, WWW_AUTHENTICATE},
+		request::Parts,
+	},
 	response::{IntoResponse, Response},
 };
 use problem_details::ProblemDetails;
 use std::{
 	any::{Any, TypeId},
-	sync::Arc,
+	sync::{Arc, RwLock},
 };
 use tokio::sync::Mutex;
 
@@ -34,17 +40,13 @@ impl PromptClient for PromptRepeaterClient {
 	async fn prompt(
 		&self, id: uuid::Uuid, send: CloneableBrokerPipe, msg: String,
 	) -> Result<()> {
+		let sender = send.lock().await.sender();
 		loop {
 			tokio::select! {
-				mut lock = send.lock() => {
-					tracing::debug!("send lock acquired for: {}", id);
-					tokio::select! {
-						_ = lock.send_message(PromptResponse::PromptResponse(msg.clone()).clone()) => {}
-					}
-				}
+				_ = sender.send(PromptResponse::PromptResponse(msg.clone())) => {}
 				_ = tokio::time::sleep(std::time::Duration::from_millis(100)) => { },
 			}
-			tracing::debug!("freeing send lock for: {}", id);
+			tracing::debug!("sent for: {}", id);
 		}
 	}
 }
@@ -56,6 +58,7 @@ impl PromptClient for PromptLLMClient {
 	async fn prompt(
 		&self, id: uuid::Uuid, send: CloneableBrokerPipe, msg: String,
 	) -> Result<()> {
+		tracing::debug!(prompt = %msg, "prompting for: {}", id);
 		let client = LLMClient::new(
 			self.0
 				.client_type
@@ -67,27 +70,115 @@ impl PromptClient for PromptLLMClient {
 				.expect("Please configure the LLM Client"),
 		)?;
 
-		let mut prompt = client.prompt(msg).await?;
+		let tools = Arc::new(SessionTools::new(id, &self.0).await?);
+		let mut prompt =
+			client.prompt_with_tools(msg, Some(tools.clone())).await?;
 
-		loop {
-			while let Some(result) = prompt.recv().await {
-				let mut lock = send.lock().await;
-				tracing::debug!("send lock acquiredl for: {}", id);
-				lock.send_message(result).await?;
-			}
+		let mut revealer = tools.revealer().await;
+
+		// the pipe stays unlocked while a send waits for room, so the prompt stream can take from it
+		let sender = send.lock().await.sender();
+		while let Some(result) = prompt.recv().await {
+			let result = match result {
+				PromptResponse::PromptResponse(x) => {
+					tools.refresh(&mut revealer).await;
+					let x = revealer.push(&x);
+					if x.is_empty() {
+						continue;
+					}
+					PromptResponse::PromptResponse(x)
+				}
+				x => x,
+			};
+
+			sender.send(result).await?;
+			tracing::debug!("sent for: {}", id);
+		}
 
-			tracing::debug!("freeing send lock for: {}", id);
+		let rest = revealer.finish();
+		if !rest.is_empty() {
+			sender.send(PromptResponse::PromptResponse(rest)).await?;
 		}
+
+		Ok(())
 	}
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct ServerState {
-	pub config: Config,
+	// swapped by reloads, see super::reload
+	config: RwLock<Arc<Config>>,
+	pub(crate) loader: Option<ConfigLoader>,
+	// reloads so far; held while one runs
+	pub(crate) reloads: Mutex<u64>,
+	pub(crate) started: std::time::Instant,
+	pub(crate) health: Arc<HealthCache>,
+	// what principals used of their quotas
+	pub(crate) quotas: Quotas,
+	// the client search embeds with, and the config it was built for
+	embedder:
+		std::sync::Mutex<Option<(Arc<Config>, Option<LLMClient>)>>,
 }
 
+impl ServerState {
+	pub fn new(config: Config, loader: Option<ConfigLoader>) -> Self {
+		Self {
+			config: RwLock::new(Arc::new(config)),
+			loader,
+			reloads: Default::default(),
+			started: std::time::Instant::now(),
+			health: Default::default(),
+			quotas: Default::default(),
+			embedder: Default::default(),
+		}
+	}
+
+	// the config new sessions start with
+	pub fn config(&self) -> Arc<Config> {
+		self.config.read().unwrap().clone()
+	}
+
+	pub(crate) fn set_config(&self, config: Config) {
+		*self.config.write().unwrap() = Arc::new(config);
+	}
+
+	// built once per config, and again after a reload; None unless search_embeddings is set
+	pub(crate) fn embedder(&self) -> Option<LLMClient> {
+		let config = self.config();
+		let mut embedder = self.embedder.lock().unwrap();
+		if let Some((built, client)) = &*embedder
+			&& Arc::ptr_eq(built, &config)
+		{
+			return client.clone();
+		}
+
+		let client = match (
+			config.search_embeddings,
+			config.client_type.clone(),
+			config.client_params.clone(),
+		) {
+			(true, Some(client_type), Some(params)) => LLMClient::new(
+				client_type,
+				params,
+			)
+			.inspect_err(|e| {
+				tracing::warn!(
+					"could not make the embedding client, searching by words only: {}",
+					e
+				)
+			})
+			.ok(),
+			_ => None,
+		};
+		*embedder = Some((config, client.clone()));
+		client
+	}
+}
+
+// the problem, and headers to answer with besides, e.g. Retry-After; the problem is boxed to keep
+// results small
 #[derive(Debug, Clone, Default)]
-pub struct AppError(pub ProblemDetails);
+pub struct AppError(pub Box<ProblemDetails>, pub HeaderMap);
 
 impl<E> From<E> for AppError
 where
@@ -100,21 +191,24 @@ where
 				ProblemDetails,
 			>(&value)
 			{
-				return Self(value.clone());
+				return Self(Box::new(value.clone()), HeaderMap::new());
 			}
 		}
 
 		Self(
-			ProblemDetails::new()
-				.with_detail(value.into().to_string())
-				.with_title("Uncategorized Error"),
+			Box::new(
+				ProblemDetails::new()
+					.with_detail(value.into().to_string())
+					.with_title("Uncategorized Error"),
+			),
+			HeaderMap::new(),
 		)
 	}
 }
 
 impl IntoResponse for AppError {
 	fn into_response(self) -> Response {
-		self.0.into_response()
+		(self.1, *self.0).into_response()
 	}
 }
 
@@ -134,12 +228,52 @@ impl FromRequestParts<Arc<ServerState>> for Auth {
 #[derive(Debug, Clone, Default)]
 pub struct ServiceAuth(pub bool);
 
+// the admin listener's token when there is one, see super::admin
 impl FromRequestParts<Arc<ServerState>> for ServiceAuth {
 	type Rejection = AppError;
 
 	async fn from_request_parts(
-		_parts: &mut Parts, _state: &Arc<ServerState>,
+		parts: &mut Parts, state: &Arc<ServerState>,
 	) -> core::result::Result<Self, Self::Rejection> {
-		Ok(Self(true))
+		let config = state.config();
+		let Some(token) =
+			config.admin.as_ref().and_then(|x| x.token.as_ref())
+		else {
+			return Ok(Self(true));
+		};
+
+		let given = parts
+			.headers
+			.get(AUTHORIZATION)
+			.and_then(|x| x.to_str().ok())
+			.and_then(|x| x.strip_prefix("Bearer "));
+		Ok(Self(given.is_some_and(|x| same(x, token))))
 	}
 }
+
+impl ServiceAuth {
+	// 401, for requests without the token or with a wrong one
+	pub(crate) fn challenge() -> AppError {
+		let mut headers = HeaderMap::new();
+		headers.insert(
+			WWW_AUTHENTICATE,
+			HeaderValue::from_static("Bearer"),
+		);
+		AppError(
+			Box::new(
+				ProblemDetails::from_status_code(
+					StatusCode::UNAUTHORIZED,
+				)
+				.with_detail("the admin token is missing or wrong"),
+			),
+			headers,
+		)
+	}
+}
+
+// compares in a time that doesn't depend on where they differ
+fn same(a: &str, b: &str) -> bool {
+	a.len() == b.len()
+		&& a.bytes().zip(b.bytes()).fold(0, |x, (a, b)| x | (a ^ b))
+			== 0
+}
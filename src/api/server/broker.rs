@@ -1,15 +1,20 @@
 use crate::api::server::PromptResponse;
 
-use super::McpRequest;
+use super::dispatch::rpc_id;
+use super::redact::Pseudonyms;
+use super::{Config, McpRequest};
+use crate::mcp::policy::ConsentLog;
+use crate::mcp::search::SearchIndex;
 use anyhow::Result;
 use std::{
-	collections::HashMap,
+	collections::{HashMap, VecDeque},
 	sync::{Arc, LazyLock},
 	time::Instant,
 };
 use tokio::sync::{
-	Mutex,
+	Mutex, Notify,
 	mpsc::{Receiver, Sender, channel},
+	oneshot,
 };
 use uuid::Uuid;
 
@@ -18,11 +23,15 @@ pub static GLOBAL_BROKER: LazyLock<GlobalBroker> =
 	LazyLock::new(|| Default::default());
 pub(crate) const CHANNEL_SIZE: usize = 1000;
 const TIMEOUT_SECS: u64 = 600;
+// how much of a session's events, serialized, is kept for clients that reconnect; a stream is
+// mostly tokens of a few bytes each
+const REPLAY_BYTES: usize = 1024 * 1024;
+// how long a retried request is recognized by its Idempotency-Key
+const IDEMPOTENCY_TTL_SECS: u64 = 3600;
 
 #[derive(Debug)]
 pub struct BrokerPipe<T> {
-	last_message: Instant,
-	sender: Sender<T>,
+	sender: PipeSender<T>,
 	receiver: Receiver<T>,
 }
 
@@ -33,34 +42,197 @@ where
 	pub fn new() -> Self {
 		let (sender, receiver) = channel(CHANNEL_SIZE);
 		Self {
-			sender,
+			sender: PipeSender {
+				sender,
+				last_message: Arc::new(std::sync::Mutex::new(
+					Instant::now(),
+				)),
+			},
 			receiver,
-			last_message: Instant::now(),
 		}
 	}
 
 	pub async fn next_message(&mut self) -> Option<T> {
 		self.receiver.recv().await.map(|x| {
-			self.last_message = Instant::now();
+			self.sender.touch();
 			x
 		})
 	}
 
 	pub async fn send_message(&mut self, msg: T) -> Result<()> {
-		Ok(self.sender.send(msg).await.map(|x| {
-			self.last_message = Instant::now();
-			x
-		})?)
+		self.sender.send(msg).await
 	}
 
 	pub fn last_message(&self) -> Instant {
-		self.last_message.clone()
+		self.sender.last_message()
+	}
+
+	// messages sent and not yet received
+	pub fn depth(&self) -> usize {
+		self.sender.depth()
 	}
 
 	pub fn check_timeout(&self) -> bool {
 		std::time::Instant::now()
 			- std::time::Duration::from_secs(TIMEOUT_SECS)
-			> self.last_message
+			> self.last_message()
+	}
+
+	// sends without holding the pipe's lock, which its receiver needs to make room
+	pub(crate) fn sender(&self) -> PipeSender<T> {
+		self.sender.clone()
+	}
+}
+
+// the sending end of a pipe, and what is known about it without locking it
+#[derive(Debug)]
+pub(crate) struct PipeSender<T> {
+	sender: Sender<T>,
+	last_message: Arc<std::sync::Mutex<Instant>>,
+}
+
+impl<T> Clone for PipeSender<T> {
+	fn clone(&self) -> Self {
+		Self {
+			sender: self.sender.clone(),
+			last_message: self.last_message.clone(),
+		}
+	}
+}
+
+impl<T> PipeSender<T> {
+	pub(crate) async fn send(&self, msg: T) -> Result<()> {
+		self.sender
+			.send(msg)
+			.await
+			.map_err(|_| anyhow::anyhow!("channel closed"))?;
+		self.touch();
+		Ok(())
+	}
+
+	fn touch(&self) {
+		if let Ok(mut x) = self.last_message.lock() {
+			*x = Instant::now();
+		}
+	}
+
+	pub(crate) fn last_message(&self) -> Instant {
+		self.last_message
+			.lock()
+			.map(|x| *x)
+			.unwrap_or_else(|x| *x.into_inner())
+	}
+
+	// messages sent and not yet received
+	pub(crate) fn depth(&self) -> usize {
+		self.sender.max_capacity() - self.sender.capacity()
+	}
+}
+
+// state of a conversation that outlives any one SSE connection to it
+#[derive(Debug, Default)]
+pub struct Session {
+	// tools/call requests sent to the phone, keyed by JSON-RPC id
+	pub(crate) calls: HashMap<String, oneshot::Sender<String>>,
+	// actions waiting for the user's approval, keyed by confirmation id
+	pub(crate) confirmations: HashMap<Uuid, oneshot::Sender<bool>>,
+	// what the privacy policy and the user decided about tool calls
+	pub(crate) consent: ConsentLog,
+	// pseudonyms handed to the model in place of redacted values
+	pub(crate) pseudonyms: Pseudonyms,
+	// tools the phone registered, offered to the model instead of the built-in ones
+	pub(crate) tools: Option<Vec<rmcp::model::Tool>>,
+	// events sent on the session's prompt streams, for clients that lost their connection
+	pub(crate) replay: Replay,
+	// what the user said while a prompt was running, see super::handlers::input
+	pub(crate) interjections: Interjections,
+	// the phone's contacts and messages, as uploaded for super::handlers::search
+	pub(crate) index: SearchIndex,
+	// the conversation's trace, see super::telemetry
+	pub(crate) span: Option<tracing::Span>,
+	// the config the session started with, kept across reloads; see super::reload
+	pub(crate) config: Option<Arc<Config>>,
+	// set when an operator closes the session, to end its prompt streams; see super::admin
+	pub(crate) closed: bool,
+}
+
+impl Session {
+	// whether an event still has to reach the phone: a tool call that was answered or given up
+	// on is never sent again, since the phone would run it again
+	pub(crate) fn outstanding(&self, event: &PromptResponse) -> bool {
+		match event {
+			PromptResponse::McpRequest(x) => rpc_id(&x.command)
+				.is_ok_and(|x| self.calls.contains_key(&x)),
+			_ => true,
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Interjections {
+	pending: Vec<(String, bool)>,
+	// woken when urgent input arrives
+	pub(crate) urgent: Arc<Notify>,
+}
+
+impl Interjections {
+	pub(crate) fn push(&mut self, text: String, urgent: bool) {
+		self.pending.push((text, urgent));
+		if urgent {
+			self.urgent.notify_one();
+		}
+	}
+
+	pub(crate) fn has_urgent(&self) -> bool {
+		self.pending.iter().any(|x| x.1)
+	}
+
+	// everything said so far, in order
+	pub(crate) fn take(&mut self) -> Vec<String> {
+		self.pending.drain(..).map(|x| x.0).collect()
+	}
+}
+
+// NOTE: every event taken off the session's pipes is numbered and kept here before it is sent, and
+// each SSE connection sends what it hasn't sent yet in order. A client that reconnects with the
+// number of the last event it saw gets everything after it, including events an earlier
+// connection took off the pipes but never delivered.
+#[derive(Debug, Default)]
+pub(crate) struct Replay {
+	last: u64,
+	// with the size of each
+	events: VecDeque<(u64, PromptResponse, usize)>,
+	bytes: usize,
+}
+
+impl Replay {
+	pub(crate) fn push(&mut self, event: PromptResponse) -> u64 {
+		let size = serde_json::to_vec(&event).map_or(0, |x| x.len());
+		self.last += 1;
+		self.events.push_back((self.last, event, size));
+		self.bytes += size;
+		// the newest is kept however big it is
+		while self.bytes > REPLAY_BYTES && self.events.len() > 1 {
+			if let Some(x) = self.events.pop_front() {
+				self.bytes -= x.2;
+			}
+		}
+		self.last
+	}
+
+	pub(crate) fn last(&self) -> u64 {
+		self.last
+	}
+
+	// events numbered after cursor that are still kept
+	pub(crate) fn since(
+		&self, cursor: u64,
+	) -> Vec<(u64, PromptResponse)> {
+		self.events
+			.iter()
+			.filter(|x| x.0 > cursor)
+			.map(|x| (x.0, x.1.clone()))
+			.collect()
 	}
 }
 
@@ -71,19 +243,49 @@ where
 pub struct Broker {
 	mcp: HashMap<uuid::Uuid, Arc<Mutex<BrokerPipe<McpRequest>>>>,
 	prompt: HashMap<uuid::Uuid, Arc<Mutex<BrokerPipe<PromptResponse>>>>,
+	session: HashMap<uuid::Uuid, Arc<Mutex<Session>>>,
+	// Idempotency-Keys of requests made on each session
+	keys: HashMap<uuid::Uuid, HashMap<String, IdempotencyRecord>>,
+	// the principal that opened each session, see super::quota
+	owners: HashMap<uuid::Uuid, String>,
+	// each session's prompt and mcp pipes, as seen from outside their locks
+	senders: HashMap<uuid::Uuid, (PromptSender, McpSender)>,
+}
+
+#[derive(Debug, Clone)]
+struct IdempotencyRecord {
+	at: Instant,
+	// where the session's events stood when the request was made
+	cursor: u64,
+}
+
+// where an Idempotency-Key is looked up: the session a request names, or, for a request that
+// names none, every session of the principal making it
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum KeyScope<'a> {
+	Session(uuid::Uuid),
+	Owner(&'a str),
 }
 
 pub(crate) type PromptPipe = Arc<Mutex<BrokerPipe<PromptResponse>>>;
 pub(crate) type McpPipe = Arc<Mutex<BrokerPipe<McpRequest>>>;
+pub(crate) type SessionHandle = Arc<Mutex<Session>>;
+pub(crate) type PromptSender = PipeSender<PromptResponse>;
+pub(crate) type McpSender = PipeSender<McpRequest>;
 
 impl Broker {
 	// FIXME: replace anyhow with thiserror here
 	pub fn create(&mut self) -> Result<uuid::Uuid> {
 		let uuid = Uuid::new_v4();
-		let prompt_proxy = Arc::new(Mutex::new(BrokerPipe::new()));
-		let mcp_proxy = Arc::new(Mutex::new(BrokerPipe::new()));
+		let prompt_proxy = BrokerPipe::new();
+		let mcp_proxy = BrokerPipe::new();
+		self.senders
+			.insert(uuid, (prompt_proxy.sender(), mcp_proxy.sender()));
+		let prompt_proxy = Arc::new(Mutex::new(prompt_proxy));
+		let mcp_proxy = Arc::new(Mutex::new(mcp_proxy));
 		self.prompt.insert(uuid, prompt_proxy);
 		self.mcp.insert(uuid, mcp_proxy);
+		self.session.insert(uuid, Default::default());
 
 		Ok(uuid)
 	}
@@ -96,9 +298,84 @@ impl Broker {
 		self.prompt.get(&id).cloned()
 	}
 
+	pub fn get_session(&self, id: uuid::Uuid) -> Option<SessionHandle> {
+		self.session.get(&id).cloned()
+	}
+
+	pub(crate) fn handles(
+		&self,
+	) -> Vec<(Uuid, PromptSender, McpSender, SessionHandle)> {
+		self.session
+			.iter()
+			.filter_map(|(id, session)| {
+				let (prompt, mcp) = self.senders.get(id)?.clone();
+				Some((*id, prompt, mcp, session.clone()))
+			})
+			.collect()
+	}
+
 	pub fn expire(&mut self, id: uuid::Uuid) {
 		self.prompt.remove(&id);
 		self.mcp.remove(&id);
+		self.session.remove(&id);
+		self.keys.remove(&id);
+		self.owners.remove(&id);
+		self.senders.remove(&id);
+	}
+
+	pub(crate) fn set_owner(&mut self, id: uuid::Uuid, owner: &str) {
+		if self.session.contains_key(&id) {
+			self.owners.insert(id, owner.to_string());
+		}
+	}
+
+	pub(crate) fn owner(&self, id: uuid::Uuid) -> Option<String> {
+		self.owners.get(&id).cloned()
+	}
+
+	// sessions the principal has open
+	pub(crate) fn owned_by(&self, owner: &str) -> usize {
+		self.owners.values().filter(|x| *x == owner).count()
+	}
+
+	// the session and cursor of an earlier request with this key, within the scope; one caller's
+	// keys never match another's
+	pub(crate) fn idempotent(
+		&mut self, key: &str, scope: KeyScope<'_>,
+	) -> Option<(uuid::Uuid, u64)> {
+		let ttl = std::time::Duration::from_secs(IDEMPOTENCY_TTL_SECS);
+		for keys in self.keys.values_mut() {
+			keys.retain(|_, x| x.at.elapsed() < ttl);
+		}
+
+		self.keys
+			.iter()
+			.filter(|(x, _)| match scope {
+				KeyScope::Session(id) => **x == id,
+				KeyScope::Owner(owner) => {
+					self.owners.get(*x).is_some_and(|x| x == owner)
+				}
+			})
+			.find_map(|(x, keys)| keys.get(key).map(|k| (*x, k.cursor)))
+	}
+
+	pub(crate) fn store_key(
+		&mut self, id: uuid::Uuid, key: &str, cursor: u64,
+	) {
+		self.keys.entry(id).or_default().insert(
+			key.to_string(),
+			IdempotencyRecord {
+				at: Instant::now(),
+				cursor,
+			},
+		);
+	}
+
+	// for requests that failed, so that trying again isn't taken for a duplicate
+	pub(crate) fn forget_key(&mut self, id: uuid::Uuid, key: &str) {
+		if let Some(keys) = self.keys.get_mut(&id) {
+			keys.remove(key);
+		}
 	}
 }
 
@@ -106,7 +383,7 @@ impl Broker {
 mod tests {
 	use crate::api::server::{
 		PromptResponse,
-		broker::{Broker, CHANNEL_SIZE},
+		broker::{Broker, CHANNEL_SIZE, KeyScope},
 	};
 	use anyhow::anyhow;
 	use tokio::sync::mpsc::channel;
@@ -118,7 +395,7 @@ mod tests {
 		let id = broker.create().unwrap();
 		let proxy = broker.get_prompt(id).unwrap();
 		let lock = proxy.lock().await;
-		let start = lock.last_message.clone();
+		let start = lock.last_message();
 		drop(lock);
 
 		let (s, mut r) = channel(1);
@@ -148,7 +425,7 @@ mod tests {
 
 		let proxy = broker.get_prompt(id).unwrap();
 		let lock = proxy.lock().await;
-		assert_ne!(lock.last_message, start);
+		assert_ne!(lock.last_message(), start);
 	}
 
 	#[tokio::test]
@@ -158,7 +435,7 @@ mod tests {
 		let id = broker.create().unwrap();
 		let proxy = broker.get_prompt(id).unwrap();
 		let lock = proxy.lock().await;
-		let start = lock.last_message.clone();
+		let start = lock.last_message();
 		drop(lock);
 
 		let (s, mut r) = channel(2);
@@ -171,6 +448,7 @@ mod tests {
 			let lock = proxy.lock().await;
 			for _ in 0..CHANNEL_SIZE {
 				match lock
+					.sender
 					.sender
 					.send(PromptResponse::PromptResponse(
 						"hello, world!".into(),
@@ -224,6 +502,112 @@ mod tests {
 
 		let proxy = broker.get_prompt(id).unwrap();
 		let lock = proxy.lock().await;
-		assert_ne!(lock.last_message, start);
+		assert_ne!(lock.last_message(), start);
+	}
+
+	#[test]
+	fn test_replay() {
+		let mut replay = super::Replay::default();
+		// a stream's worth of tokens is kept whole
+		for i in 0..10_000 {
+			replay.push(PromptResponse::PromptResponse(i.to_string()));
+		}
+		assert_eq!(replay.last(), 10_000);
+		assert!(replay.since(replay.last()).is_empty());
+		assert_eq!(replay.since(replay.last() - 1)[0].0, replay.last());
+		assert_eq!(replay.since(0).len(), 10_000);
+
+		// the oldest events are gone once they are too big
+		let big = "x".repeat(super::REPLAY_BYTES / 4);
+		for _ in 0..4 {
+			replay.push(PromptResponse::PromptResponse(big.clone()));
+		}
+		let kept = replay.since(0);
+		assert_eq!(kept.len(), 3);
+		assert_eq!(kept[0].0, 10_002);
+		assert_eq!(kept[2].0, replay.last());
+	}
+
+	#[test]
+	fn test_outstanding() {
+		let mut session = super::Session::default();
+		let call = |id: &str| {
+			PromptResponse::McpRequest(crate::api::server::McpRequest {
+				connection_id: Default::default(),
+				command:
+					serde_json::json!({"jsonrpc": "2.0", "id": id})
+						.to_string(),
+				traceparent: None,
+			})
+		};
+		let (s, _r) = tokio::sync::oneshot::channel();
+		session.calls.insert("pending".into(), s);
+
+		// answered tool calls aren't sent twice, everything else is
+		assert!(session.outstanding(&call("pending")));
+		assert!(!session.outstanding(&call("answered")));
+		assert!(session.outstanding(&PromptResponse::PromptResponse(
+			"hello".into()
+		)));
+	}
+
+	#[test]
+	fn test_idempotency_keys() {
+		let mut broker = Broker::default();
+		let a = broker.create().unwrap();
+		let b = broker.create().unwrap();
+
+		broker.set_owner(a, "alice");
+		broker.set_owner(b, "bob");
+
+		broker.store_key(a, "key-1", 5);
+		assert_eq!(
+			broker.idempotent("key-1", KeyScope::Owner("alice")),
+			Some((a, 5))
+		);
+		assert_eq!(
+			broker.idempotent("key-1", KeyScope::Session(a)),
+			Some((a, 5))
+		);
+		// keys belong to their session, and their session's owner
+		assert_eq!(
+			broker.idempotent("key-1", KeyScope::Session(b)),
+			None
+		);
+		assert_eq!(
+			broker.idempotent("key-1", KeyScope::Owner("bob")),
+			None
+		);
+
+		broker.forget_key(a, "key-1");
+		assert_eq!(
+			broker.idempotent("key-1", KeyScope::Owner("alice")),
+			None
+		);
+
+		broker.store_key(b, "key-2", 0);
+		broker.expire(b);
+		assert_eq!(
+			broker.idempotent("key-2", KeyScope::Owner("bob")),
+			None
+		);
+	}
+
+	#[test]
+	fn test_owners() {
+		let mut broker = Broker::default();
+		let a = broker.create().unwrap();
+		let b = broker.create().unwrap();
+		broker.set_owner(a, "alice");
+		broker.set_owner(b, "alice");
+		assert_eq!(broker.owned_by("alice"), 2);
+		assert_eq!(broker.owner(a).as_deref(), Some("alice"));
+
+		broker.expire(a);
+		assert_eq!(broker.owned_by("alice"), 1);
+		assert_eq!(broker.owner(a), None);
+		// only sessions the broker has
+		broker.set_owner(a, "alice");
+		assert_eq!(broker.owned_by("alice"), 1);
 	}
 }
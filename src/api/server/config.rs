@@ -1,11 +1,31 @@
 use crate::api::llm::{LLMClientParams, LLMClientType};
+use crate::api::server::{
+	AdminConfig, CorsConfig, LimitsConfig, LoggingConfig, QuotaConfig,
+	RedactionConfig, TlsConfig, TracingConfig, logging, telemetry, tls,
+};
+use crate::mcp::policy::Policy;
 
-use serde::Deserialize;
-use std::{net::SocketAddr, path::PathBuf};
-use tracing::info;
-use tracing_subscriber::FmtSubscriber;
+use serde::{Deserialize, Serialize};
+use serde_yaml_ng::{Mapping, Value};
+use std::{
+	collections::HashMap,
+	net::SocketAddr,
+	path::{Path, PathBuf},
+	sync::Arc,
+};
+use tracing_subscriber::{
+	EnvFilter, layer::SubscriberExt, util::SubscriberInitExt,
+};
 
-#[derive(Debug, Clone, Deserialize)]
+// NOTE: a config is read in layers: the YAML file, or nothing, then ALLELO_* environment variables
+// over it, then the command line (see allelo-ai-api). A variable names a key by its path, with
+// "__" between levels, e.g. ALLELO_LOG_LEVEL or ALLELO_CLIENT_PARAMS__BASE_URL, and its value is
+// read as YAML, unless the key is a secret or holds a string, which is taken as it is. Variables
+// not starting with a top-level key are left alone; some of them are the command line's. Secrets can be kept out of the file: a SECRET_KEYS key like api_key can be given
+// as api_key_file, a file holding it, or api_key_env, a variable holding it. Every problem found
+// is reported with the key it is about, not just the first one.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum LogLevel {
 	#[serde(rename = "warn")]
 	Warn,
@@ -43,37 +63,543 @@ impl From<tracing::Level> for LogLevel {
 	}
 }
 
-#[derive(Debug, Clone, Deserialize)]
+const ENV_PREFIX: &str = "ALLELO_";
+// keys that can be read from a file or another variable
+const SECRET_KEYS: &[&str] = &["api_key", "token"];
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:8999";
+const DEFAULT_TOOL_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_CONFIRMATION_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_MAX_REGISTERED_TOOLS: usize = 128;
+const DEFAULT_MAX_TOOL_LIST_BYTES: usize = 256 * 1024;
+
+fn default_listen() -> SocketAddr {
+	DEFAULT_LISTEN.parse().unwrap()
+}
+
+fn default_log_level() -> LogLevel {
+	LogLevel::Info
+}
+
+fn default_tool_timeout_secs() -> u64 {
+	DEFAULT_TOOL_TIMEOUT_SECS
+}
+
+fn default_confirmation_timeout_secs() -> u64 {
+	DEFAULT_CONFIRMATION_TIMEOUT_SECS
+}
+
+fn default_max_registered_tools() -> usize {
+	DEFAULT_MAX_REGISTERED_TOOLS
+}
+
+fn default_max_tool_list_bytes() -> usize {
+	DEFAULT_MAX_TOOL_LIST_BYTES
+}
+
+// pages from anywhere may call the public listener unless the config narrows it
+fn default_cors() -> CorsConfig {
+	CorsConfig {
+		allow_origins: vec!["*".into()],
+		..Default::default()
+	}
+}
+
+// every setting at its default, with what it does; the output of allelo-ai-api print-default-config
+pub const DEFAULT_CONFIG: &str = include_str!("default_config.yaml");
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+	#[serde(default = "default_listen")]
 	pub listen: SocketAddr,
+	#[serde(default = "default_log_level")]
 	pub log_level: LogLevel,
+	// format, per-module levels, and whether the user's data may be logged
+	#[serde(default)]
+	pub logging: LoggingConfig,
 	pub client_type: Option<LLMClientType>,
 	pub client_params: Option<LLMClientParams>,
+	// how long the phone gets to answer a tool call
+	#[serde(default = "default_tool_timeout_secs")]
+	pub tool_timeout_secs: u64,
+	// how long the user gets to approve an action before it counts as denied
+	#[serde(default = "default_confirmation_timeout_secs")]
+	pub confirmation_timeout_secs: u64,
+	// which tools, for which contacts, the model may call without asking the user
+	#[serde(default)]
+	pub tool_policy: Policy,
+	// what is taken out of tool results before the model sees them
+	#[serde(default)]
+	pub redaction: RedactionConfig,
+	// limits on the tool list a phone registers for its session
+	#[serde(default = "default_max_registered_tools")]
+	pub max_registered_tools: usize,
+	#[serde(default = "default_max_tool_list_bytes")]
+	pub max_tool_list_bytes: usize,
+	// rank /search results by meaning as well as by words, with embeddings from the LLM backend
+	#[serde(default)]
+	pub search_embeddings: bool,
+	// OTLP export of traces
+	#[serde(default)]
+	pub tracing: TracingConfig,
+	// serve HTTPS, and maybe ask clients for certificates
+	#[serde(default)]
+	pub tls: Option<TlsConfig>,
+	// a second listener for the operators' routes, kept off the public one
+	#[serde(default)]
+	pub admin: Option<AdminConfig>,
+	// which web pages may call the public listener
+	#[serde(default = "default_cors")]
+	pub cors: CorsConfig,
+	// request body sizes and durations
+	#[serde(default)]
+	pub limits: LimitsConfig,
+	// requests, sessions and generations per principal, and generations per node
+	#[serde(default)]
+	pub quotas: QuotaConfig,
 }
 
 impl Default for Config {
 	fn default() -> Self {
 		Config {
-			listen: "127.0.0.1:8999".parse().unwrap(),
-			log_level: LogLevel::Info,
+			listen: default_listen(),
+			log_level: default_log_level(),
+			logging: LoggingConfig::default(),
 			client_params: None,
 			client_type: None,
+			tool_timeout_secs: DEFAULT_TOOL_TIMEOUT_SECS,
+			confirmation_timeout_secs:
+				DEFAULT_CONFIRMATION_TIMEOUT_SECS,
+			tool_policy: Policy::default(),
+			redaction: RedactionConfig::default(),
+			max_registered_tools: DEFAULT_MAX_REGISTERED_TOOLS,
+			max_tool_list_bytes: DEFAULT_MAX_TOOL_LIST_BYTES,
+			search_embeddings: false,
+			tracing: TracingConfig::default(),
+			tls: None,
+			admin: None,
+			cors: default_cors(),
+			limits: LimitsConfig::default(),
+			quotas: QuotaConfig::default(),
 		}
 	}
 }
 
+// reads the config again the way it was read at startup, for reloads
+#[derive(Clone)]
+pub struct ConfigLoader(
+	Arc<dyn Fn() -> anyhow::Result<Config> + Send + Sync>,
+);
+
+impl ConfigLoader {
+	pub fn new(
+		f: impl Fn() -> anyhow::Result<Config> + Send + Sync + 'static,
+	) -> Self {
+		Self(Arc::new(f))
+	}
+
+	pub fn load(&self) -> anyhow::Result<Config> {
+		(self.0)()
+	}
+}
+
+impl std::fmt::Debug for ConfigLoader {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str("ConfigLoader")
+	}
+}
+
+// something wrong with one key of the config
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+	// e.g. client_params.base_url, or the variable it came from
+	pub key: String,
+	pub message: String,
+}
+
+impl Problem {
+	fn new(key: impl Into<String>, message: impl ToString) -> Self {
+		Self {
+			key: key.into(),
+			message: message.to_string(),
+		}
+	}
+}
+
+impl std::fmt::Display for Problem {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}: {}", self.key, self.message)
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+	#[error("could not read {0}: {1}")]
+	Read(PathBuf, std::io::Error),
+	#[error("{0}")]
+	Syntax(#[from] serde_yaml_ng::Error),
+	#[error("invalid configuration:{}", problems(.0))]
+	Invalid(Vec<Problem>),
+}
+
+fn problems(x: &[Problem]) -> String {
+	x.iter().map(|x| format!("\n  {}", x)).collect()
+}
+
 impl Config {
-	pub fn from_file(filename: PathBuf) -> anyhow::Result<Self> {
-		let r =
-			std::fs::OpenOptions::new().read(true).open(filename)?;
-		let this: Self = serde_yaml_ng::from_reader(r)?;
-		let subscriber = FmtSubscriber::builder()
-			.with_max_level(Into::<tracing::Level>::into(
-				this.log_level.clone(),
-			))
-			.finish();
-		tracing::subscriber::set_global_default(subscriber)?;
-		info!("Configuration parsed successfully.");
+	// reads the config file, with the environment over it; nothing is set up, see init_logging
+	pub fn from_file(filename: PathBuf) -> Result<Self, ConfigError> {
+		Self::load(&filename)
+	}
+
+	pub fn load(filename: &Path) -> Result<Self, ConfigError> {
+		let yaml = std::fs::read_to_string(filename)
+			.map_err(|e| ConfigError::Read(filename.into(), e))?;
+		Self::parse(&yaml, std::env::vars())
+	}
+
+	// the defaults, with the environment over them
+	pub fn from_env() -> Result<Self, ConfigError> {
+		Self::parse("", std::env::vars())
+	}
+
+	pub fn parse(
+		yaml: &str, env: impl IntoIterator<Item = (String, String)>,
+	) -> Result<Self, ConfigError> {
+		let env: HashMap<String, String> = env.into_iter().collect();
+		let mut value: Value = serde_yaml_ng::from_str(yaml)?;
+		if value.is_null() {
+			value = Value::Mapping(Mapping::new());
+		}
+
+		let mut problems = override_from_env(&mut value, &env);
+		resolve_secrets(&mut value, "", &env, &mut problems);
+		if !problems.is_empty() {
+			return Err(ConfigError::Invalid(problems));
+		}
+
+		let this: Self = serde_path_to_error::deserialize(value)
+			.map_err(|e| {
+				// an unknown key is reported against the key holding it, and "config" at the top
+				let mut key = e.path().to_string();
+				if e.inner().to_string().starts_with("unknown field") {
+					key = match key.rsplit_once('.') {
+						Some((x, _)) => x.to_string(),
+						None => ".".to_string(),
+					};
+				}
+				if key == "." {
+					key = "config".to_string();
+				}
+				ConfigError::Invalid(vec![Problem::new(key, e.inner())])
+			})?;
+
+		let problems = this.validate();
+		if !problems.is_empty() {
+			return Err(ConfigError::Invalid(problems));
+		}
 		Ok(this)
 	}
+
+	// what serde can't tell
+	fn validate(&self) -> Vec<Problem> {
+		let mut problems = Vec::new();
+
+		match (&self.client_type, &self.client_params) {
+			(Some(_), None) => problems.push(Problem::new(
+				"client_params",
+				"needed with client_type",
+			)),
+			(None, Some(_)) => problems.push(Problem::new(
+				"client_type",
+				"needed with client_params",
+			)),
+			_ => {}
+		}
+		if let Some(params) = &self.client_params
+			&& let Err(e) = url::Url::parse(&params.base_url)
+		{
+			problems.push(Problem::new("client_params.base_url", e));
+		}
+
+		for (key, value) in [
+			("tool_timeout_secs", self.tool_timeout_secs),
+			(
+				"confirmation_timeout_secs",
+				self.confirmation_timeout_secs,
+			),
+		] {
+			if value == 0 {
+				problems.push(Problem::new(key, "must be more than 0"));
+			}
+		}
+
+		if let Some(filter) = &self.logging.filter
+			&& let Err(e) = EnvFilter::builder().parse(filter)
+		{
+			problems.push(Problem::new("logging.filter", e));
+		}
+
+		for (i, rule) in self.redaction.rules.iter().enumerate() {
+			if let Err(e) = regex::Regex::new(&rule.pattern) {
+				problems.push(Problem::new(
+					format!("redaction.rules[{}].pattern", i),
+					e,
+				));
+			}
+		}
+
+		if let Some(endpoint) = &self.tracing.otlp_endpoint
+			&& let Err(e) = url::Url::parse(endpoint)
+		{
+			problems.push(Problem::new("tracing.otlp_endpoint", e));
+		}
+		if !(0.0..=1.0).contains(&self.tracing.sample_ratio) {
+			problems.push(Problem::new(
+				"tracing.sample_ratio",
+				"must be between 0 and 1",
+			));
+		}
+
+		if let Some(config) = &self.tls {
+			if config.reload_secs == 0 {
+				problems.push(Problem::new(
+					"tls.reload_secs",
+					"must be more than 0",
+				));
+			}
+			if let Err(e) = tls::check(config) {
+				problems.push(Problem::new("tls", format!("{:#}", e)));
+			}
+		}
+
+		if let Some(admin) = &self.admin {
+			if admin.listen == self.listen {
+				problems.push(Problem::new(
+					"admin.listen",
+					"must be different from listen",
+				));
+			}
+			if admin.token.as_ref().is_some_and(|x| x.is_empty()) {
+				problems.push(Problem::new("admin.token", "is empty"));
+			}
+		}
+
+		let mut cors = vec![("cors", &self.cors)];
+		if let Some(admin) = &self.admin {
+			cors.push(("admin.cors", &admin.cors));
+		}
+		for (prefix, cors) in cors {
+			for (key, e) in cors.check() {
+				problems.push(Problem::new(
+					format!("{}.{}", prefix, key),
+					e,
+				));
+			}
+		}
+
+		for (key, value) in [
+			("limits.prompt_body_bytes", self.limits.prompt_body_bytes),
+			(
+				"limits.tool_response_body_bytes",
+				self.limits.tool_response_body_bytes,
+			),
+			("limits.index_body_bytes", self.limits.index_body_bytes),
+			("limits.body_bytes", self.limits.body_bytes),
+			(
+				"limits.request_timeout_secs",
+				self.limits.request_timeout_secs as usize,
+			),
+			(
+				"limits.max_stream_secs",
+				self.limits.max_stream_secs as usize,
+			),
+		] {
+			if value == 0 {
+				problems.push(Problem::new(key, "must be more than 0"));
+			}
+		}
+		if self.limits.prompt_body_bytes < self.max_tool_list_bytes {
+			problems.push(Problem::new(
+				"limits.prompt_body_bytes",
+				"must be at least max_tool_list_bytes, or the tools can't be registered",
+			));
+		}
+
+		for (key, message) in self.quotas.check() {
+			problems
+				.push(Problem::new(format!("quotas.{}", key), message));
+		}
+
+		problems
+	}
+
+	// installs the global subscriber, for logs and for traces if they are exported; once per process
+	pub fn init_logging(&self) -> anyhow::Result<()> {
+		let level: tracing::Level = self.log_level.clone().into();
+		tracing_subscriber::registry()
+			.with(logging::layer(&self.logging, level)?)
+			.with(telemetry::init(&self.tracing)?)
+			.try_init()?;
+		Ok(())
+	}
+}
+
+// sets the keys named by ALLELO_* variables
+fn override_from_env(
+	value: &mut Value, env: &HashMap<String, String>,
+) -> Vec<Problem> {
+	let Ok(Value::Mapping(defaults)) =
+		serde_yaml_ng::to_value(Config::default())
+	else {
+		unreachable!("a config is a mapping");
+	};
+
+	let mut problems = Vec::new();
+	let mut vars: Vec<_> = env
+		.iter()
+		.filter_map(|(k, v)| Some((k, k.strip_prefix(ENV_PREFIX)?, v)))
+		.collect();
+	// parents before their children, so a child isn't lost when its parent is set
+	vars.sort();
+
+	for (var, name, x) in vars {
+		let path: Vec<String> =
+			name.split("__").map(|x| x.to_lowercase()).collect();
+		if !defaults.contains_key(path[0].as_str()) {
+			continue;
+		}
+
+		// a string as given, or a token of digits would be a number and "null" no token at all
+		let result = if is_string(&defaults, value, &path) {
+			set(value, &path, Value::String(x.clone()))
+		} else {
+			serde_yaml_ng::from_str(x)
+				.map_err(|e| e.to_string())
+				.and_then(|x| set(value, &path, x))
+		};
+		if let Err(e) = result {
+			problems.push(Problem::new(var.as_str(), e));
+		}
+	}
+	problems
+}
+
+// whether the key at path holds a string: a secret, or a key that is one by default or in the file
+fn is_string(
+	defaults: &Mapping, value: &Value, path: &[String],
+) -> bool {
+	let leaf = path.last().map(String::as_str).unwrap_or_default();
+	let secret = SECRET_KEYS.iter().any(|x| {
+		leaf == *x
+			|| leaf == format!("{}_file", x)
+			|| leaf == format!("{}_env", x)
+	});
+	let at = |x: &Value| {
+		path.iter()
+			.try_fold(x, |x, key| x.get(key.as_str()))
+			.is_some_and(Value::is_string)
+	};
+	secret || at(&Value::Mapping(defaults.clone())) || at(value)
+}
+
+fn set(
+	value: &mut Value, path: &[String], x: Value,
+) -> Result<(), String> {
+	let Some((key, rest)) = path.split_first() else {
+		*value = x;
+		return Ok(());
+	};
+
+	if value.is_null() {
+		*value = Value::Mapping(Mapping::new());
+	}
+	let Value::Mapping(map) = value else {
+		return Err(format!("{} is not a mapping", key));
+	};
+	set(
+		map.entry(Value::String(key.clone())).or_insert(Value::Null),
+		rest,
+		x,
+	)
+}
+
+// replaces secret_file and secret_env keys with the secret
+fn resolve_secrets(
+	value: &mut Value, path: &str, env: &HashMap<String, String>,
+	problems: &mut Vec<Problem>,
+) {
+	let Value::Mapping(map) = value else {
+		return;
+	};
+	let at = |key: &str| {
+		if path.is_empty() {
+			key.to_string()
+		} else {
+			format!("{}.{}", path, key)
+		}
+	};
+
+	for key in SECRET_KEYS {
+		let file = format!("{}_file", key);
+		let var = format!("{}_env", key);
+		let sources: Vec<_> =
+			[key.to_string(), file.clone(), var.clone()]
+				.into_iter()
+				.filter(|x| {
+					map.get(x.as_str()).is_some_and(|x| !x.is_null())
+				})
+				.collect();
+		if sources.len() > 1 {
+			problems.push(Problem::new(
+				at(key),
+				format!(
+					"only one of {} can be set",
+					sources.join(", ")
+				),
+			));
+			continue;
+		}
+
+		let secret = if let Some(x) = map.remove(file.as_str()) {
+			match x.as_str().map(std::fs::read_to_string) {
+				Some(Ok(x)) => {
+					x.trim_end_matches(['\r', '\n']).to_string()
+				}
+				Some(Err(e)) => {
+					problems.push(Problem::new(at(&file), e));
+					continue;
+				}
+				None => {
+					problems
+						.push(Problem::new(at(&file), "not a path"));
+					continue;
+				}
+			}
+		} else if let Some(x) = map.remove(var.as_str()) {
+			match x.as_str().and_then(|x| env.get(x)) {
+				Some(x) => x.clone(),
+				None => {
+					problems.push(Problem::new(
+						at(&var),
+						"no such environment variable",
+					));
+					continue;
+				}
+			}
+		} else {
+			continue;
+		};
+		map.insert(
+			Value::String(key.to_string()),
+			Value::String(secret),
+		);
+	}
+
+	for (key, x) in map.iter_mut() {
+		if let Some(key) = key.as_str() {
+			resolve_secrets(x, &at(key), env, problems);
+		}
+	}
 }
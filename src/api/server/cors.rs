@@ -0,0 +1,161 @@
+use anyhow::bail;
+use http::{HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+// NOTE: CORS only matters to browsers, deciding which web pages may call a listener from script.
+// The phone app isn't a browser and doesn't need it; a dashboard served from another origin does.
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CorsConfig {
+	// e.g. "https://dashboard.example.com", or "*" for any; with none, only pages of the listener's
+	// own origin can call it
+	#[serde(default)]
+	pub allow_origins: Vec<String>,
+	// e.g. ["GET", "POST"]; without them, every method the listener's routes take
+	#[serde(default)]
+	pub allow_methods: Option<Vec<String>>,
+	// request headers scripts may set, e.g. ["content-type"]; without them, the ones the listener's
+	// clients send
+	#[serde(default)]
+	pub allow_headers: Option<Vec<String>>,
+}
+
+impl CorsConfig {
+	fn allow_origin(&self) -> anyhow::Result<Option<AllowOrigin>> {
+		if self.allow_origins.is_empty() {
+			return Ok(None);
+		}
+		if self.allow_origins.iter().any(|x| x == "*") {
+			if self.allow_origins.len() > 1 {
+				bail!("\"*\" can't be listed with other origins");
+			}
+			return Ok(Some(AllowOrigin::any()));
+		}
+
+		let mut origins = Vec::new();
+		for x in &self.allow_origins {
+			let origin =
+				url::Url::parse(x)?.origin().ascii_serialization();
+			if origin != *x {
+				bail!(
+					"{} isn't an origin, e.g. https://example.com",
+					x
+				);
+			}
+			origins.push(HeaderValue::from_str(&origin)?);
+		}
+		Ok(Some(AllowOrigin::list(origins)))
+	}
+
+	fn allow_methods(
+		&self, default: &[Method],
+	) -> anyhow::Result<Vec<Method>> {
+		match &self.allow_methods {
+			Some(x) => Ok(x
+				.iter()
+				.map(|x| Method::from_bytes(x.as_bytes()))
+				.collect::<Result<_, _>>()?),
+			None => Ok(default.to_vec()),
+		}
+	}
+
+	fn allow_headers(
+		&self, default: &[HeaderName],
+	) -> anyhow::Result<Vec<HeaderName>> {
+		match &self.allow_headers {
+			Some(x) => Ok(x
+				.iter()
+				.map(|x| HeaderName::from_bytes(x.as_bytes()))
+				.collect::<Result<_, _>>()?),
+			None => Ok(default.to_vec()),
+		}
+	}
+
+	// what is wrong with it, by key, for validating a config
+	pub(crate) fn check(&self) -> Vec<(&'static str, anyhow::Error)> {
+		[
+			("allow_origins", self.allow_origin().err()),
+			("allow_methods", self.allow_methods(&[]).err()),
+			("allow_headers", self.allow_headers(&[]).err()),
+		]
+		.into_iter()
+		.filter_map(|(key, e)| Some((key, e?)))
+		.collect()
+	}
+
+	// nothing when no origin is allowed, since browsers enforce same-origin by themselves; methods
+	// and headers are used unless the config has its own
+	pub(crate) fn layer(
+		&self, methods: &[Method], headers: &[HeaderName],
+	) -> anyhow::Result<Option<CorsLayer>> {
+		let Some(origin) = self.allow_origin()? else {
+			return Ok(None);
+		};
+		Ok(Some(
+			CorsLayer::new()
+				.allow_origin(origin)
+				.allow_methods(self.allow_methods(methods)?)
+				.allow_headers(self.allow_headers(headers)?),
+		))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use http::header::CONTENT_TYPE;
+
+	#[test]
+	fn test_origins() {
+		let config = |x: &[&str]| CorsConfig {
+			allow_origins: x.iter().map(|x| x.to_string()).collect(),
+			..Default::default()
+		};
+
+		assert!(config(&[]).allow_origin().unwrap().is_none());
+		assert!(config(&["*"]).check().is_empty());
+		assert!(
+			config(&["https://a.example.com", "http://localhost:3000"])
+				.check()
+				.is_empty()
+		);
+		assert!(
+			!config(&["*", "https://a.example.com"]).check().is_empty()
+		);
+		assert!(
+			!config(&["https://a.example.com/admin"])
+				.check()
+				.is_empty()
+		);
+		assert!(!config(&["a.example.com"]).check().is_empty());
+	}
+
+	#[test]
+	fn test_methods_and_headers() {
+		let mut config = CorsConfig {
+			allow_origins: vec!["*".into()],
+			..Default::default()
+		};
+		assert_eq!(
+			config.allow_methods(&[Method::GET]).unwrap(),
+			[Method::GET]
+		);
+
+		config.allow_methods = Some(vec!["POST".into(), "PUT".into()]);
+		config.allow_headers = Some(vec!["content-type".into()]);
+		assert_eq!(
+			config.allow_methods(&[Method::GET]).unwrap(),
+			[Method::POST, Method::PUT]
+		);
+		assert_eq!(config.allow_headers(&[]).unwrap(), [CONTENT_TYPE]);
+		assert!(config.check().is_empty());
+
+		config.allow_methods = Some(vec!["GET POST".into()]);
+		config.allow_headers = Some(vec!["content type".into()]);
+		let keys: Vec<_> =
+			config.check().into_iter().map(|x| x.0).collect();
+		assert_eq!(keys, ["allow_methods", "allow_headers"]);
+	}
+}
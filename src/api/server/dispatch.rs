@@ -0,0 +1,683 @@
+use super::broker::{
+	GLOBAL_BROKER, McpPipe, PromptPipe, SessionHandle,
+};
+use super::redact::{Redactor, Revealer};
+use super::registry::{is_registered_action, to_llm_tool};
+use super::{metrics, telemetry};
+use crate::api::{
+	llm::{ToolDispatcher, ToolError},
+	server::{ActionConfirmation, Config, McpRequest, PromptResponse},
+};
+use crate::mcp::{
+	policy::{Policy, PolicyDecision, target},
+	tool::{is_action, is_tool},
+};
+
+use anyhow::anyhow;
+use std::{
+	sync::Arc,
+	time::{Duration, Instant},
+};
+use tokio::sync::{Notify, oneshot};
+use tracing::Instrument;
+
+type Result<T> = core::result::Result<T, ToolError>;
+
+// NOTE: tool calls from the model are sent to the phone as JSON-RPC tools/call requests over the
+// session's mcp pipe. The phone runs them against its MCP service and posts the JSON-RPC response
+// to /mcp_response, which hands it back here through the session's pending calls. Calls to action
+// tools are held until the user approves them through /confirm, and so are calls the privacy
+// policy says to ask about. The phone applies its own policy again before running anything.
+// Results are redacted before they go back to the model, see super::redact.
+pub(crate) struct SessionTools {
+	id: uuid::Uuid,
+	prompt: PromptPipe,
+	mcp: McpPipe,
+	session: SessionHandle,
+	tool_timeout: Duration,
+	confirmation_timeout: Duration,
+	policy: Policy,
+	redactor: Redactor,
+	// what the phone registered for the session when this prompt started, if anything
+	registered: Option<Vec<rmcp::model::Tool>>,
+	// see Interjections in super::broker
+	urgent: Arc<Notify>,
+}
+
+impl SessionTools {
+	pub(crate) async fn new(
+		id: uuid::Uuid, config: &Config,
+	) -> anyhow::Result<Self> {
+		let redactor = Redactor::new(&config.redaction)?;
+		let broker = GLOBAL_BROKER.lock().await;
+
+		match (
+			broker.get_prompt(id),
+			broker.get_mcp(id),
+			broker.get_session(id),
+		) {
+			(Some(prompt), Some(mcp), Some(session)) => {
+				let (registered, urgent) = {
+					let session = session.lock().await;
+					(
+						session.tools.clone(),
+						session.interjections.urgent.clone(),
+					)
+				};
+
+				Ok(Self {
+					id,
+					prompt,
+					mcp,
+					registered,
+					urgent,
+					session,
+					tool_timeout: Duration::from_secs(
+						config.tool_timeout_secs,
+					),
+					confirmation_timeout: Duration::from_secs(
+						config.confirmation_timeout_secs,
+					),
+					policy: config.tool_policy.clone(),
+					redactor,
+				})
+			}
+			_ => Err(anyhow!("stream closed")),
+		}
+	}
+
+	// reveals the pseudonyms handed out so far in the model's answer
+	pub(crate) async fn revealer(&self) -> Revealer {
+		self.session.lock().await.pseudonyms.revealer()
+	}
+
+	// an interjection can lead to more tool calls while the answer streams, and to new pseudonyms
+	pub(crate) async fn refresh(&self, revealer: &mut Revealer) {
+		revealer.refresh(&self.session.lock().await.pseudonyms);
+	}
+
+	async fn redact(&self, text: &str) -> String {
+		let mut session = self.session.lock().await;
+		self.redactor.redact(text, &mut session.pseudonyms)
+	}
+
+	fn is_action(&self, name: &str) -> bool {
+		is_action(name)
+			|| self
+				.registered
+				.iter()
+				.flatten()
+				.any(|x| x.name == name && is_registered_action(x))
+	}
+
+	async fn send(&self, response: PromptResponse) -> Result<()> {
+		let sender = self.prompt.lock().await.sender();
+		sender
+			.send(response)
+			.await
+			.map_err(|e| ToolError::Failed(e.to_string()))
+	}
+
+	async fn confirm(
+		&self, wrap: fn(ActionConfirmation) -> PromptResponse,
+		name: &str, arguments: &serde_json::Value,
+	) -> Result<()> {
+		let id = uuid::Uuid::new_v4();
+		let (s, r) = oneshot::channel();
+		self.session.lock().await.confirmations.insert(id, s);
+
+		self.send(wrap(ActionConfirmation {
+			id,
+			tool: name.into(),
+			arguments: arguments.clone(),
+		}))
+		.await?;
+
+		let result =
+			tokio::time::timeout(self.confirmation_timeout, r).await;
+		self.session.lock().await.confirmations.remove(&id);
+
+		match result {
+			Ok(Ok(true)) => Ok(()),
+			Ok(Ok(false)) => Err(ToolError::Denied),
+			Ok(Err(_)) => {
+				Err(ToolError::Failed("session closed".into()))
+			}
+			Err(_) => {
+				Err(ToolError::Timeout("the user's approval".into()))
+			}
+		}
+	}
+
+	// NOTE: the proxy can't resolve contact names, so rules only match the name as the model passed
+	// it. Action tools are always confirmed, the policy can only deny them outright.
+	async fn authorize(
+		&self, name: &str, arguments: &serde_json::Value,
+	) -> Result<()> {
+		let target = target(arguments);
+		let consent =
+			self.policy.consent(name, &Vec::from_iter(target.clone()));
+		let decided = self.session.lock().await.consent.decided(
+			consent,
+			name,
+			target.as_deref(),
+		);
+
+		let result = match decided {
+			Some(false) => Err(ToolError::NotPermitted(name.into())),
+			Some(true) if !self.is_action(name) => Ok(()),
+			_ if self.is_action(name) => {
+				self.confirm(
+					PromptResponse::ConfirmAction,
+					name,
+					arguments,
+				)
+				.await
+			}
+			_ => {
+				self.confirm(
+					PromptResponse::RequestConsent,
+					name,
+					arguments,
+				)
+				.await
+			}
+		};
+
+		let decision = PolicyDecision {
+			tool: name.into(),
+			target,
+			consent,
+			allowed: result.is_ok(),
+			decided_at: chrono::Utc::now(),
+		};
+		self.session.lock().await.consent.record(decision.clone());
+		self.send(PromptResponse::PolicyDecision(decision)).await?;
+
+		result
+	}
+
+	async fn call_mcp(
+		&self, name: &str, arguments: serde_json::Value,
+	) -> Result<String> {
+		let rpc_id = uuid::Uuid::new_v4().to_string();
+		let span = tracing::info_span!(
+			"mcp.request",
+			tool = %name,
+			rpc.id = %rpc_id,
+		);
+		let (s, r) = oneshot::channel();
+		self.session.lock().await.calls.insert(rpc_id.clone(), s);
+
+		let command = serde_json::json!({
+			"jsonrpc": "2.0",
+			"id": rpc_id,
+			"method": "tools/call",
+			"params": {
+				"name": name,
+				"arguments": arguments,
+			},
+		});
+
+		let sender = self.mcp.lock().await.sender();
+		sender
+			.send(McpRequest {
+				connection_id: self.id.to_string(),
+				command: command.to_string(),
+				traceparent: telemetry::traceparent(&span),
+			})
+			.await
+			.map_err(|e| ToolError::Failed(e.to_string()))?;
+
+		let result = tokio::time::timeout(self.tool_timeout, r)
+			.instrument(span)
+			.await;
+		self.session.lock().await.calls.remove(&rpc_id);
+
+		match result {
+			Ok(Ok(response)) => tool_result(&response),
+			Ok(Err(_)) => {
+				Err(ToolError::Failed("session closed".into()))
+			}
+			Err(_) => Err(ToolError::Timeout(format!(
+				"the phone to answer {}",
+				name
+			))),
+		}
+	}
+
+	// a call from the model, from its arguments to the redacted result
+	async fn dispatch(
+		&self, name: &str, arguments: &str,
+	) -> Result<String> {
+		let arguments: serde_json::Value = if arguments
+			.trim()
+			.is_empty()
+		{
+			serde_json::json!({})
+		} else {
+			serde_json::from_str(arguments).map_err(|e| {
+				ToolError::Failed(format!("invalid arguments: {}", e))
+			})?
+		};
+
+		// the model only knows the pseudonyms; the user and the phone get the real values
+		let mut arguments = arguments;
+		self.session
+			.lock()
+			.await
+			.pseudonyms
+			.reveal_value(&mut arguments);
+
+		if let Some(registered) = &self.registered
+			&& !registered.iter().any(|x| x.name == name)
+		{
+			return Err(ToolError::Failed(format!(
+				"no tool named {}",
+				name
+			)));
+		}
+
+		self.authorize(name, &arguments).await?;
+		match self.call_mcp(name, arguments).await {
+			Ok(output) => Ok(self.redact(&output).await),
+			Err(ToolError::Failed(e)) => {
+				Err(ToolError::Failed(self.redact(&e).await))
+			}
+			Err(e) => Err(e),
+		}
+	}
+}
+
+#[async_trait::async_trait]
+impl ToolDispatcher for SessionTools {
+	fn tools(&self) -> Option<Vec<llm::chat::Tool>> {
+		self.registered
+			.as_ref()
+			.map(|x| x.iter().map(to_llm_tool).collect())
+	}
+
+	async fn call(
+		&self, name: &str, arguments: &str,
+	) -> Result<String> {
+		let started = Instant::now();
+		let result = self.dispatch(name, arguments).await;
+
+		let outcome = match &result {
+			Ok(_) => "ok",
+			Err(ToolError::Denied) => "denied",
+			Err(ToolError::NotPermitted(_)) => "not_permitted",
+			Err(ToolError::Timeout(_)) => "timeout",
+			Err(ToolError::Failed(_)) => "failed",
+		};
+		// the model picks the name, so only the built-in tools are labeled with theirs
+		let tool = if is_tool(name) { name } else { "unknown" };
+		metrics::TOOL_CALLS
+			.inc(&[("tool", tool), ("outcome", outcome)]);
+		metrics::TOOL_DURATION.observe(
+			&[("tool", tool)],
+			started.elapsed().as_secs_f64(),
+		);
+		result
+	}
+
+	async fn interjections(&self) -> Vec<String> {
+		self.session.lock().await.interjections.take()
+	}
+
+	async fn interrupted(&self) {
+		loop {
+			// created first, so urgent input arriving during the check isn't missed
+			let urgent = self.urgent.notified();
+			if self.session.lock().await.interjections.has_urgent() {
+				return;
+			}
+			urgent.await;
+		}
+	}
+}
+
+// id of a JSON-RPC message, as a string no matter how the sender encoded it
+pub(crate) fn rpc_id(message: &str) -> anyhow::Result<String> {
+	let message: serde_json::Value = serde_json::from_str(message)?;
+	match message.get("id") {
+		Some(serde_json::Value::String(id)) => Ok(id.clone()),
+		Some(serde_json::Value::Number(id)) => Ok(id.to_string()),
+		_ => Err(anyhow!("JSON-RPC message has no id")),
+	}
+}
+
+// text content of a JSON-RPC tools/call response
+pub(crate) fn tool_result(response: &str) -> Result<String> {
+	let response: serde_json::Value = serde_json::from_str(response)
+		.map_err(|e| ToolError::Failed(e.to_string()))?;
+
+	if let Some(error) = response.get("error") {
+		return Err(ToolError::Failed(
+			error
+				.get("message")
+				.and_then(|x| x.as_str())
+				.unwrap_or("unknown error")
+				.into(),
+		));
+	}
+
+	let result = response.get("result").ok_or_else(|| {
+		ToolError::Failed("tool response has no result".into())
+	})?;
+
+	let text = result
+		.get("content")
+		.and_then(|x| x.as_array())
+		.map(|content| {
+			content
+				.iter()
+				.filter_map(|x| x.get("text").and_then(|x| x.as_str()))
+				.collect::<Vec<_>>()
+				.join("\n")
+		})
+		.unwrap_or_default();
+
+	if result
+		.get("isError")
+		.and_then(|x| x.as_bool())
+		.unwrap_or(false)
+	{
+		Err(ToolError::Failed(text))
+	} else {
+		Ok(text)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::api::server::broker::BrokerPipe;
+	use std::sync::Arc;
+	use tokio::sync::Mutex;
+
+	// polls like prompt_multiplex does, so the lock is free for the sender in between
+	async fn next<T: Sync + Send + 'static>(
+		pipe: &Arc<Mutex<BrokerPipe<T>>>,
+	) -> T {
+		loop {
+			let mut lock = pipe.lock().await;
+			tokio::select! {
+				Some(x) = lock.next_message() => return x,
+				_ = tokio::time::sleep(Duration::from_millis(10)) => {}
+			}
+		}
+	}
+
+	// next event that asks something of the phone, skipping the policy decisions in between
+	async fn next_request(
+		pipe: &Arc<Mutex<BrokerPipe<PromptResponse>>>,
+	) -> PromptResponse {
+		loop {
+			match next(pipe).await {
+				PromptResponse::PolicyDecision(_) => {}
+				x => return x,
+			}
+		}
+	}
+
+	async fn answer(
+		pipe: &McpPipe, session: &SessionHandle, text: &str,
+	) {
+		let request = next(pipe).await;
+		let call_id = rpc_id(&request.command).unwrap();
+		let call = session.lock().await.calls.remove(&call_id).unwrap();
+		call.send(
+			serde_json::json!({
+				"jsonrpc": "2.0",
+				"id": call_id,
+				"result": {"content": [{"type": "text", "text": text}]},
+			})
+			.to_string(),
+		)
+		.unwrap();
+	}
+
+	#[tokio::test]
+	async fn test_action_confirmation() {
+		let id = GLOBAL_BROKER.lock().await.create().unwrap();
+		let tools = SessionTools::new(
+			id,
+			&Config {
+				confirmation_timeout_secs: 1,
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		let broker = GLOBAL_BROKER.lock().await.clone();
+		let prompt = broker.get_prompt(id).unwrap();
+		let mcp = broker.get_mcp(id).unwrap();
+		let session = broker.get_session(id).unwrap();
+		let args = r#"{"name":"sam","body":"running late"}"#;
+
+		let (result, _) =
+			tokio::join!(tools.call("send_message", args), async {
+				let PromptResponse::ConfirmAction(action) =
+					next_request(&prompt).await
+				else {
+					panic!("no confirmation was requested")
+				};
+				assert_eq!(action.tool, "send_message");
+				assert_eq!(action.arguments["body"], "running late");
+				let pending = session
+					.lock()
+					.await
+					.confirmations
+					.remove(&action.id);
+				pending.unwrap().send(false).unwrap();
+			});
+		assert_eq!(result, Err(ToolError::Denied));
+
+		let (result, _) =
+			tokio::join!(tools.call("send_message", args), async {
+				let PromptResponse::ConfirmAction(action) =
+					next_request(&prompt).await
+				else {
+					panic!("no confirmation was requested")
+				};
+				let pending = session
+					.lock()
+					.await
+					.confirmations
+					.remove(&action.id);
+				pending.unwrap().send(true).unwrap();
+				answer(&mcp, &session, "sent").await;
+			});
+		assert_eq!(result, Ok("sent".into()));
+
+		assert_eq!(
+			tools.call("send_message", args).await,
+			Err(ToolError::Timeout("the user's approval".into()))
+		);
+
+		GLOBAL_BROKER.lock().await.expire(id);
+	}
+
+	#[tokio::test]
+	async fn test_registered_action_confirmation() {
+		let id = GLOBAL_BROKER.lock().await.create().unwrap();
+		let broker = GLOBAL_BROKER.lock().await.clone();
+		let prompt = broker.get_prompt(id).unwrap();
+		let session = broker.get_session(id).unwrap();
+		// without annotations, a tool may change something
+		session.lock().await.tools = Some(vec![
+			serde_json::from_value(serde_json::json!({
+				"name": "unlock_door",
+				"description": "unlocks the front door",
+				"inputSchema": {"type": "object", "properties": {}},
+			}))
+			.unwrap(),
+		]);
+		let tools =
+			SessionTools::new(id, &Config::default()).await.unwrap();
+
+		let (result, _) =
+			tokio::join!(tools.call("unlock_door", "{}"), async {
+				let PromptResponse::ConfirmAction(action) =
+					next_request(&prompt).await
+				else {
+					panic!("no confirmation was requested")
+				};
+				assert_eq!(action.tool, "unlock_door");
+				let pending = session
+					.lock()
+					.await
+					.confirmations
+					.remove(&action.id);
+				pending.unwrap().send(false).unwrap();
+			});
+		assert_eq!(result, Err(ToolError::Denied));
+
+		GLOBAL_BROKER.lock().await.expire(id);
+	}
+
+	#[tokio::test]
+	async fn test_tool_policy() {
+		let id = GLOBAL_BROKER.lock().await.create().unwrap();
+		let tools = SessionTools::new(
+			id,
+			&Config {
+				tool_policy: serde_yaml_ng::from_str(
+					r#"
+rules:
+  - tool: chat_messages
+    consent: ask_once
+  - tool: contact_status
+    consent: deny
+"#,
+				)
+				.unwrap(),
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		let broker = GLOBAL_BROKER.lock().await.clone();
+		let prompt = broker.get_prompt(id).unwrap();
+		let mcp = broker.get_mcp(id).unwrap();
+		let session = broker.get_session(id).unwrap();
+		let args = r#"{"name":"sam"}"#;
+
+		assert_eq!(
+			tools.call("contact_status", args).await,
+			Err(ToolError::NotPermitted("contact_status".into()))
+		);
+		let PromptResponse::PolicyDecision(decision) =
+			next(&prompt).await
+		else {
+			panic!("the decision was not reported")
+		};
+		assert_eq!(decision.target.as_deref(), Some("sam"));
+		assert!(!decision.allowed);
+
+		let (result, _) =
+			tokio::join!(tools.call("chat_messages", args), async {
+				let PromptResponse::RequestConsent(request) =
+					next_request(&prompt).await
+				else {
+					panic!("consent was not requested")
+				};
+				assert_eq!(request.tool, "chat_messages");
+				let pending = session
+					.lock()
+					.await
+					.confirmations
+					.remove(&request.id);
+				pending.unwrap().send(true).unwrap();
+				answer(&mcp, &session, "hi").await;
+			});
+		assert_eq!(result, Ok("hi".into()));
+
+		// granted once for sam, so not asked again
+		let (result, _) =
+			tokio::join!(tools.call("chat_messages", args), async {
+				answer(&mcp, &session, "hi again").await;
+			});
+		assert_eq!(result, Ok("hi again".into()));
+
+		let allowed: Vec<bool> = session
+			.lock()
+			.await
+			.consent
+			.decisions()
+			.iter()
+			.map(|x| x.allowed)
+			.collect();
+		assert_eq!(allowed, vec![false, true, true]);
+
+		GLOBAL_BROKER.lock().await.expire(id);
+	}
+
+	#[test]
+	fn test_tool_result() {
+		assert_eq!(
+			tool_result(
+				r#"{"jsonrpc":"2.0","id":"1","result":{"content":[{"type":"text","text":"hello"}]}}"#
+			),
+			Ok("hello".into())
+		);
+		assert_eq!(
+			tool_result(
+				r#"{"jsonrpc":"2.0","id":"1","result":{"content":[{"type":"text","text":"no contact matches 'bob'"}],"isError":true}}"#
+			),
+			Err(ToolError::Failed("no contact matches 'bob'".into()))
+		);
+		assert_eq!(
+			tool_result(
+				r#"{"jsonrpc":"2.0","id":"1","error":{"code":-32602,"message":"tool not found"}}"#
+			),
+			Err(ToolError::Failed("tool not found".into()))
+		);
+	}
+
+	#[test]
+	fn test_rpc_id() {
+		assert_eq!(rpc_id(r#"{"id":"abc"}"#).unwrap(), "abc");
+		assert_eq!(rpc_id(r#"{"id":7}"#).unwrap(), "7");
+		assert!(rpc_id(r#"{"result":{}}"#).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_interjections() {
+		let id = GLOBAL_BROKER.lock().await.create().unwrap();
+		let tools = Arc::new(
+			SessionTools::new(id, &Config::default()).await.unwrap(),
+		);
+		let session =
+			GLOBAL_BROKER.lock().await.get_session(id).unwrap();
+
+		let t = tools.clone();
+		let interrupted =
+			tokio::spawn(async move { t.interrupted().await });
+
+		session
+			.lock()
+			.await
+			.interjections
+			.push("also check the group".into(), false);
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert!(!interrupted.is_finished());
+
+		session
+			.lock()
+			.await
+			.interjections
+			.push("stop, ask Sam instead".into(), true);
+		tokio::time::timeout(Duration::from_secs(1), interrupted)
+			.await
+			.unwrap()
+			.unwrap();
+
+		assert_eq!(
+			tools.interjections().await,
+			["also check the group", "stop, ask Sam instead"]
+		);
+		assert!(tools.interjections().await.is_empty());
+	}
+}
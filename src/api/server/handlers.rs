@@ -1,14 +1,28 @@
-use super::broker::{CHANNEL_SIZE, GLOBAL_BROKER};
+use super::admin::{self, SessionInfo};
+use super::broker::{GLOBAL_BROKER, KeyScope};
+use super::dispatch::rpc_id;
+use super::health::{self, Status};
+use super::quota::{self, Generation, Principal};
+use super::registry::validate_tools;
+use super::reload::{self, Reload};
 use super::{AppError, Auth, ServerState, ServiceAuth};
+use super::{limits, metrics, telemetry};
 #[cfg(test)]
 use crate::api::server::PromptRepeaterClient;
-use crate::api::server::broker::{McpPipe, PromptPipe};
+use crate::api::server::broker::{McpPipe, PromptPipe, SessionHandle};
 use crate::api::server::{
 	CloneableBrokerPipe, Config, PromptClient, PromptLLMClient,
 };
 
+use crate::mcp::policy::PolicyDecision;
+use crate::mcp::search::{
+	Document, DocumentKind, SearchHit, SearchQuery,
+};
 use anyhow::anyhow;
-use axum::extract::Query;
+use axum::extract::{Extension, Path, Query};
+use axum::http::{
+	HeaderMap, HeaderName, StatusCode, header::CONTENT_TYPE,
+};
 use axum::{
 	extract::{Json, State},
 	response::sse::{Event, KeepAlive, Sse},
@@ -17,7 +31,8 @@ use futures_util::stream::Stream;
 use serde::{Deserialize, Serialize};
 use std::{sync::Arc, time::Duration};
 use tokio::sync::mpsc::{Receiver, channel};
-use tokio_stream::{StreamExt, wrappers::ReceiverStream};
+use tokio_stream::StreamExt;
+use tracing::Instrument;
 
 type Result<T> = core::result::Result<T, AppError>;
 
@@ -25,30 +40,68 @@ type Result<T> = core::result::Result<T, AppError>;
 pub struct McpRequest {
 	pub connection_id: String,
 	pub command: String,
+	// W3C trace context of the call, to be sent back with the response
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub traceparent: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct McpResponse {
 	pub connection_id: String,
 	pub response: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub traceparent: Option<String>,
 }
 
 // input struct for prompt API
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct Prompt {
 	pub connection_id: Option<uuid::Uuid>,
 	pub prompt: Option<String>,
+	// the phone's tools/list; registered on the session when given, see super::registry
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub tools: Option<Vec<rmcp::model::Tool>>,
+	// id of the last event seen on an earlier connection; the events after it are sent again
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub cursor: Option<u64>,
 }
 
 // Response enum for prompt SSE events. Ingested by client which proxies to MCP, or directly to
 // the client depending on what response is sent. Server should always send Connection first and
 // client should expect that. From there, until the connection is interrupted, all connections are
-// assumed to be from the same transaction ID (a UUID).
+// assumed to be from the same transaction ID (a UUID). Every event but Connection carries an SSE id
+// that is the cursor to resume the session from.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum PromptResponse {
 	Connection(uuid::Uuid),
 	PromptResponse(String),
 	McpRequest(McpRequest),
+	ConfirmAction(ActionConfirmation),
+	RequestConsent(ActionConfirmation),
+	PolicyDecision(PolicyDecision),
+}
+
+// Sent before the model's call to an action tool (see crate::mcp::tool::action_list) is forwarded
+// to the phone. The phone shows the exact action to the user and answers with a Confirmation
+// through the confirm API; if it doesn't answer in time the action is treated as denied.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionConfirmation {
+	pub id: uuid::Uuid,
+	pub tool: String,
+	pub arguments: serde_json::Value,
+}
+
+// NOTE: RequestConsent uses the same shape and is answered the same way as ConfirmAction; it is
+// sent for read-only tools whose privacy policy says to ask the user first. Every decision about a
+// tool call, whether asked or not, is reported as a PolicyDecision so the user can see what data
+// went to the model.
+
+// input struct for confirm API
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Confirmation {
+	pub connection_id: uuid::Uuid,
+	pub id: uuid::Uuid,
+	pub approved: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -69,22 +122,108 @@ struct PromptControl {
 	id: uuid::Uuid,
 	prompt: PromptPipe,
 	mcp: McpPipe,
+	session: SessionHandle,
 }
 
-async fn get_prompt(id: Option<uuid::Uuid>) -> Result<PromptControl> {
+// NOTE: a client that never saw the response to a POST sends it again with the same
+// Idempotency-Key. A retried prompt on the session it started gets its events from the start
+// instead of starting another generation, and a retried tool response is acknowledged without
+// being passed on again. Keys live in the Broker with the session they were used on and only
+// match requests on that session, or new prompts from the named principal that owns it, see
+// super::quota.
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+// events wait in the session's replay until a stream sends them, so a stream buffers only a few;
+// when the session is closed, that is all it has left to send
+const STREAM_BUFFER_SIZE: usize = 16;
+
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>> {
+	let Some(key) = headers.get(IDEMPOTENCY_KEY) else {
+		return Ok(None);
+	};
+
+	match key.to_str() {
+		Ok(key)
+			if !key.is_empty()
+				&& key.len() <= MAX_IDEMPOTENCY_KEY_LEN =>
+		{
+			Ok(Some(key.to_string()))
+		}
+		_ => Err(anyhow!("invalid {} header", IDEMPOTENCY_KEY).into()),
+	}
+}
+
+// the prompt to stream, where a retried prompt's events start if this is one, and the generation
+// of a new prompt; both are counted against the principal's quotas, see super::quota
+async fn get_prompt(
+	state: &ServerState, principal: &Principal, id: Option<uuid::Uuid>,
+	key: Option<&str>, new: bool,
+) -> Result<(PromptControl, Option<u64>, Option<Generation>)> {
 	let mut lock = GLOBAL_BROKER.lock().into_future().await;
-	let id = if let Some(id) = id {
+	// a new prompt's key is looked up on the principal's sessions only if they can be told apart
+	let scope = match id {
+		Some(id) => Some(KeyScope::Session(id)),
+		None if principal.named => Some(KeyScope::Owner(&principal.id)),
+		None => None,
+	};
+	let duplicate = key
+		.zip(scope)
+		.and_then(|(key, scope)| lock.idempotent(key, scope));
+	// a resumed or retried prompt only attaches to the principal's own session
+	if let Some(id) = duplicate.map(|x| x.0).or(id) {
+		quota::owns(principal, lock.owner(id))?;
+	}
+	// taken before a session is made for it, so a refused prompt leaves none behind; a retry
+	// attaches to the prompt already running
+	let generation = if new && duplicate.is_none() {
+		Some(
+			state
+				.quotas
+				.generation(principal, &state.config().quotas)?,
+		)
+	} else {
+		None
+	};
+	let id = if let Some((id, _)) = duplicate {
+		tracing::info!("attaching retried prompt to: {}", id);
+		id
+	} else if let Some(id) = id {
 		tracing::info!("resuming prompt: {}", id);
 		id
 	} else {
+		quota::session(principal, lock.owned_by(&principal.id))?;
 		let id = lock.create()?;
+		lock.set_owner(id, &principal.id);
 		tracing::info!("created new prompt: {}", id);
+		if let Some(session) = lock.get_session(id) {
+			session.lock().await.span =
+				Some(telemetry::session_span(id));
+		}
 		id
 	};
 
 	if let Some(prompt) = lock.get_prompt(id) {
-		if let Some(mcp) = lock.get_mcp(id) {
-			Ok(PromptControl { id, prompt, mcp })
+		if let Some(mcp) = lock.get_mcp(id)
+			&& let Some(session) = lock.get_session(id)
+		{
+			// stored while the broker is locked, so a retry racing this request sees it
+			if let Some(key) = key
+				&& duplicate.is_none()
+			{
+				let cursor = session.lock().await.replay.last();
+				lock.store_key(id, key, cursor);
+			}
+
+			Ok((
+				PromptControl {
+					id,
+					prompt,
+					mcp,
+					session,
+				},
+				duplicate.map(|x| x.1),
+				generation,
+			))
 		} else {
 			Err(anyhow!("stream closed").into())
 		}
@@ -94,9 +233,11 @@ async fn get_prompt(id: Option<uuid::Uuid>) -> Result<PromptControl> {
 	}
 }
 
+// the generation is counted until the prompt is answered
 async fn prompt_client(
 	#[allow(unused)] query_type: Option<QueryType>, config: Config,
 	id: uuid::Uuid, send: CloneableBrokerPipe, msg: String,
+	span: tracing::Span, generation: Generation,
 ) {
 	#[cfg(test)]
 	{
@@ -104,12 +245,22 @@ async fn prompt_client(
 			// FIXME: this shouldn't fall through
 			if matches!(query_type, QueryType::RepeatPrompt) {
 				let prc = &PromptRepeaterClient;
-				tokio::spawn(prc.prompt(id, send, msg));
+				tokio::spawn(
+					async move {
+						let _generation = generation;
+						prc.prompt(id, send, msg).await
+					}
+					.instrument(span),
+				);
 			}
 		} else {
 			let prc = PromptLLMClient(config);
 			tokio::spawn(
-				async move { prc.prompt(id, send, msg).await },
+				async move {
+					let _generation = generation;
+					prc.prompt(id, send, msg).await
+				}
+				.instrument(span),
 			);
 		}
 	}
@@ -117,33 +268,68 @@ async fn prompt_client(
 	#[cfg(not(test))]
 	{
 		let prc = PromptLLMClient(config);
-		tokio::spawn(async move { prc.prompt(id, send, msg).await });
+		tokio::spawn(
+			async move {
+				let _generation = generation;
+				prc.prompt(id, send, msg).await
+			}
+			.instrument(span),
+		);
 	}
 }
 
 async fn prompt_multiplex(
-	control: PromptControl,
-) -> Receiver<PromptResponse> {
-	let (s, r) = channel(CHANNEL_SIZE);
+	control: PromptControl, cursor: Option<u64>,
+) -> Receiver<(Option<u64>, PromptResponse)> {
+	let (s, r) = channel(STREAM_BUFFER_SIZE);
 
 	tokio::spawn(async move {
+		let _open = metrics::SSE_CONNECTIONS.guard();
 		if let Err(_) =
-			s.send(PromptResponse::Connection(control.id)).await
+			s.send((None, PromptResponse::Connection(control.id))).await
 		{
 			return;
 		}
 
+		// a new connection starts with what happens next, a resumed one where it left off
+		let mut cursor = match cursor {
+			Some(x) => x,
+			None => control.session.lock().await.replay.last(),
+		};
+
 		loop {
 			if s.is_closed() {
 				return;
 			}
 
+			let (events, closed) = {
+				let session = control.session.lock().await;
+				let events = session
+					.replay
+					.since(cursor)
+					.into_iter()
+					.filter(|x| session.outstanding(&x.1))
+					.collect::<Vec<_>>();
+				(events, session.closed)
+			};
+			for (id, event) in events {
+				if s.send((Some(id), event)).await.is_err() {
+					return;
+				}
+				cursor = id;
+			}
+			if closed {
+				tracing::debug!("session closed: {}", control.id);
+				return;
+			}
+
 			let mut prompt_lock = control.prompt.lock().await;
 			if prompt_lock.check_timeout() {
 				let mut global =
 					GLOBAL_BROKER.lock().into_future().await;
 				global.expire(control.id);
 				drop(global);
+				metrics::PIPE_TIMEOUTS.inc(&[("pipe", "prompt")]);
 				tracing::debug!("prompt proxy expired: {}", control.id);
 				return;
 			}
@@ -154,26 +340,32 @@ async fn prompt_multiplex(
 					GLOBAL_BROKER.lock().into_future().await;
 				global.expire(control.id);
 				drop(global);
+				metrics::PIPE_TIMEOUTS.inc(&[("pipe", "mcp")]);
 				tracing::debug!("mcp proxy expired: {}", control.id);
 				return;
 			}
 
 			tracing::debug!("recv lock acquired for: {}", control.id);
 
-			tokio::select! {
+			let output = tokio::select! {
 				Some(output) = mcp_lock.next_message() => {
-					let _ = s.send(PromptResponse::McpRequest(output)).await;
+					Some(PromptResponse::McpRequest(output))
 				},
-				Some(output) = prompt_lock.next_message() => {
-					let _ = s.send(output).await;
-				}
-				_ = tokio::time::sleep(std::time::Duration::from_millis(100)) => {}
-			}
+				Some(output) = prompt_lock.next_message() => Some(output),
+				_ = tokio::time::sleep(std::time::Duration::from_millis(100)) => None,
+			};
 
 			tracing::debug!(
 				"freeing prompt recv lock for: {}",
 				control.id
 			);
+			drop(mcp_lock);
+			drop(prompt_lock);
+
+			// sent at the top of the loop, by this connection or the one that replaces it
+			if let Some(output) = output {
+				control.session.lock().await.replay.push(output);
+			}
 		}
 	});
 
@@ -182,7 +374,9 @@ async fn prompt_multiplex(
 
 pub(crate) async fn prompt(
 	Auth(authed): Auth, State(state): State<Arc<ServerState>>,
-	Query(params): Query<PromptType>, Json(prompt): Json<Prompt>,
+	Extension(principal): Extension<Principal>,
+	Query(params): Query<PromptType>, headers: HeaderMap,
+	Json(prompt): Json<Prompt>,
 ) -> Result<
 	Sse<
 		impl Stream<
@@ -194,26 +388,98 @@ pub(crate) async fn prompt(
 		return Err(anyhow!("unauthenticated").into());
 	}
 
-	let control = get_prompt(prompt.connection_id).await?;
+	if let Some(tools) = &prompt.tools {
+		validate_tools(tools, &state.config())?;
+	}
+
+	// only a new prompt can be run twice
+	let key = match &prompt.prompt {
+		Some(_) => idempotency_key(&headers)?,
+		None => None,
+	};
+
+	let (control, duplicate, generation) = get_prompt(
+		&state,
+		&principal,
+		prompt.connection_id,
+		key.as_deref(),
+		prompt.prompt.is_some(),
+	)
+	.await?;
+	let kind = match (&prompt.prompt, duplicate) {
+		(Some(_), None) => "new",
+		(Some(_), Some(_)) => "retry",
+		(None, _) => "resume",
+	};
+	metrics::PROMPT_STREAMS.inc(&[("kind", kind)]);
 	tracing::debug!("retreived prompt: {}", control.id);
 
+	let session = GLOBAL_BROKER.lock().await.get_session(control.id);
+	if let Some(tools) = prompt.tools
+		&& let Some(session) = session
+	{
+		session.lock().await.tools = Some(tools);
+	}
+
 	let send = control.prompt.clone();
-	if let Some(msg) = prompt.prompt {
+	if let Some(msg) = prompt.prompt
+		&& let Some(generation) = generation
+	{
+		let config = control
+			.session
+			.lock()
+			.await
+			.config
+			.get_or_insert_with(|| state.config())
+			.clone();
+		let session = control
+			.session
+			.lock()
+			.await
+			.span
+			.as_ref()
+			.and_then(|x| x.id());
+		let span = tracing::info_span!(
+			parent: session,
+			"prompt",
+			session.id = %control.id,
+		);
 		prompt_client(
 			params.query_type,
-			state.config.clone(),
+			Config::clone(&config),
 			control.id,
 			send,
 			msg,
+			span,
+			generation,
 		)
 		.await;
 	}
 
-	let r = prompt_multiplex(control).await;
-	let stream = ReceiverStream::new(r)
-		.map(|x| {
-			Event::default()
-				.data(&serde_json::to_string(&x).unwrap_or_default())
+	let r =
+		prompt_multiplex(control, prompt.cursor.or(duplicate)).await;
+	let event = |id: Option<u64>, x: PromptResponse| {
+		let event = Event::default()
+			.data(serde_json::to_string(&x).unwrap_or_default());
+		match id {
+			Some(id) => event.id(id.to_string()),
+			None => event,
+		}
+	};
+
+	// cut off at max_stream_secs, see super::limits
+	let secs = state.config().limits.max_stream_secs;
+	let deadline =
+		tokio::time::Instant::now() + Duration::from_secs(secs);
+	let stream =
+		futures_util::stream::unfold(Some(r), move |r| async move {
+			let mut r = r?;
+			tokio::select! {
+				x = r.recv() => x.map(|(id, x)| (event(id, x), Some(r))),
+				_ = tokio::time::sleep_until(deadline) => {
+					Some((limits::stream_ended(secs), None))
+				}
+			}
 		})
 		.map(Ok)
 		.throttle(Duration::from_millis(10));
@@ -222,75 +488,355 @@ pub(crate) async fn prompt(
 
 pub(crate) async fn mcp_response(
 	Auth(authed): Auth, State(_state): State<Arc<ServerState>>,
-	Json(_response): Json<McpResponse>,
+	Extension(principal): Extension<Principal>, headers: HeaderMap,
+	Json(response): Json<McpResponse>,
 ) -> Result<()> {
 	if !authed {
 		return Err(anyhow!("unauthenticated").into());
 	}
 
+	let key = idempotency_key(&headers)?;
+	let id: uuid::Uuid = response.connection_id.parse()?;
+	let session = {
+		let mut broker = GLOBAL_BROKER.lock().await;
+		let session = broker
+			.get_session(id)
+			.ok_or_else(|| anyhow!("stream closed"))?;
+		quota::owns(&principal, broker.owner(id))?;
+
+		if let Some(key) = &key {
+			if broker.idempotent(key, KeyScope::Session(id)).is_some() {
+				tracing::debug!("tool response already taken: {}", key);
+				return Ok(());
+			}
+			broker.store_key(id, key, 0);
+		}
+		session
+	};
+
+	// part of the trace of the call, when the phone passed it on
+	let span = tracing::info_span!("mcp.response", session.id = %id);
+	if let Some(traceparent) = &response.traceparent {
+		telemetry::continue_trace(&span, traceparent);
+	}
+
+	let call: anyhow::Result<_> = async {
+		let rpc_id = rpc_id(&response.response)?;
+		session.lock().await.calls.remove(&rpc_id).ok_or_else(|| {
+			anyhow!("no tool call pending for id {}", rpc_id)
+		})
+	}
+	.instrument(span)
+	.await;
+
+	let call = match call {
+		Ok(call) => call,
+		Err(e) => {
+			if let Some(key) = &key {
+				GLOBAL_BROKER.lock().await.forget_key(id, key);
+			}
+			return Err(e.into());
+		}
+	};
+
+	// the tool loop may have given up on this call already
+	let _ = call.send(response.response);
 	Ok(())
 }
 
+pub(crate) async fn confirm(
+	Auth(authed): Auth, State(_state): State<Arc<ServerState>>,
+	Extension(principal): Extension<Principal>,
+	Json(confirmation): Json<Confirmation>,
+) -> Result<()> {
+	if !authed {
+		return Err(anyhow!("unauthenticated").into());
+	}
+
+	let session = {
+		let broker = GLOBAL_BROKER.lock().await;
+		let session = broker
+			.get_session(confirmation.connection_id)
+			.ok_or_else(|| anyhow!("stream closed"))?;
+		quota::owns(
+			&principal,
+			broker.owner(confirmation.connection_id),
+		)?;
+		session
+	};
+
+	let pending = session
+		.lock()
+		.await
+		.confirmations
+		.remove(&confirmation.id)
+		.ok_or_else(|| {
+			anyhow!("no action pending for id {}", confirmation.id)
+		})?;
+
+	let _ = pending.send(confirmation.approved);
+	Ok(())
+}
+
+// NOTE: the server keeps no copy of the phone's data. The phone uploads what should be searchable
+// to its session, see crate::api::client::Client::index_store, and it goes away with the session.
+// With search_embeddings configured, documents and queries are embedded by the LLM backend;
+// search goes on by words alone if the backend can't do it.
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Search {
-	input: String,
+	// the session whose uploaded data is searched
+	pub connection_id: uuid::Uuid,
+	pub input: String,
+	// only results of these kinds; all of them if empty
+	#[serde(default)]
+	pub kinds: Vec<DocumentKind>,
+	#[serde(default)]
+	pub limit: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SearchResults {
-	results: Vec<String>,
+	// best first
+	pub results: Vec<SearchHit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct SearchUpload {
+	pub connection_id: uuid::Uuid,
+	// documents already indexed are replaced by ones of the same kind and id
+	pub documents: Vec<Document>,
+	// drop everything uploaded before
+	#[serde(default)]
+	pub replace: bool,
+}
+
+// None unless configured, and when the backend fails at it
+async fn embed(
+	state: &ServerState, input: Vec<String>,
+) -> Option<Vec<Vec<f32>>> {
+	if input.is_empty() {
+		return None;
+	}
+	let client = state.embedder()?;
+
+	let count = input.len();
+	match client.embed(input).await {
+		Ok(x) if x.len() == count => Some(x),
+		Ok(x) => {
+			tracing::warn!(
+				"got {} embeddings for {} inputs, searching by words only",
+				x.len(),
+				count
+			);
+			None
+		}
+		Err(e) => {
+			tracing::warn!(
+				"could not compute embeddings, searching by words only: {}",
+				e
+			);
+			None
+		}
+	}
+}
+
+// the principal's own session, see super::quota::owns
+async fn find_session(
+	principal: &Principal, id: uuid::Uuid,
+) -> Result<SessionHandle> {
+	let broker = GLOBAL_BROKER.lock().await;
+	quota::owns(principal, broker.owner(id))?;
+	broker
+		.get_session(id)
+		.ok_or_else(|| anyhow!("stream closed").into())
 }
 
 pub(crate) async fn search(
-	Auth(authed): Auth, State(_state): State<Arc<ServerState>>,
-	Json(_search): Json<Search>,
+	Auth(authed): Auth, State(state): State<Arc<ServerState>>,
+	Extension(principal): Extension<Principal>,
+	Json(search): Json<Search>,
 ) -> Result<Json<SearchResults>> {
 	if !authed {
 		return Err(anyhow!("unauthenticated").into());
 	}
 
-	return Ok(Default::default());
+	let session =
+		find_session(&principal, search.connection_id).await?;
+	let embedding = embed(&state, vec![search.input.clone()])
+		.await
+		.and_then(|x| x.into_iter().next());
+
+	let query = SearchQuery {
+		query: search.input,
+		kinds: search.kinds,
+		limit: search.limit,
+	};
+	let results = session
+		.lock()
+		.await
+		.index
+		.search(&query, embedding.as_deref());
+	Ok(Json(SearchResults { results }))
+}
+
+// adds to the session's search index, and says how many documents are in it
+pub(crate) async fn index(
+	Auth(authed): Auth, State(state): State<Arc<ServerState>>,
+	Extension(principal): Extension<Principal>,
+	Json(upload): Json<SearchUpload>,
+) -> Result<Json<usize>> {
+	if !authed {
+		return Err(anyhow!("unauthenticated").into());
+	}
+
+	let session =
+		find_session(&principal, upload.connection_id).await?;
+	let embeddings = embed(
+		&state,
+		upload
+			.documents
+			.iter()
+			.map(Document::embedding_text)
+			.collect(),
+	)
+	.await
+	.unwrap_or_default();
+
+	let mut session = session.lock().await;
+	if upload.replace {
+		session.index = Default::default();
+	}
+
+	let mut embeddings = embeddings.into_iter();
+	for document in upload.documents {
+		session.index.insert(document, embeddings.next());
+	}
+	Ok(Json(session.index.len()))
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Input {
-	input: String,
+	pub connection_id: uuid::Uuid,
+	pub input: String,
+	// cut the current turn short instead of waiting for it to end
+	#[serde(default)]
+	pub urgent: bool,
 }
 
 pub(crate) async fn input(
 	Auth(authed): Auth, State(_state): State<Arc<ServerState>>,
-	Json(_input): Json<Input>,
+	Extension(principal): Extension<Principal>,
+	Json(input): Json<Input>,
 ) -> Result<Json<bool>> {
 	if !authed {
 		return Err(anyhow!("unauthenticated").into());
 	}
 
-	return Ok(axum::Json(true));
+	let session = find_session(&principal, input.connection_id).await?;
+
+	// a prompt running on the session picks it up, otherwise the next one does
+	session
+		.lock()
+		.await
+		.interjections
+		.push(input.input, input.urgent);
+	Ok(axum::Json(true))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Metrics {}
+// reads the config again, see super::reload
+pub(crate) async fn reload_config(
+	ServiceAuth(authed): ServiceAuth,
+	State(state): State<Arc<ServerState>>,
+) -> Result<Json<Reload>> {
+	if !authed {
+		return Err(ServiceAuth::challenge());
+	}
+
+	Ok(Json(reload::reload(&state).await?))
+}
 
-pub(crate) async fn metrics(
+// Prometheus text format, see super::metrics
+pub(crate) async fn metrics_text(
 	ServiceAuth(authed): ServiceAuth,
 	State(_state): State<Arc<ServerState>>,
-) -> Result<Json<Metrics>> {
+) -> Result<([(HeaderName, &'static str); 1], String)> {
 	if !authed {
-		return Err(anyhow!("unauthenticated").into());
+		return Err(ServiceAuth::challenge());
 	}
-	Ok(Json::from(Metrics {}))
-}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Status {}
+	if let Some(stats) = health::broker_stats().await {
+		metrics::BROKER_SESSIONS.set(&[], stats.sessions as f64);
+		metrics::QUEUE_DEPTH
+			.set(&[("pipe", "prompt")], stats.queued_events as f64);
+		metrics::QUEUE_DEPTH
+			.set(&[("pipe", "mcp")], stats.queued_tool_calls as f64);
+		metrics::PENDING_TOOL_CALLS
+			.set(&[], stats.pending_tool_calls as f64);
+		metrics::PENDING_CONFIRMATIONS
+			.set(&[], stats.pending_confirmations as f64);
+	}
+
+	Ok((
+		[(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+		metrics::render(),
+	))
+}
 
 pub(crate) async fn status(
 	ServiceAuth(authed): ServiceAuth,
-	State(_state): State<Arc<ServerState>>,
+	State(state): State<Arc<ServerState>>,
 ) -> Result<Json<Status>> {
 	if !authed {
-		return Err(anyhow!("unauthenticated").into());
+		return Err(ServiceAuth::challenge());
+	}
+
+	Ok(Json::from(health::status(&state).await))
+}
+
+// the sessions on this node, see super::admin
+pub(crate) async fn sessions(
+	ServiceAuth(authed): ServiceAuth,
+	State(_state): State<Arc<ServerState>>,
+) -> Result<Json<Vec<SessionInfo>>> {
+	if !authed {
+		return Err(ServiceAuth::challenge());
+	}
+
+	Ok(Json(admin::sessions().await))
+}
+
+pub(crate) async fn close_session(
+	ServiceAuth(authed): ServiceAuth,
+	State(_state): State<Arc<ServerState>>, Path(id): Path<uuid::Uuid>,
+) -> Result<Json<bool>> {
+	if !authed {
+		return Err(ServiceAuth::challenge());
 	}
 
-	Ok(Json::from(Status {}))
+	if !admin::close(id).await {
+		return Err(anyhow!("no such session").into());
+	}
+	Ok(Json(true))
+}
+
+// liveness, for probes; no auth, and nothing about the node in the answer
+pub(crate) async fn healthz() -> (StatusCode, &'static str) {
+	if health::live().await {
+		(StatusCode::OK, "ok")
+	} else {
+		(StatusCode::SERVICE_UNAVAILABLE, "not responding")
+	}
+}
+
+// readiness, for probes; says what is wrong so on-call can see it in the probe log
+pub(crate) async fn readyz(
+	State(state): State<Arc<ServerState>>,
+) -> (StatusCode, String) {
+	let problems = health::readiness(&state).await;
+	if problems.is_empty() {
+		(StatusCode::OK, "ready".into())
+	} else {
+		(StatusCode::SERVICE_UNAVAILABLE, problems.join("\n"))
+	}
 }
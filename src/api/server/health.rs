@@ -0,0 +1,266 @@
+use super::broker::GLOBAL_BROKER;
+use super::{Config, ServerState};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+// a backend check is reused for this long; every load balancer probes every few seconds
+const CHECK_TTL_SECS: u64 = 5;
+const CHECK_TIMEOUT_SECS: u64 = 3;
+// a broker that can't be locked for this long is stuck
+const LIVENESS_TIMEOUT_SECS: u64 = 2;
+
+// NOTE: liveness and readiness answer different questions. /healthz fails only when the process
+// is wedged, i.e. the broker can't be locked, and restarting it is the fix. /readyz also fails
+// while the LLM backend is unreachable or doesn't have the model, so the node is taken out of
+// rotation and its sessions drain, but it isn't restarted. /status has the detail behind both.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+	pub live: bool,
+	pub ready: bool,
+	pub uptime_secs: u64,
+	pub build: BuildInfo,
+	pub broker: BrokerStats,
+	pub components: Vec<ComponentHealth>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BuildInfo {
+	pub version: String,
+	// set with ALLELO_GIT_SHA at build time
+	pub git_sha: Option<String>,
+	pub debug: bool,
+}
+
+impl Default for BuildInfo {
+	fn default() -> Self {
+		Self {
+			version: env!("CARGO_PKG_VERSION").into(),
+			git_sha: option_env!("ALLELO_GIT_SHA").map(Into::into),
+			debug: cfg!(debug_assertions),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BrokerStats {
+	pub sessions: usize,
+	// events waiting to be taken off the sessions' prompt pipes
+	pub queued_events: usize,
+	// tool calls waiting to be taken off the sessions' MCP pipes
+	pub queued_tool_calls: usize,
+	// tool calls sent to phones that haven't answered yet
+	pub pending_tool_calls: usize,
+	// actions waiting for the user's approval
+	pub pending_confirmations: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentHealth {
+	pub name: String,
+	pub healthy: bool,
+	// why it isn't healthy
+	#[serde(default)]
+	pub detail: Option<String>,
+	pub checked_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ComponentHealth {
+	fn new(name: &str, result: Result<(), String>) -> Self {
+		Self {
+			name: name.into(),
+			healthy: result.is_ok(),
+			detail: result.err(),
+			checked_at: chrono::Utc::now(),
+		}
+	}
+}
+
+// the last backend check; probes arriving while one runs wait for it
+#[derive(Debug, Default)]
+pub(crate) struct HealthCache(
+	Mutex<Option<(Instant, Vec<ComponentHealth>)>>,
+);
+
+impl HealthCache {
+	pub(crate) async fn components(
+		&self, config: &Config,
+	) -> Vec<ComponentHealth> {
+		let mut cache = self.0.lock().await;
+		if let Some((at, components)) = &*cache
+			&& at.elapsed() < Duration::from_secs(CHECK_TTL_SECS)
+		{
+			return components.clone();
+		}
+
+		let components = check_backend(config).await;
+		*cache = Some((Instant::now(), components.clone()));
+		components
+	}
+}
+
+pub(crate) async fn live() -> bool {
+	tokio::time::timeout(
+		Duration::from_secs(LIVENESS_TIMEOUT_SECS),
+		GLOBAL_BROKER.lock(),
+	)
+	.await
+	.is_ok()
+}
+
+// everything the backend check found wrong; empty when ready
+pub(crate) async fn readiness(state: &ServerState) -> Vec<String> {
+	let mut problems = Vec::new();
+	if !live().await {
+		problems.push("broker: not responding".to_string());
+	}
+
+	for x in state.health.components(&state.config()).await {
+		if !x.healthy {
+			problems.push(format!(
+				"{}: {}",
+				x.name,
+				x.detail.unwrap_or_default()
+			));
+		}
+	}
+	problems
+}
+
+pub(crate) async fn status(state: &ServerState) -> Status {
+	let broker = broker_stats().await;
+	let live = broker.is_some();
+
+	let mut components = vec![ComponentHealth::new(
+		"broker",
+		if live {
+			Ok(())
+		} else {
+			Err("not responding".into())
+		},
+	)];
+	components.extend(state.health.components(&state.config()).await);
+
+	Status {
+		live,
+		ready: components.iter().all(|x| x.healthy),
+		uptime_secs: state.started.elapsed().as_secs(),
+		build: Default::default(),
+		broker: broker.unwrap_or_default(),
+		components,
+	}
+}
+
+// None if the broker is stuck
+pub(crate) async fn broker_stats() -> Option<BrokerStats> {
+	// the sessions are locked by prompt streams for a moment at a time, so they are counted after
+	// the broker is released; the pipes aren't locked at all
+	let handles = tokio::time::timeout(
+		Duration::from_secs(LIVENESS_TIMEOUT_SECS),
+		GLOBAL_BROKER.lock(),
+	)
+	.await
+	.ok()?
+	.handles();
+
+	let mut stats = BrokerStats {
+		sessions: handles.len(),
+		..Default::default()
+	};
+	for (_, prompt, mcp, session) in handles {
+		stats.queued_events += prompt.depth();
+		stats.queued_tool_calls += mcp.depth();
+		let session = session.lock().await;
+		stats.pending_tool_calls += session.calls.len();
+		stats.pending_confirmations += session.confirmations.len();
+	}
+	Some(stats)
+}
+
+// whether the backend answers, and has the model the client type asks for
+async fn check_backend(config: &Config) -> Vec<ComponentHealth> {
+	let (Some(client_type), Some(params)) =
+		(&config.client_type, &config.client_params)
+	else {
+		return vec![ComponentHealth::new(
+			"llm_backend",
+			Err("no LLM client configured".into()),
+		)];
+	};
+
+	let model = client_type.to_model();
+	match list_models(&params.base_url).await {
+		Ok(models) => vec![
+			ComponentHealth::new("llm_backend", Ok(())),
+			ComponentHealth::new(
+				"llm_model",
+				if models.iter().any(|x| same_model(x, &model)) {
+					Ok(())
+				} else {
+					Err(format!("{} is not available", model))
+				},
+			),
+		],
+		Err(e) => vec![
+			ComponentHealth::new("llm_backend", Err(e)),
+			ComponentHealth::new(
+				"llm_model",
+				Err(format!("{} could not be checked", model)),
+			),
+		],
+	}
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaTags {
+	models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaModel {
+	name: String,
+}
+
+// the models an Ollama server has pulled
+async fn list_models(base_url: &str) -> Result<Vec<String>, String> {
+	// under the base's path, which a proxy in front of Ollama may have
+	let url = url::Url::parse(base_url)
+		.and_then(|mut x| {
+			if !x.path().ends_with('/') {
+				x.set_path(&format!("{}/", x.path()));
+			}
+			x.join("api/tags")
+		})
+		.map_err(|e| e.to_string())?;
+
+	let response = reqwest::Client::new()
+		.get(url)
+		.timeout(Duration::from_secs(CHECK_TIMEOUT_SECS))
+		.send()
+		.await
+		.map_err(|e| e.to_string())?;
+	if !response.status().is_success() {
+		return Err(format!(
+			"model list returned {}",
+			response.status()
+		));
+	}
+
+	let body = response.bytes().await.map_err(|e| e.to_string())?;
+	let tags: OllamaTags =
+		serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+	Ok(tags.models.into_iter().map(|x| x.name).collect())
+}
+
+// Ollama names models without a tag "latest"
+fn same_model(a: &str, b: &str) -> bool {
+	let tagged = |x: &str| {
+		if x.contains(':') {
+			x.to_string()
+		} else {
+			format!("{}:latest", x)
+		}
+	};
+	tagged(a) == tagged(b)
+}
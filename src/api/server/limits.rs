@@ -0,0 +1,164 @@
+use super::AppError;
+use axum::{
+	body::Body,
+	extract::{Request, State},
+	http::{StatusCode, header::CONTENT_LENGTH},
+	middleware::Next,
+	response::{IntoResponse, Response, sse::Event},
+};
+use problem_details::ProblemDetails;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// NOTE: every limit is answered with problem details, like any other error. A body is refused
+// before it is read when its Content-Length is too big, and otherwise once reading it goes past
+// the limit. A prompt stream is the exception, since its response has started: when it reaches
+// max_stream_secs it ends with an "error" event holding the problem, and the client resumes it
+// on a new connection like after a dropped one. Body limits and timeouts are read at startup,
+// max_stream_secs whenever a stream starts.
+
+const DEFAULT_PROMPT_BODY_BYTES: usize = 1024 * 1024;
+const DEFAULT_TOOL_RESPONSE_BODY_BYTES: usize = 4 * 1024 * 1024;
+const DEFAULT_INDEX_BODY_BYTES: usize = 16 * 1024 * 1024;
+const DEFAULT_BODY_BYTES: usize = 64 * 1024;
+const DEFAULT_REQUEST_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_MAX_STREAM_SECS: u64 = 3600;
+
+fn default_prompt_body_bytes() -> usize {
+	DEFAULT_PROMPT_BODY_BYTES
+}
+
+fn default_tool_response_body_bytes() -> usize {
+	DEFAULT_TOOL_RESPONSE_BODY_BYTES
+}
+
+fn default_index_body_bytes() -> usize {
+	DEFAULT_INDEX_BODY_BYTES
+}
+
+fn default_body_bytes() -> usize {
+	DEFAULT_BODY_BYTES
+}
+
+fn default_request_timeout_secs() -> u64 {
+	DEFAULT_REQUEST_TIMEOUT_SECS
+}
+
+fn default_max_stream_secs() -> u64 {
+	DEFAULT_MAX_STREAM_SECS
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitsConfig {
+	// /prompt, with the tools the phone registers
+	#[serde(default = "default_prompt_body_bytes")]
+	pub prompt_body_bytes: usize,
+	// /mcp_response, with the result of a tool call
+	#[serde(default = "default_tool_response_body_bytes")]
+	pub tool_response_body_bytes: usize,
+	// /search/index, with the phone's contacts and messages
+	#[serde(default = "default_index_body_bytes")]
+	pub index_body_bytes: usize,
+	// every other route
+	#[serde(default = "default_body_bytes")]
+	pub body_bytes: usize,
+	// for an answer on any route but /prompt
+	#[serde(default = "default_request_timeout_secs")]
+	pub request_timeout_secs: u64,
+	// how long one prompt stream lasts
+	#[serde(default = "default_max_stream_secs")]
+	pub max_stream_secs: u64,
+}
+
+impl Default for LimitsConfig {
+	fn default() -> Self {
+		Self {
+			prompt_body_bytes: DEFAULT_PROMPT_BODY_BYTES,
+			tool_response_body_bytes: DEFAULT_TOOL_RESPONSE_BODY_BYTES,
+			index_body_bytes: DEFAULT_INDEX_BODY_BYTES,
+			body_bytes: DEFAULT_BODY_BYTES,
+			request_timeout_secs: DEFAULT_REQUEST_TIMEOUT_SECS,
+			max_stream_secs: DEFAULT_MAX_STREAM_SECS,
+		}
+	}
+}
+
+fn too_large(limit: usize) -> Response {
+	AppError(
+		Box::new(
+			ProblemDetails::from_status_code(
+				StatusCode::PAYLOAD_TOO_LARGE,
+			)
+			.with_detail(format!(
+				"the body can't be more than {} bytes",
+				limit
+			)),
+		),
+		Default::default(),
+	)
+	.into_response()
+}
+
+// refuses bodies over limit; axum's own limit is disabled where this is used
+pub(crate) async fn body(
+	State(limit): State<usize>, request: Request, next: Next,
+) -> Response {
+	let length = request
+		.headers()
+		.get(CONTENT_LENGTH)
+		.and_then(|x| x.to_str().ok())
+		.and_then(|x| x.parse::<usize>().ok());
+	if length.is_some_and(|x| x > limit) {
+		return too_large(limit);
+	}
+
+	// an extractor reading past the limit answers 413, in plain text
+	let request = request
+		.map(|x| Body::new(http_body_util::Limited::new(x, limit)));
+	let response = next.run(request).await;
+	if response.status() == StatusCode::PAYLOAD_TOO_LARGE {
+		return too_large(limit);
+	}
+	response
+}
+
+pub(crate) async fn timeout(
+	State(secs): State<u64>, request: Request, next: Next,
+) -> Response {
+	match tokio::time::timeout(
+		Duration::from_secs(secs),
+		next.run(request),
+	)
+	.await
+	{
+		Ok(x) => x,
+		Err(_) => AppError(
+			Box::new(
+				ProblemDetails::from_status_code(
+					StatusCode::GATEWAY_TIMEOUT,
+				)
+				.with_detail(format!(
+					"no answer within {} seconds",
+					secs
+				)),
+			),
+			Default::default(),
+		)
+		.into_response(),
+	}
+}
+
+// the last event of a prompt stream that lasted max_stream_secs
+pub(crate) fn stream_ended(secs: u64) -> Event {
+	let problem = ProblemDetails::from_status_code(
+		StatusCode::SERVICE_UNAVAILABLE,
+	)
+	.with_detail(format!(
+		"the stream ended after {} seconds; resume it",
+		secs
+	));
+	Event::default()
+		.event("error")
+		.data(serde_json::to_string(&problem).unwrap_or_default())
+}
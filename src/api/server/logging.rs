@@ -0,0 +1,342 @@
+use serde::{Deserialize, Serialize};
+use std::{fmt, sync::OnceLock};
+use tracing::{Event, Level, Metadata, Subscriber, field::Field};
+use tracing_subscriber::{
+	EnvFilter, Layer, Registry,
+	field::{MakeExt, Visit},
+	filter::{FilterExt, LevelFilter, filter_fn},
+	fmt::{
+		FmtContext, FormatEvent, FormatFields, FormattedFields,
+		MakeWriter,
+		format::{Writer, debug_fn},
+	},
+	layer::Filter,
+	registry::LookupSpan,
+	reload,
+};
+
+// NOTE: prompts, tool arguments and tool results are the user's messages and contacts, and stay
+// out of the logs. Code that logs them puts them in one of CONTENT_FIELDS rather than in the
+// message, e.g. tracing::debug!(arguments = %x, "calling {}", name), and the formatters here write
+// those fields as SCRUBBED. Crates that log requests to the LLM backend with their bodies are kept
+// at info, and the OpenTelemetry layer drops events with content. debug_content turns all of this
+// off, for debugging on a machine with nobody's data on it.
+
+// fields holding the user's data
+pub const CONTENT_FIELDS: &[&str] =
+	&["prompt", "arguments", "result", "content"];
+// crates logging request and response bodies below info
+const CONTENT_TARGETS: &[&str] =
+	&["llm", "rmcp", "reqwest", "hyper", "h2"];
+const SCRUBBED: &str = "[scrubbed]";
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+	#[default]
+	Text,
+	// one JSON object per line
+	Json,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingConfig {
+	#[serde(default)]
+	pub format: LogFormat,
+	// EnvFilter directives on top of log_level, e.g. "allelo_mcp::api::llm=trace,hyper=warn"
+	#[serde(default)]
+	pub filter: Option<String>,
+	// log prompts, tool arguments and tool results
+	#[serde(default)]
+	pub debug_content: bool,
+}
+
+fn is_content(field: &Field) -> bool {
+	CONTENT_FIELDS.contains(&field.name())
+}
+
+// whether spans or events of this callsite can carry the user's data
+pub fn has_content(metadata: &Metadata) -> bool {
+	metadata.fields().iter().any(|x| is_content(&x))
+}
+
+fn is_content_target(target: &str) -> bool {
+	CONTENT_TARGETS.iter().any(|x| {
+		target
+			.strip_prefix(x)
+			.is_some_and(|x| x.is_empty() || x.starts_with("::"))
+	})
+}
+
+type BoxedFilter = Box<dyn Filter<Registry> + Send + Sync>;
+
+// the filter of the layer below, and the debug_content it was made with
+static RELOAD: OnceLock<(reload::Handle<BoxedFilter, Registry>, bool)> =
+	OnceLock::new();
+
+fn filter(
+	config: &LoggingConfig, level: Level,
+) -> anyhow::Result<BoxedFilter> {
+	let debug_content = config.debug_content;
+	Ok(EnvFilter::builder()
+		.with_default_directive(LevelFilter::from_level(level).into())
+		.parse(config.filter.as_deref().unwrap_or_default())?
+		.and(filter_fn(move |x| {
+			debug_content
+				|| *x.level() <= Level::INFO
+				|| !is_content_target(x.target())
+		}))
+		.boxed())
+}
+
+// the layer writing logs to stdout
+pub fn layer(
+	config: &LoggingConfig, level: Level,
+) -> anyhow::Result<Box<dyn Layer<Registry> + Send + Sync>> {
+	let (filter, handle) = reload::Layer::new(filter(config, level)?);
+	let _ = RELOAD.set((handle, config.debug_content));
+
+	Ok(fmt_layer(config, std::io::stdout)
+		.with_filter(filter)
+		.boxed())
+}
+
+// new levels and filter directives for the layer; the format and debug_content stay as they were,
+// since the formatter scrubs by the debug_content it was made with
+pub fn reload(
+	config: &LoggingConfig, level: Level,
+) -> anyhow::Result<()> {
+	let Some((handle, debug_content)) = RELOAD.get() else {
+		// nothing is logged, e.g. in tests
+		return Ok(());
+	};
+
+	let config = LoggingConfig {
+		debug_content: *debug_content,
+		..config.clone()
+	};
+	handle.reload(filter(&config, level)?)?;
+	Ok(())
+}
+
+fn fmt_layer<S, W>(
+	config: &LoggingConfig, writer: W,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+	let debug_content = config.debug_content;
+	// for span fields, and event fields in text
+	let fields = debug_fn(move |w, field, value| {
+		if !debug_content && is_content(field) {
+			write!(w, "{}={}", field, SCRUBBED)
+		} else if field.name() == "message" {
+			write!(w, "{:?}", value)
+		} else {
+			write!(w, "{}={:?}", field, value)
+		}
+	})
+	.delimited(" ");
+
+	let layer = tracing_subscriber::fmt::layer()
+		.with_writer(writer)
+		.fmt_fields(fields);
+	match config.format {
+		LogFormat::Text => layer.boxed(),
+		LogFormat::Json => {
+			layer.event_format(Json { debug_content }).boxed()
+		}
+	}
+}
+
+// timestamp, level, target, the event's fields and the spans it happened in, outermost first
+struct Json {
+	debug_content: bool,
+}
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+	N: for<'a> FormatFields<'a> + 'static,
+{
+	fn format_event(
+		&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>,
+		event: &Event<'_>,
+	) -> fmt::Result {
+		let mut fields = JsonFields {
+			debug_content: self.debug_content,
+			fields: Default::default(),
+		};
+		event.record(&mut fields);
+
+		let spans: Vec<_> = ctx
+			.event_scope()
+			.into_iter()
+			.flat_map(|x| x.from_root())
+			.map(|span| {
+				let mut x = serde_json::Map::new();
+				x.insert("name".into(), span.name().into());
+				if let Some(fields) =
+					span.extensions().get::<FormattedFields<N>>()
+					&& !fields.is_empty()
+				{
+					x.insert("fields".into(), fields.as_str().into());
+				}
+				serde_json::Value::Object(x)
+			})
+			.collect();
+
+		let metadata = event.metadata();
+		writeln!(
+			writer,
+			"{}",
+			serde_json::json!({
+				"timestamp": chrono::Utc::now().to_rfc3339(),
+				"level": metadata.level().as_str(),
+				"target": metadata.target(),
+				"fields": fields.fields,
+				"spans": spans,
+			})
+		)
+	}
+}
+
+struct JsonFields {
+	debug_content: bool,
+	fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl JsonFields {
+	fn insert(&mut self, field: &Field, value: serde_json::Value) {
+		let value = if !self.debug_content && is_content(field) {
+			SCRUBBED.into()
+		} else {
+			value
+		};
+		self.fields.insert(field.name().into(), value);
+	}
+}
+
+impl Visit for JsonFields {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		self.insert(field, format!("{:?}", value).into())
+	}
+
+	fn record_str(&mut self, field: &Field, value: &str) {
+		self.insert(field, value.into())
+	}
+
+	fn record_i64(&mut self, field: &Field, value: i64) {
+		self.insert(field, value.into())
+	}
+
+	fn record_u64(&mut self, field: &Field, value: u64) {
+		self.insert(field, value.into())
+	}
+
+	fn record_f64(&mut self, field: &Field, value: f64) {
+		self.insert(field, value.into())
+	}
+
+	fn record_bool(&mut self, field: &Field, value: bool) {
+		self.insert(field, value.into())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, Mutex};
+	use tracing_subscriber::layer::SubscriberExt;
+
+	// what a layer writes, for one config
+	fn logs(config: LoggingConfig, f: impl FnOnce()) -> String {
+		let out = Arc::new(Mutex::new(Vec::new()));
+		let writer = out.clone();
+		let layer = fmt_layer(&config, move || Buffer(writer.clone()));
+		tracing::subscriber::with_default(
+			tracing_subscriber::registry().with(layer),
+			f,
+		);
+		String::from_utf8(out.lock().unwrap().clone()).unwrap()
+	}
+
+	struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+	impl std::io::Write for Buffer {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			self.0.lock().unwrap().write(buf)
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn call() {
+		let span = tracing::info_span!("turn", round = 1);
+		let _entered = span.enter();
+		tracing::info!(
+			arguments = r#"{"name":"Sam Reyes"}"#,
+			result = "+1 555 0100",
+			"called {}",
+			"contact_info"
+		);
+	}
+
+	#[test]
+	fn test_scrub() {
+		for format in [LogFormat::Text, LogFormat::Json] {
+			let out = logs(
+				LoggingConfig {
+					format,
+					..Default::default()
+				},
+				call,
+			);
+			assert!(out.contains("contact_info"), "{}", out);
+			assert!(out.contains(SCRUBBED));
+			assert!(!out.contains("Sam Reyes"));
+			assert!(!out.contains("+1 555 0100"));
+
+			let out = logs(
+				LoggingConfig {
+					format,
+					debug_content: true,
+					..Default::default()
+				},
+				call,
+			);
+			assert!(out.contains("Sam Reyes"));
+			assert!(out.contains("+1 555 0100"));
+		}
+	}
+
+	#[test]
+	fn test_json() {
+		let out = logs(
+			LoggingConfig {
+				format: LogFormat::Json,
+				..Default::default()
+			},
+			call,
+		);
+		let line: serde_json::Value =
+			serde_json::from_str(out.trim()).unwrap();
+		assert_eq!(line["level"], "INFO");
+		assert_eq!(line["fields"]["message"], "called contact_info");
+		assert_eq!(line["fields"]["arguments"], SCRUBBED);
+		assert_eq!(line["spans"][0]["name"], "turn");
+		assert_eq!(line["spans"][0]["fields"], "round=1");
+	}
+
+	#[test]
+	fn test_targets() {
+		assert!(is_content_target("llm"));
+		assert!(is_content_target("llm::backends::ollama"));
+		assert!(!is_content_target("llmx"));
+		assert!(!is_content_target("allelo_mcp::api::llm"));
+	}
+}
@@ -0,0 +1,414 @@
+use axum::{
+	extract::{MatchedPath, Request},
+	middleware::Next,
+	response::Response,
+};
+use std::{
+	collections::BTreeMap,
+	fmt::Write,
+	sync::{LazyLock, Mutex},
+	time::Instant,
+};
+
+// NOTE: a small Prometheus registry. Metrics are declared here and recorded from wherever they
+// happen; /metrics renders them in the text exposition format, after setting the gauges that are
+// read off the broker at scrape time. Label values should come from a small set, e.g. route
+// templates rather than paths, or the scrape grows with every session.
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Default::default);
+
+// seconds
+const LATENCY_BUCKETS: &[f64] = &[
+	0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+	30.0, 60.0,
+];
+// tokens per second
+const RATE_BUCKETS: &[f64] =
+	&[1.0, 2.5, 5.0, 10.0, 20.0, 40.0, 80.0, 160.0];
+
+pub(crate) const HTTP_REQUESTS: Counter = Counter {
+	name: "allelo_http_requests_total",
+	help: "HTTP requests, by route, method and status",
+};
+pub(crate) const HTTP_DURATION: Histogram = Histogram {
+	name: "allelo_http_request_duration_seconds",
+	help: "Time to answer HTTP requests, by route and method; for prompts, until the stream opens",
+	buckets: LATENCY_BUCKETS,
+};
+pub(crate) const SSE_CONNECTIONS: Gauge = Gauge {
+	name: "allelo_sse_connections",
+	help: "Open prompt streams",
+};
+pub(crate) const PROMPT_STREAMS: Counter = Counter {
+	name: "allelo_prompt_streams_total",
+	help: "Prompt streams opened, by kind: new, resume after a reconnect, or retry of a prompt already running",
+};
+pub(crate) const QUOTA_REJECTIONS: Counter = Counter {
+	name: "allelo_quota_rejections_total",
+	help: "Requests refused with 429, by quota: ip, rate, sessions, generations, or global for the node's generations",
+};
+pub(crate) const BROKER_SESSIONS: Gauge = Gauge {
+	name: "allelo_broker_sessions",
+	help: "Sessions held by the broker",
+};
+pub(crate) const QUEUE_DEPTH: Gauge = Gauge {
+	name: "allelo_broker_queue_depth",
+	help: "Messages waiting in broker pipes, by pipe",
+};
+pub(crate) const PENDING_TOOL_CALLS: Gauge = Gauge {
+	name: "allelo_broker_pending_tool_calls",
+	help: "Tool calls sent to phones and not answered yet",
+};
+pub(crate) const PENDING_CONFIRMATIONS: Gauge = Gauge {
+	name: "allelo_broker_pending_confirmations",
+	help: "Actions waiting for the user's approval",
+};
+pub(crate) const PIPE_TIMEOUTS: Counter = Counter {
+	name: "allelo_broker_pipe_timeouts_total",
+	help: "Sessions expired because a pipe went quiet, by pipe",
+};
+pub(crate) const LLM_FIRST_TOKEN: Histogram = Histogram {
+	name: "allelo_llm_time_to_first_token_seconds",
+	help: "Time from the prompt to the first token of the answer, tool calls included, by model",
+	buckets: LATENCY_BUCKETS,
+};
+pub(crate) const LLM_CHUNK_RATE: Histogram = Histogram {
+	name: "allelo_llm_chunks_per_second",
+	help: "Speed of streamed answers in stream chunks, by model",
+	buckets: RATE_BUCKETS,
+};
+pub(crate) const LLM_CHUNKS: Counter = Counter {
+	name: "allelo_llm_chunks_total",
+	help: "Stream chunks of answers, by model; a chunk holds one token or more, depending on the backend",
+};
+pub(crate) const TOOL_CALLS: Counter = Counter {
+	name: "allelo_tool_calls_total",
+	help: "Tool calls, by tool (unknown if not a built-in one) and outcome: ok, denied, not_permitted, timeout or failed",
+};
+pub(crate) const TOOL_DURATION: Histogram = Histogram {
+	name: "allelo_tool_call_duration_seconds",
+	help: "Time to answer tool calls, by tool, waiting for the user's approval included",
+	buckets: LATENCY_BUCKETS,
+};
+
+type Labels<'a> = &'a [(&'static str, &'a str)];
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Counter {
+	name: &'static str,
+	help: &'static str,
+}
+
+impl Counter {
+	pub(crate) fn inc(&self, labels: Labels) {
+		self.add(labels, 1.0)
+	}
+
+	pub(crate) fn add(&self, labels: Labels, value: f64) {
+		REGISTRY.update(
+			self.name,
+			self.help,
+			Kind::Counter,
+			labels,
+			|x| x.sum += value,
+		)
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Gauge {
+	name: &'static str,
+	help: &'static str,
+}
+
+impl Gauge {
+	pub(crate) fn set(&self, labels: Labels, value: f64) {
+		REGISTRY.update(
+			self.name,
+			self.help,
+			Kind::Gauge,
+			labels,
+			|x| x.sum = value,
+		)
+	}
+
+	pub(crate) fn add(&self, labels: Labels, value: f64) {
+		REGISTRY.update(
+			self.name,
+			self.help,
+			Kind::Gauge,
+			labels,
+			|x| x.sum += value,
+		)
+	}
+
+	// up by one until the guard is dropped
+	pub(crate) fn guard(self) -> GaugeGuard {
+		self.add(&[], 1.0);
+		GaugeGuard(self)
+	}
+}
+
+pub(crate) struct GaugeGuard(Gauge);
+
+impl Drop for GaugeGuard {
+	fn drop(&mut self) {
+		self.0.add(&[], -1.0)
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Histogram {
+	name: &'static str,
+	help: &'static str,
+	buckets: &'static [f64],
+}
+
+impl Histogram {
+	pub(crate) fn observe(&self, labels: Labels, value: f64) {
+		REGISTRY.update(
+			self.name,
+			self.help,
+			Kind::Histogram(self.buckets),
+			labels,
+			|x| x.observe(self.buckets, value),
+		)
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+	Counter,
+	Gauge,
+	Histogram(&'static [f64]),
+}
+
+#[derive(Debug, Default)]
+struct Series {
+	// the value of counters and gauges
+	sum: f64,
+	// histograms only; observations in each bucket, not cumulative
+	counts: Vec<u64>,
+	count: u64,
+}
+
+impl Series {
+	fn observe(&mut self, buckets: &[f64], value: f64) {
+		if self.counts.is_empty() {
+			self.counts = vec![0; buckets.len()];
+		}
+		if let Some(i) = buckets.iter().position(|x| value <= *x) {
+			self.counts[i] += 1;
+		}
+		self.sum += value;
+		self.count += 1;
+	}
+}
+
+#[derive(Debug)]
+struct Family {
+	help: &'static str,
+	kind: Kind,
+	series: BTreeMap<Vec<(&'static str, String)>, Series>,
+}
+
+#[derive(Debug, Default)]
+struct Registry(Mutex<BTreeMap<&'static str, Family>>);
+
+impl Registry {
+	fn update(
+		&self, name: &'static str, help: &'static str, kind: Kind,
+		labels: Labels, f: impl FnOnce(&mut Series),
+	) {
+		let mut families = self.0.lock().unwrap();
+		let family = families.entry(name).or_insert_with(|| Family {
+			help,
+			kind,
+			series: Default::default(),
+		});
+		f(family
+			.series
+			.entry(
+				labels
+					.iter()
+					.map(|(k, v)| (*k, v.to_string()))
+					.collect(),
+			)
+			.or_default())
+	}
+
+	fn render(&self) -> String {
+		let mut out = String::new();
+
+		for (name, family) in self.0.lock().unwrap().iter() {
+			let kind = match family.kind {
+				Kind::Counter => "counter",
+				Kind::Gauge => "gauge",
+				Kind::Histogram(_) => "histogram",
+			};
+			let _ = writeln!(out, "# HELP {} {}", name, family.help);
+			let _ = writeln!(out, "# TYPE {} {}", name, kind);
+
+			for (labels, series) in &family.series {
+				let Kind::Histogram(buckets) = family.kind else {
+					sample(&mut out, name, labels, None, series.sum);
+					continue;
+				};
+
+				let bucket = format!("{}_bucket", name);
+				let mut cumulative = 0;
+				for (le, count) in buckets.iter().zip(&series.counts) {
+					cumulative += count;
+					sample(
+						&mut out,
+						&bucket,
+						labels,
+						Some(&le.to_string()),
+						cumulative as f64,
+					);
+				}
+				sample(
+					&mut out,
+					&bucket,
+					labels,
+					Some("+Inf"),
+					series.count as f64,
+				);
+				sample(
+					&mut out,
+					&format!("{}_sum", name),
+					labels,
+					None,
+					series.sum,
+				);
+				sample(
+					&mut out,
+					&format!("{}_count", name),
+					labels,
+					None,
+					series.count as f64,
+				);
+			}
+		}
+
+		out
+	}
+}
+
+fn sample(
+	out: &mut String, name: &str, labels: &[(&'static str, String)],
+	le: Option<&str>, value: f64,
+) {
+	let labels: Vec<_> = labels
+		.iter()
+		.map(|(k, v)| (*k, v.as_str()))
+		.chain(le.map(|x| ("le", x)))
+		.map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+		.collect();
+
+	if labels.is_empty() {
+		let _ = writeln!(out, "{} {}", name, value);
+	} else {
+		let _ =
+			writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+	}
+}
+
+fn escape(value: &str) -> String {
+	value
+		.replace('\\', "\\\\")
+		.replace('"', "\\\"")
+		.replace('\n', "\\n")
+}
+
+pub(crate) fn render() -> String {
+	REGISTRY.render()
+}
+
+// counts and times requests by route; goes on the router with route_layer, so the route is known
+pub(crate) async fn track(
+	path: MatchedPath, request: Request, next: Next,
+) -> Response {
+	let method = request.method().to_string();
+	let started = Instant::now();
+	let response = next.run(request).await;
+
+	let route = path.as_str();
+	HTTP_DURATION.observe(
+		&[("route", route), ("method", &method)],
+		started.elapsed().as_secs_f64(),
+	);
+	HTTP_REQUESTS.inc(&[
+		("route", route),
+		("method", &method),
+		("status", response.status().as_str()),
+	]);
+	response
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_render() {
+		let registry = Registry::default();
+		let requests = |labels: Labels| {
+			registry.update(
+				"requests_total",
+				"Requests",
+				Kind::Counter,
+				labels,
+				|x| x.sum += 1.0,
+			)
+		};
+		requests(&[("route", "/prompt")]);
+		requests(&[("route", "/prompt")]);
+		requests(&[("route", "say \"hi\"\n")]);
+
+		for value in [0.05, 0.5, 5.0] {
+			registry.update(
+				"latency_seconds",
+				"Latency",
+				Kind::Histogram(&[0.1, 1.0]),
+				&[],
+				|x| x.observe(&[0.1, 1.0], value),
+			);
+		}
+
+		assert_eq!(
+			registry.render(),
+			"# HELP latency_seconds Latency
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le=\"0.1\"} 1
+latency_seconds_bucket{le=\"1\"} 2
+latency_seconds_bucket{le=\"+Inf\"} 3
+latency_seconds_sum 5.55
+latency_seconds_count 3
+# HELP requests_total Requests
+# TYPE requests_total counter
+requests_total{route=\"/prompt\"} 2
+requests_total{route=\"say \\\"hi\\\"\\n\"} 1
+"
+		);
+	}
+
+	#[test]
+	fn test_gauge_guard() {
+		let open = Gauge {
+			name: "test_open_guards",
+			help: "Guards held by test_gauge_guard",
+		};
+		let value = || {
+			render()
+				.lines()
+				.find_map(|x| x.strip_prefix("test_open_guards "))
+				.map(|x| x.to_string())
+		};
+
+		let guard = open.guard();
+		assert_eq!(value().as_deref(), Some("1"));
+		drop(guard);
+		assert_eq!(value().as_deref(), Some("0"));
+	}
+}
@@ -1,72 +1,212 @@
+mod admin;
 mod axum_support;
 pub(crate) mod broker;
 mod config;
+mod cors;
+mod dispatch;
 mod handlers;
+mod health;
+mod limits;
+pub mod logging;
+pub(crate) mod metrics;
+mod quota;
+mod redact;
+mod registry;
+mod reload;
+pub mod telemetry;
 #[cfg(test)]
 mod tests;
+mod tls;
 pub use self::config::*;
+pub use admin::{AdminConfig, SessionInfo};
 pub use axum_support::*;
+pub use cors::CorsConfig;
+pub(crate) use dispatch::{SessionTools, rpc_id};
 pub use handlers::*;
+pub use health::{BrokerStats, BuildInfo, ComponentHealth, Status};
+pub use limits::LimitsConfig;
+pub use logging::{LogFormat, LoggingConfig};
+pub use quota::{Quota, QuotaConfig, Rate};
+pub use redact::{
+	EntityRule, RedactionAction, RedactionConfig, RedactionRule,
+};
+pub use registry::RegistrationError;
+pub use reload::Reload;
+pub use telemetry::TracingConfig;
+pub use tls::TlsConfig;
 
 use axum::{
 	Router,
-	routing::{get, post, put},
+	extract::DefaultBodyLimit,
+	middleware::from_fn_with_state,
+	routing::{delete, get, post, put},
 };
 use http::{Method, header::*};
-use std::sync::Arc;
-use tower::ServiceBuilder;
-use tower_http::cors::{Any as CorsAny, CorsLayer};
-use tower_http::trace::{
-	DefaultMakeSpan, DefaultOnFailure, DefaultOnRequest,
-};
+use std::{net::SocketAddr, sync::Arc};
+use tower_http::cors::CorsLayer;
+use tower_http::trace::{DefaultOnFailure, DefaultOnRequest};
 use tracing::Level;
 
 #[derive(Debug, Clone)]
 pub struct Server {
 	router: Router,
+	// the operators' routes, when they have a listener of their own; see admin
+	admin: Option<(SocketAddr, Router)>,
 	config: Config,
+	state: Arc<ServerState>,
+}
+
+// the phone's routes, and the probes
+fn public_routes(
+	config: &LimitsConfig, state: &Arc<ServerState>,
+) -> Router<Arc<ServerState>> {
+	let body = |x| from_fn_with_state(x, limits::body);
+	// on every route but /prompt; the stream has max_stream_secs instead
+	let timeout = || {
+		from_fn_with_state(config.request_timeout_secs, limits::timeout)
+	};
+
+	// answers to what the server asked the phone, and the probes, which quotas don't count
+	let uncounted = Router::new()
+		.route(
+			"/mcp_response",
+			post(mcp_response)
+				.layer(body(config.tool_response_body_bytes)),
+		)
+		.route("/confirm", post(confirm).layer(body(config.body_bytes)))
+		.route_layer(from_fn_with_state(state.clone(), quota::identify))
+		.route("/healthz", get(healthz))
+		.route("/readyz", get(readyz))
+		.route_layer(timeout());
+
+	Router::new()
+		.route("/search", post(search).layer(body(config.body_bytes)))
+		.route(
+			"/search/index",
+			put(index).layer(body(config.index_body_bytes)),
+		)
+		.route("/input", put(input).layer(body(config.body_bytes)))
+		.route_layer(timeout())
+		.route(
+			"/prompt",
+			post(prompt).layer(body(config.prompt_body_bytes)),
+		)
+		.route_layer(from_fn_with_state(state.clone(), quota::limit))
+		.merge(uncounted)
+}
+
+// the operators' routes, behind ServiceAuth; /admin/* only on an admin listener, since nothing
+// keeps them from the public
+fn service_routes(
+	config: &LimitsConfig, admin: bool,
+) -> Router<Arc<ServerState>> {
+	let routes = Router::new()
+		.route("/status", get(status))
+		.route("/metrics", get(metrics_text));
+	let routes = if admin {
+		routes
+			.route("/admin/reload", post(reload_config))
+			.route("/admin/sessions", get(sessions))
+			.route("/admin/sessions/{id}", delete(close_session))
+	} else {
+		routes
+	};
+	routes
+		.route_layer(from_fn_with_state(
+			config.body_bytes,
+			limits::body,
+		))
+		.route_layer(from_fn_with_state(
+			config.request_timeout_secs,
+			limits::timeout,
+		))
+}
+
+// the state, and the layers of every listener
+fn finish(
+	routes: Router<Arc<ServerState>>, state: Arc<ServerState>,
+	cors: Option<CorsLayer>,
+) -> Router {
+	let router = routes
+		.route_layer(axum::middleware::from_fn(metrics::track))
+		.with_state(state)
+		// every route has a limits::body of its own
+		.layer(DefaultBodyLimit::disable());
+	let router = match cors {
+		Some(x) => router.layer(x),
+		None => router,
+	};
+	router.layer(
+		tower_http::trace::TraceLayer::new_for_http()
+			.make_span_with(telemetry::request_span)
+			.on_request(DefaultOnRequest::new().level(Level::INFO))
+			.on_failure(DefaultOnFailure::new().level(Level::ERROR)),
+	)
 }
 
 impl Server {
 	pub async fn new(config: Config) -> anyhow::Result<Self> {
+		Self::with_loader(config, None).await
+	}
+
+	// reloads read the config with loader; without one they fail, see reload
+	pub async fn with_loader(
+		config: Config, loader: Option<ConfigLoader>,
+	) -> anyhow::Result<Self> {
+		let state = Arc::new(ServerState::new(config.clone(), loader));
+		let cors = config
+			.cors
+			.layer(
+				&[
+					Method::GET,
+					Method::POST,
+					Method::DELETE,
+					Method::PUT,
+					Method::PATCH,
+					Method::HEAD,
+					Method::TRACE,
+					Method::OPTIONS,
+				],
+				&[
+					CONTENT_TYPE,
+					ACCEPT,
+					AUTHORIZATION,
+					HeaderName::from_static("idempotency-key"),
+				],
+			)?
+			.map(|x| x.allow_private_network(true));
+
+		let (routes, admin) = match &config.admin {
+			Some(admin) => {
+				let cors = admin.cors.layer(
+					&[Method::GET, Method::POST, Method::DELETE],
+					&[CONTENT_TYPE, ACCEPT, AUTHORIZATION],
+				)?;
+				(
+					public_routes(&config.limits, &state),
+					Some((
+						admin.listen,
+						finish(
+							service_routes(&config.limits, true),
+							state.clone(),
+							cors,
+						),
+					)),
+				)
+			}
+			None => (
+				public_routes(&config.limits, &state)
+					.merge(service_routes(&config.limits, false)),
+				None,
+			),
+		};
+
 		Ok(Self {
-            router: Router::new()
-                .route("/prompt", post(prompt))
-                .route("/mcp_response", post(mcp_response))
-                .route("/search", post(search))
-                .route("/input", put(input))
-                .route("/status", get(status))
-                .route("/metrics", get(metrics))
-                .with_state(Arc::new(ServerState {
-                    config: config.clone(),
-                }))
-                .layer(
-                    ServiceBuilder::new()
-                        .layer(
-                            tower_http::trace::TraceLayer::new_for_http()
-                                .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
-                                .on_request(DefaultOnRequest::new().level(Level::INFO))
-                                .on_failure(DefaultOnFailure::new().level(Level::ERROR)),
-                        )
-                        .layer(
-                            CorsLayer::new()
-                                .allow_methods([
-                                    Method::GET,
-                                    Method::POST,
-                                    Method::DELETE,
-                                    Method::PUT,
-                                    Method::PATCH,
-                                    Method::HEAD,
-                                    Method::TRACE,
-                                    Method::OPTIONS,
-                                ])
-                                .allow_origin(CorsAny)
-                                .allow_headers([CONTENT_TYPE, ACCEPT, AUTHORIZATION])
-                                .allow_private_network(true),
-                        ),
-                ),
-            config: config.clone(),
-        })
+			router: finish(routes, state.clone(), cors),
+			admin,
+			config,
+			state,
+		})
 	}
 
 	pub async fn start(&self) -> anyhow::Result<()> {
@@ -78,10 +218,56 @@ impl Server {
 		&self, handle: axum_server::Handle,
 	) -> anyhow::Result<()> {
 		tokio::spawn(shutdown_signal(handle.clone()));
-		Ok(axum_server::bind(self.config.listen)
-			.handle(handle)
-			.serve(self.router.clone().into_make_service())
-			.await?)
+		tokio::spawn(reload::on_hangup(self.state.clone()));
+
+		// both listeners stop on the handle; one failing, e.g. to bind, stops the other
+		let result = match &self.admin {
+			Some((listen, router)) => {
+				let admin = async {
+					axum_server::bind(*listen)
+						.handle(handle.clone())
+						.serve(router.clone().into_make_service())
+						.await?;
+					Ok::<_, anyhow::Error>(())
+				};
+				tokio::try_join!(self.serve(handle.clone()), admin)
+					.map(|_| ())
+			}
+			None => self.serve(handle.clone()).await,
+		};
+		if result.is_err() {
+			handle.shutdown();
+		}
+		telemetry::shutdown();
+		result
+	}
+
+	// the public listener, telling handlers the client's address for quotas
+	async fn serve(
+		&self, handle: axum_server::Handle,
+	) -> anyhow::Result<()> {
+		let service = self
+			.router
+			.clone()
+			.into_make_service_with_connect_info::<SocketAddr>();
+		match &self.config.tls {
+			Some(config) => {
+				axum_server::bind_rustls(
+					self.config.listen,
+					tls::rustls_config(config)?,
+				)
+				.handle(handle)
+				.serve(service)
+				.await?
+			}
+			None => {
+				axum_server::bind(self.config.listen)
+					.handle(handle)
+					.serve(service)
+					.await?
+			}
+		}
+		Ok(())
 	}
 }
 
@@ -0,0 +1,578 @@
+use super::{AppError, ServerState, metrics};
+use axum::{
+	extract::{ConnectInfo, Request, State},
+	http::{
+		HeaderMap, HeaderName, HeaderValue, StatusCode,
+		header::RETRY_AFTER,
+	},
+	middleware::Next,
+	response::{IntoResponse, Response},
+};
+use problem_details::ProblemDetails;
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{BTreeMap, HashMap},
+	net::{IpAddr, SocketAddr},
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+// NOTE: quotas keep one principal from having the node, and the model behind it, to itself. A
+// request to /prompt, /search, /search/index or /input takes a token from its principal's bucket
+// and from its address's; the answers the server asked the phone for and the probes don't. A
+// principal also has a number of sessions open, and of prompts being answered ("generations"),
+// at once, and the node a number of generations over all principals. Whatever is over its quota
+// is refused with 429 and Retry-After. Nothing here authenticates anyone: the principal is what
+// principal_header says, which the gateway in front has to set and clients must not be able to,
+// or else the client's address. Limits that aren't set don't apply. Quotas are read on every
+// request, so reloads change them; buckets and counts are kept. Sessions are kept to the
+// principal that opened them only with principal_header: a phone's address changes when it moves
+// from Wi-Fi to LTE, and phones behind the same NAT share one.
+
+// when to try again after too many sessions or generations, which don't end on a schedule
+const SESSION_RETRY_SECS: u64 = 60;
+const GENERATION_RETRY_SECS: u64 = 5;
+// buckets kept before the full ones are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuotaConfig {
+	// names the principal, e.g. x-allelo-principal; without it, or when a request doesn't have it,
+	// the principal is the client's address
+	#[serde(default)]
+	pub principal_header: Option<String>,
+	// names the principal's tier, set by the gateway like principal_header
+	#[serde(default)]
+	pub tier_header: Option<String>,
+	// holds the client's address, set by a proxy in front, e.g. x-real-ip; without it, the address
+	// connecting to the listener
+	#[serde(default)]
+	pub ip_header: Option<String>,
+	// for every principal
+	#[serde(default)]
+	pub default: Quota,
+	// over default for the principals of a tier, by tier name; what a tier doesn't set is default's
+	#[serde(default)]
+	pub tiers: BTreeMap<String, Quota>,
+	// requests per client address, whichever principal makes them
+	#[serde(default)]
+	pub per_ip: Option<Rate>,
+	// generations running at once on this node, over all principals
+	#[serde(default)]
+	pub max_generations: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+	#[serde(default)]
+	pub rate: Option<Rate>,
+	#[serde(default)]
+	pub max_sessions: Option<usize>,
+	#[serde(default)]
+	pub max_generations: Option<usize>,
+}
+
+// a token bucket, refilled at per_minute and holding up to burst
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+	pub per_minute: u32,
+	// requests that can be made at once after a quiet spell
+	pub burst: u32,
+}
+
+impl Quota {
+	// self, with other where self doesn't say
+	fn or(&self, other: &Quota) -> Quota {
+		Quota {
+			rate: self.rate.clone().or_else(|| other.rate.clone()),
+			max_sessions: self.max_sessions.or(other.max_sessions),
+			max_generations: self
+				.max_generations
+				.or(other.max_generations),
+		}
+	}
+}
+
+impl QuotaConfig {
+	fn header<'a>(
+		headers: &'a HeaderMap, name: &Option<String>,
+	) -> Option<&'a str> {
+		headers
+			.get(name.as_ref()?.as_str())?
+			.to_str()
+			.ok()
+			.filter(|x| !x.is_empty())
+	}
+
+	fn client_ip(&self, request: &Request) -> Option<IpAddr> {
+		Self::header(request.headers(), &self.ip_header)
+			.and_then(|x| x.trim().parse().ok())
+			.or_else(|| {
+				request
+					.extensions()
+					.get::<ConnectInfo<SocketAddr>>()
+					.map(|x| x.0.ip())
+			})
+	}
+
+	fn principal(
+		&self, headers: &HeaderMap, ip: Option<IpAddr>,
+	) -> Principal {
+		let id = match Self::header(headers, &self.principal_header) {
+			Some(x) => x.to_string(),
+			None => ip.map(|x| x.to_string()).unwrap_or_default(),
+		};
+		let quota = match Self::header(headers, &self.tier_header)
+			.and_then(|x| self.tiers.get(x))
+		{
+			Some(tier) => tier.or(&self.default),
+			None => self.default.clone(),
+		};
+		Principal {
+			id,
+			quota,
+			named: self.principal_header.is_some(),
+		}
+	}
+
+	// what is wrong with it, by key, for validating a config
+	pub(crate) fn check(&self) -> Vec<(String, &'static str)> {
+		let mut problems = Vec::new();
+		for (key, name) in [
+			("principal_header", &self.principal_header),
+			("tier_header", &self.tier_header),
+			("ip_header", &self.ip_header),
+		] {
+			if name.as_ref().is_some_and(|x| {
+				HeaderName::try_from(x.as_str()).is_err()
+			}) {
+				problems.push((key.to_string(), "not a header name"));
+			}
+		}
+		if self.tier_header.is_some() && self.principal_header.is_none()
+		{
+			problems.push((
+				"tier_header".to_string(),
+				"needs principal_header",
+			));
+		}
+
+		let mut rates = vec![("per_ip".to_string(), &self.per_ip)];
+		let mut limits =
+			vec![("max_generations".to_string(), self.max_generations)];
+		let quotas =
+			std::iter::once(("default".to_string(), &self.default))
+				.chain(
+					self.tiers
+						.iter()
+						.map(|(k, v)| (format!("tiers.{}", k), v)),
+				);
+		for (key, quota) in quotas {
+			rates.push((format!("{}.rate", key), &quota.rate));
+			limits.push((
+				format!("{}.max_sessions", key),
+				quota.max_sessions,
+			));
+			limits.push((
+				format!("{}.max_generations", key),
+				quota.max_generations,
+			));
+		}
+		for (key, rate) in rates {
+			let Some(rate) = rate else { continue };
+			if rate.per_minute == 0 {
+				problems.push((
+					format!("{}.per_minute", key),
+					"must be more than 0",
+				));
+			}
+			if rate.burst == 0 {
+				problems.push((
+					format!("{}.burst", key),
+					"must be more than 0",
+				));
+			}
+		}
+		for (key, limit) in limits {
+			if limit == Some(0) {
+				problems.push((key, "must be more than 0"));
+			}
+		}
+		problems
+	}
+}
+
+// who a request is from, and what they may use; put in the request by limit
+#[derive(Debug, Clone)]
+pub(crate) struct Principal {
+	pub(crate) id: String,
+	// default, with the principal's tier over it
+	pub(crate) quota: Quota,
+	// principal_header is set, so this is who the gateway says and not just an address
+	pub(crate) named: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+	tokens: f64,
+	at: Instant,
+}
+
+impl Bucket {
+	fn new(rate: &Rate) -> Self {
+		Self {
+			tokens: rate.burst as f64,
+			at: Instant::now(),
+		}
+	}
+
+	fn refill(&mut self, rate: &Rate) {
+		let now = Instant::now();
+		let per_sec = rate.per_minute as f64 / 60.0;
+		self.tokens = (self.tokens
+			+ now.duration_since(self.at).as_secs_f64() * per_sec)
+			.min(rate.burst as f64);
+		self.at = now;
+	}
+
+	// takes a token, or says how long until there is one
+	fn take(&mut self, rate: &Rate) -> Result<(), Duration> {
+		self.refill(rate);
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			return Ok(());
+		}
+		let per_sec = rate.per_minute as f64 / 60.0;
+		Err(Duration::from_secs_f64((1.0 - self.tokens) / per_sec))
+	}
+
+	fn full(&mut self, rate: &Rate) -> bool {
+		self.refill(rate);
+		self.tokens >= rate.burst as f64
+	}
+}
+
+#[derive(Debug)]
+struct Buckets<K>(HashMap<K, Bucket>);
+
+impl<K> Default for Buckets<K> {
+	fn default() -> Self {
+		Self(HashMap::new())
+	}
+}
+
+impl<K: std::hash::Hash + Eq> Buckets<K> {
+	fn take(&mut self, key: K, rate: &Rate) -> Result<(), Duration> {
+		// a full bucket is the same as none
+		if self.0.len() >= MAX_BUCKETS {
+			self.0.retain(|_, x| !x.full(rate));
+		}
+		self.0
+			.entry(key)
+			.or_insert_with(|| Bucket::new(rate))
+			.take(rate)
+	}
+}
+
+#[derive(Debug, Default)]
+struct Generations {
+	by_principal: HashMap<String, usize>,
+	all: usize,
+}
+
+// what principals used so far, kept by the server
+#[derive(Debug, Default)]
+pub(crate) struct Quotas {
+	principals: Mutex<Buckets<String>>,
+	ips: Mutex<Buckets<IpAddr>>,
+	generations: Arc<Mutex<Generations>>,
+}
+
+// a prompt being answered, counted until this is dropped
+#[derive(Debug)]
+pub(crate) struct Generation {
+	principal: String,
+	generations: Arc<Mutex<Generations>>,
+}
+
+impl Drop for Generation {
+	fn drop(&mut self) {
+		let mut x = self.generations.lock().unwrap();
+		x.all -= 1;
+		if let Some(n) = x.by_principal.get_mut(&self.principal) {
+			*n -= 1;
+			if *n == 0 {
+				x.by_principal.remove(&self.principal);
+			}
+		}
+	}
+}
+
+impl Quotas {
+	// a generation for the principal, unless they or the node have all they may
+	pub(crate) fn generation(
+		&self, principal: &Principal, config: &QuotaConfig,
+	) -> Result<Generation, AppError> {
+		let mut x = self.generations.lock().unwrap();
+		if let Some(max) = config.max_generations
+			&& x.all >= max
+		{
+			return Err(refuse(
+				"global",
+				"the server is answering as many prompts as it can"
+					.to_string(),
+				Duration::from_secs(GENERATION_RETRY_SECS),
+			));
+		}
+		let running =
+			x.by_principal.get(&principal.id).copied().unwrap_or(0);
+		if let Some(max) = principal.quota.max_generations
+			&& running >= max
+		{
+			return Err(refuse(
+				"generations",
+				format!(
+					"no more than {} prompts answered at once",
+					max
+				),
+				Duration::from_secs(GENERATION_RETRY_SECS),
+			));
+		}
+
+		x.all += 1;
+		*x.by_principal.entry(principal.id.clone()).or_default() += 1;
+		Ok(Generation {
+			principal: principal.id.clone(),
+			generations: self.generations.clone(),
+		})
+	}
+}
+
+// whether the principal may open another session, with open ones already
+pub(crate) fn session(
+	principal: &Principal, open: usize,
+) -> Result<(), AppError> {
+	match principal.quota.max_sessions {
+		Some(max) if open >= max => Err(refuse(
+			"sessions",
+			format!("no more than {} sessions at once", max),
+			Duration::from_secs(SESSION_RETRY_SECS),
+		)),
+		_ => Ok(()),
+	}
+}
+
+// whether the principal may use a session, given who opened it; another's session is answered
+// as if it didn't exist. Without principal_header a session is anyone's who has its id
+pub(crate) fn owns(
+	principal: &Principal, owner: Option<String>,
+) -> Result<(), AppError> {
+	if owner.is_some_and(|x| !principal.named || x == principal.id) {
+		return Ok(());
+	}
+	Err(AppError(
+		Box::new(
+			ProblemDetails::from_status_code(StatusCode::NOT_FOUND)
+				.with_detail("no such session"),
+		),
+		HeaderMap::new(),
+	))
+}
+
+// 429, saying when to try again in whole seconds
+fn refuse(quota: &str, detail: String, wait: Duration) -> AppError {
+	metrics::QUOTA_REJECTIONS.inc(&[("quota", quota)]);
+	let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+	let mut headers = HeaderMap::new();
+	headers.insert(RETRY_AFTER, HeaderValue::from(secs));
+	AppError(
+		Box::new(
+			ProblemDetails::from_status_code(
+				StatusCode::TOO_MANY_REQUESTS,
+			)
+			.with_detail(detail),
+		),
+		headers,
+	)
+}
+
+// takes a token for the request's address and principal, and tells the handler who the
+// principal is
+pub(crate) async fn limit(
+	State(state): State<Arc<ServerState>>, mut request: Request,
+	next: Next,
+) -> Response {
+	let config = state.config();
+	let config = &config.quotas;
+	let ip = config.client_ip(&request);
+	let principal = config.principal(request.headers(), ip);
+
+	if let Some(rate) = &config.per_ip
+		&& let Some(ip) = ip
+		&& let Err(wait) =
+			state.quotas.ips.lock().unwrap().take(ip, rate)
+	{
+		return refuse(
+			"ip",
+			"too many requests from this address".to_string(),
+			wait,
+		)
+		.into_response();
+	}
+	if let Some(rate) = &principal.quota.rate
+		&& let Err(wait) = state
+			.quotas
+			.principals
+			.lock()
+			.unwrap()
+			.take(principal.id.clone(), rate)
+	{
+		return refuse("rate", "too many requests".to_string(), wait)
+			.into_response();
+	}
+
+	request.extensions_mut().insert(principal);
+	next.run(request).await
+}
+
+// tells the handler who the principal is, without taking a token
+pub(crate) async fn identify(
+	State(state): State<Arc<ServerState>>, mut request: Request,
+	next: Next,
+) -> Response {
+	let config = state.config();
+	let config = &config.quotas;
+	let principal =
+		config.principal(request.headers(), config.client_ip(&request));
+
+	request.extensions_mut().insert(principal);
+	next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_bucket() {
+		let rate = Rate {
+			per_minute: 60,
+			burst: 2,
+		};
+		let mut buckets = Buckets::default();
+		assert!(buckets.take("a", &rate).is_ok());
+		assert!(buckets.take("a", &rate).is_ok());
+		let wait = buckets.take("a", &rate).unwrap_err();
+		assert!(wait <= Duration::from_secs(1), "{:?}", wait);
+		// buckets are per key
+		assert!(buckets.take("b", &rate).is_ok());
+
+		// a token a second
+		buckets.0.get_mut("a").unwrap().at -= Duration::from_secs(1);
+		assert!(buckets.take("a", &rate).is_ok());
+		assert!(buckets.take("a", &rate).is_err());
+		// and no more than burst
+		buckets.0.get_mut("a").unwrap().at -= Duration::from_secs(60);
+		for _ in 0..2 {
+			assert!(buckets.take("a", &rate).is_ok());
+		}
+		assert!(buckets.take("a", &rate).is_err());
+	}
+
+	#[test]
+	fn test_tiers() {
+		let config: QuotaConfig = serde_yaml_ng::from_str(
+			r#"
+principal_header: x-principal
+tier_header: x-tier
+default:
+  rate: { per_minute: 60, burst: 10 }
+  max_sessions: 2
+tiers:
+  gold:
+    max_sessions: 10
+    max_generations: 4
+"#,
+		)
+		.unwrap();
+		assert!(config.check().is_empty());
+
+		let mut headers = HeaderMap::new();
+		let ip = Some(IpAddr::from([10, 0, 0, 1]));
+		let principal = config.principal(&headers, ip);
+		assert_eq!(principal.id, "10.0.0.1");
+		assert_eq!(principal.quota, config.default);
+
+		headers
+			.insert("x-principal", HeaderValue::from_static("alice"));
+		headers.insert("x-tier", HeaderValue::from_static("gold"));
+		let principal = config.principal(&headers, ip);
+		assert_eq!(principal.id, "alice");
+		assert_eq!(principal.quota.max_sessions, Some(10));
+		assert_eq!(principal.quota.max_generations, Some(4));
+		assert_eq!(principal.quota.rate, config.default.rate);
+
+		// an unknown tier gets the default
+		headers.insert("x-tier", HeaderValue::from_static("platinum"));
+		assert_eq!(
+			config.principal(&headers, ip).quota,
+			config.default
+		);
+	}
+
+	#[test]
+	fn test_owns() {
+		let principal = |id: &str, named| Principal {
+			id: id.into(),
+			quota: Quota::default(),
+			named,
+		};
+
+		let alice = Some("alice".to_string());
+		assert!(owns(&principal("alice", true), alice.clone()).is_ok());
+		let response = owns(&principal("bob", true), alice.clone())
+			.unwrap_err()
+			.into_response();
+		assert_eq!(response.status(), StatusCode::NOT_FOUND);
+		// an address isn't enough to keep a session to
+		assert!(owns(&principal("10.0.0.1", false), alice).is_ok());
+		// and no session is no one's
+		assert!(owns(&principal("alice", true), None).is_err());
+		assert!(owns(&principal("10.0.0.1", false), None).is_err());
+	}
+
+	#[test]
+	fn test_generations() {
+		let quotas = Quotas::default();
+		let config = QuotaConfig {
+			max_generations: Some(3),
+			..Default::default()
+		};
+		let principal = |id: &str| Principal {
+			id: id.into(),
+			quota: Quota {
+				max_generations: Some(2),
+				..Default::default()
+			},
+			named: true,
+		};
+
+		let a = quotas.generation(&principal("a"), &config).unwrap();
+		let _b = quotas.generation(&principal("a"), &config).unwrap();
+		let response = quotas
+			.generation(&principal("a"), &config)
+			.unwrap_err()
+			.into_response();
+		assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+		assert_eq!(response.headers()[RETRY_AFTER], "5");
+
+		// the node's cap is over all principals
+		let _c = quotas.generation(&principal("b"), &config).unwrap();
+		assert!(quotas.generation(&principal("b"), &config).is_err());
+		drop(a);
+		assert!(quotas.generation(&principal("b"), &config).is_ok());
+	}
+}
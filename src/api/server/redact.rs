@@ -0,0 +1,390 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// NOTE: tool results are redacted after the phone answers and before the model sees them. Fields
+// named in drop_fields are removed outright, values of entity fields (contact and group names) and
+// matches of pseudonymize rules are replaced with pseudonyms like "[Person 1]", and matches of mask
+// rules with just "[label]". Pseudonyms are kept per session, so the model can refer to "[Person
+// 1]" in a later tool call or in its answer; both are turned back into the real value before they
+// reach the phone or the user.
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RedactionConfig {
+	#[serde(default)]
+	pub rules: Vec<RedactionRule>,
+	#[serde(default)]
+	pub entities: Vec<EntityRule>,
+	// removed from tool results wherever they appear
+	#[serde(default)]
+	pub drop_fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RedactionRule {
+	pub label: String,
+	pub pattern: String,
+	#[serde(default)]
+	pub action: RedactionAction,
+}
+
+#[derive(
+	Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq,
+)]
+pub enum RedactionAction {
+	// replaced with a pseudonym that is reversed for the user
+	#[default]
+	#[serde(rename = "pseudonymize")]
+	Pseudonymize,
+	// replaced with the label; the value is gone for good
+	#[serde(rename = "mask")]
+	Mask,
+}
+
+// values of this field, strings or lists of strings, are names of whatever label says
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct EntityRule {
+	pub field: String,
+	pub label: String,
+}
+
+// per-session mapping between real values and their pseudonyms
+#[derive(Debug, Clone, Default)]
+pub struct Pseudonyms {
+	pseudonyms: HashMap<String, String>,
+	originals: HashMap<String, String>,
+	counts: HashMap<String, usize>,
+}
+
+impl Pseudonyms {
+	fn pseudonym(&mut self, label: &str, value: &str) -> String {
+		if let Some(x) = self.pseudonyms.get(value) {
+			return x.clone();
+		}
+
+		let count = self.counts.entry(label.to_string()).or_default();
+		*count += 1;
+		let pseudonym = format!("[{} {}]", label, count);
+		self.pseudonyms.insert(value.to_string(), pseudonym.clone());
+		self.originals.insert(pseudonym.clone(), value.to_string());
+		pseudonym
+	}
+
+	// known values, longest first so "Sam Reyes" goes before "Sam"
+	fn known(&self) -> Vec<(&String, &String)> {
+		let mut known: Vec<_> = self.pseudonyms.iter().collect();
+		known.sort_by_key(|x| std::cmp::Reverse(x.0.len()));
+		known
+	}
+
+	pub fn reveal(&self, text: &str) -> String {
+		let mut text = text.to_string();
+		for (pseudonym, original) in &self.originals {
+			text = text.replace(pseudonym, original);
+		}
+		text
+	}
+
+	// reveals every string in a JSON value, for arguments of tool calls made by the model
+	pub fn reveal_value(&self, value: &mut serde_json::Value) {
+		walk_strings(value, &mut |x| *x = self.reveal(x));
+	}
+
+	pub fn revealer(&self) -> Revealer {
+		Revealer {
+			pseudonyms: self.clone(),
+			pending: String::new(),
+		}
+	}
+}
+
+// reveals pseudonyms in streamed text, holding back a chunk that ends in the middle of one
+#[derive(Debug, Clone)]
+pub struct Revealer {
+	pseudonyms: Pseudonyms,
+	pending: String,
+}
+
+impl Revealer {
+	pub fn push(&mut self, chunk: &str) -> String {
+		self.pending.push_str(chunk);
+
+		let split = match self.pending.rfind('[') {
+			Some(i)
+				if self.pseudonyms.originals.keys().any(|x| {
+					x.len() > self.pending.len() - i
+						&& x.starts_with(&self.pending[i..])
+				}) =>
+			{
+				i
+			}
+			_ => self.pending.len(),
+		};
+
+		let rest = self.pending.split_off(split);
+		let ready = std::mem::replace(&mut self.pending, rest);
+		self.pseudonyms.reveal(&ready)
+	}
+
+	pub fn refresh(&mut self, pseudonyms: &Pseudonyms) {
+		// pseudonyms are only ever added
+		if pseudonyms.originals.len() != self.pseudonyms.originals.len()
+		{
+			self.pseudonyms = pseudonyms.clone();
+		}
+	}
+
+	pub fn finish(&mut self) -> String {
+		self.pseudonyms.reveal(&std::mem::take(&mut self.pending))
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Redactor {
+	rules: Vec<(Regex, RedactionRule)>,
+	entities: Vec<EntityRule>,
+	drop_fields: Vec<String>,
+}
+
+impl Redactor {
+	pub fn new(config: &RedactionConfig) -> Result<Self, regex::Error> {
+		Ok(Self {
+			rules: config
+				.rules
+				.iter()
+				.map(|x| Ok((Regex::new(&x.pattern)?, x.clone())))
+				.collect::<Result<_, regex::Error>>()?,
+			entities: config.entities.clone(),
+			drop_fields: config.drop_fields.clone(),
+		})
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.rules.is_empty()
+			&& self.entities.is_empty()
+			&& self.drop_fields.is_empty()
+	}
+
+	// redacts the text of a tool result; JSON is redacted field by field, anything else as text
+	pub fn redact(
+		&self, text: &str, pseudonyms: &mut Pseudonyms,
+	) -> String {
+		if self.is_empty() {
+			return text.to_string();
+		}
+
+		let Ok(mut value) =
+			serde_json::from_str::<serde_json::Value>(text)
+		else {
+			return self.redact_text(text, pseudonyms);
+		};
+
+		self.drop(&mut value);
+		self.collect_entities(&value, pseudonyms);
+		walk_strings(&mut value, &mut |x| {
+			*x = self.redact_text(x, pseudonyms)
+		});
+
+		serde_json::to_string_pretty(&value)
+			.unwrap_or_else(|_| text.to_string())
+	}
+
+	fn redact_text(
+		&self, text: &str, pseudonyms: &mut Pseudonyms,
+	) -> String {
+		let mut text = text.to_string();
+
+		for (original, pseudonym) in pseudonyms.known() {
+			text = replace_words(&text, original, pseudonym);
+		}
+
+		for (regex, rule) in &self.rules {
+			text = regex
+				.replace_all(&text, |c: &regex::Captures| {
+					match rule.action {
+						RedactionAction::Mask => {
+							format!("[{}]", rule.label)
+						}
+						RedactionAction::Pseudonymize => {
+							pseudonyms.pseudonym(&rule.label, &c[0])
+						}
+					}
+				})
+				.into_owned();
+		}
+
+		text
+	}
+
+	fn drop(&self, value: &mut serde_json::Value) {
+		match value {
+			serde_json::Value::Object(map) => {
+				map.retain(|k, _| !self.drop_fields.contains(k));
+				map.values_mut().for_each(|x| self.drop(x));
+			}
+			serde_json::Value::Array(list) => {
+				list.iter_mut().for_each(|x| self.drop(x))
+			}
+			_ => {}
+		}
+	}
+
+	fn collect_entities(
+		&self, value: &serde_json::Value, pseudonyms: &mut Pseudonyms,
+	) {
+		match value {
+			serde_json::Value::Object(map) => {
+				for (k, v) in map {
+					if let Some(rule) =
+						self.entities.iter().find(|x| &x.field == k)
+					{
+						let names: Vec<&serde_json::Value> = match v {
+							serde_json::Value::Array(x) => {
+								x.iter().collect()
+							}
+							x => vec![x],
+						};
+						for name in
+							names.iter().filter_map(|x| x.as_str())
+						{
+							if !name.trim().is_empty() {
+								pseudonyms.pseudonym(&rule.label, name);
+							}
+						}
+					}
+					self.collect_entities(v, pseudonyms);
+				}
+			}
+			serde_json::Value::Array(list) => list
+				.iter()
+				.for_each(|x| self.collect_entities(x, pseudonyms)),
+			_ => {}
+		}
+	}
+}
+
+fn walk_strings(
+	value: &mut serde_json::Value, f: &mut impl FnMut(&mut String),
+) {
+	match value {
+		serde_json::Value::String(x) => f(x),
+		serde_json::Value::Object(map) => {
+			map.values_mut().for_each(|x| walk_strings(x, f))
+		}
+		serde_json::Value::Array(list) => {
+			list.iter_mut().for_each(|x| walk_strings(x, f))
+		}
+		_ => {}
+	}
+}
+
+// replaces needle where it stands as a whole word, so "Sam" doesn't touch "Samsung"
+fn replace_words(
+	text: &str, needle: &str, replacement: &str,
+) -> String {
+	let is_word =
+		|c: Option<char>| c.is_some_and(char::is_alphanumeric);
+
+	let mut out = String::new();
+	let mut rest = text;
+
+	while let Some(i) = rest.find(needle) {
+		let before =
+			rest[..i].chars().last().or_else(|| out.chars().last());
+		let after = rest[i + needle.len()..].chars().next();
+
+		out.push_str(&rest[..i]);
+		if is_word(before) || is_word(after) {
+			out.push_str(needle);
+		} else {
+			out.push_str(replacement);
+		}
+		rest = &rest[i + needle.len()..];
+	}
+
+	out.push_str(rest);
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn redactor() -> Redactor {
+		Redactor::new(
+			&serde_yaml_ng::from_str(
+				r#"
+rules:
+  - label: Email
+    pattern: '[\w.+-]+@[\w-]+\.[\w.]+'
+  - label: Phone
+    pattern: '\+?\d[\d -]{7,}\d'
+    action: mask
+entities:
+  - field: name
+    label: Person
+  - field: nicknames
+    label: Person
+drop_fields:
+  - last_active
+"#,
+			)
+			.unwrap(),
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn test_redact() {
+		let mut pseudonyms = Pseudonyms::default();
+		let output = redactor().redact(
+			r#"{"name":"Sam Reyes","nicknames":["Sammy"],"last_active":"2025-01-01T00:00:00Z","status":"mail sam@example.com or call +1 555 123 4567, Sammy at Samsung"}"#,
+			&mut pseudonyms,
+		);
+		let value: serde_json::Value =
+			serde_json::from_str(&output).unwrap();
+
+		assert_eq!(value["name"], "[Person 1]");
+		assert_eq!(value["nicknames"][0], "[Person 2]");
+		assert!(value.get("last_active").is_none());
+		assert_eq!(
+			value["status"],
+			"mail [Email 1] or call [Phone], [Person 2] at Samsung"
+		);
+
+		// the same value keeps its pseudonym, also in plain text results
+		assert_eq!(
+			redactor()
+				.redact("no chats with Sam Reyes", &mut pseudonyms),
+			"no chats with [Person 1]"
+		);
+
+		let mut args = serde_json::json!({"name": "[Person 1]"});
+		pseudonyms.reveal_value(&mut args);
+		assert_eq!(args["name"], "Sam Reyes");
+	}
+
+	#[test]
+	fn test_revealer() {
+		let mut pseudonyms = Pseudonyms::default();
+		redactor().redact(r#"{"name":"Sam Reyes"}"#, &mut pseudonyms);
+
+		let mut revealer = pseudonyms.revealer();
+		let mut output = String::new();
+		for chunk in
+			["You last [talked", "] to [Per", "son 1", "] [sic]."]
+		{
+			output.push_str(&revealer.push(chunk));
+		}
+		output.push_str(&revealer.finish());
+		assert_eq!(output, "You last [talked] to Sam Reyes [sic].");
+
+		assert_eq!(
+			Redactor::new(&Default::default())
+				.unwrap()
+				.redact("Sam", &mut pseudonyms),
+			"Sam"
+		);
+	}
+}
@@ -0,0 +1,191 @@
+use super::Config;
+use rmcp::model::Tool as McpTool;
+use std::collections::HashSet;
+
+// NOTE: the phone sends its tools/list with every prompt, so the model is offered exactly the
+// tools that this app version and its permissions expose. The list is stored on the session and
+// replaces the built-in one (crate::mcp::tool::tool_list) for as long as the session lives. It
+// comes from the network, so it is checked before any of it goes into a request to the model.
+
+// what the model providers accept as a function name
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum RegistrationError {
+	#[error("{0} tools registered, at most {1} are allowed")]
+	TooManyTools(usize, usize),
+	#[error("tool list is {0} bytes, at most {1} are allowed")]
+	TooLarge(usize, usize),
+	#[error("invalid tool name '{0}'")]
+	InvalidName(String),
+	#[error("tool '{0}' is registered more than once")]
+	Duplicate(String),
+	#[error("input schema of tool '{0}' is not an object schema")]
+	InvalidSchema(String),
+}
+
+pub(crate) fn validate_tools(
+	tools: &[McpTool], config: &Config,
+) -> Result<(), RegistrationError> {
+	if tools.len() > config.max_registered_tools {
+		return Err(RegistrationError::TooManyTools(
+			tools.len(),
+			config.max_registered_tools,
+		));
+	}
+
+	let size = serde_json::to_vec(tools).map(|x| x.len()).unwrap_or(0);
+	if size > config.max_tool_list_bytes {
+		return Err(RegistrationError::TooLarge(
+			size,
+			config.max_tool_list_bytes,
+		));
+	}
+
+	let mut names = HashSet::new();
+
+	for tool in tools {
+		let name = tool.name.to_string();
+
+		if name.is_empty()
+			|| name.len() > MAX_NAME_LEN
+			|| !name.chars().all(|c| {
+				c.is_ascii_alphanumeric() || c == '_' || c == '-'
+			}) {
+			return Err(RegistrationError::InvalidName(name));
+		}
+
+		if !names.insert(name.clone()) {
+			return Err(RegistrationError::Duplicate(name));
+		}
+
+		let schema = &tool.input_schema;
+		if schema.get("type").and_then(|x| x.as_str()) != Some("object")
+			|| schema.get("properties").is_some_and(|x| !x.is_object())
+		{
+			return Err(RegistrationError::InvalidSchema(name));
+		}
+	}
+
+	Ok(())
+}
+
+// tools that may change something: all of them but the ones the phone says only read, as MCP
+// assumes of a tool without annotations. Calls to these are confirmed with the user like the
+// built-in actions.
+pub(crate) fn is_registered_action(tool: &McpTool) -> bool {
+	tool.annotations
+		.as_ref()
+		.is_none_or(|x| x.read_only_hint != Some(true))
+}
+
+pub(crate) fn to_llm_tool(tool: &McpTool) -> llm::chat::Tool {
+	llm::chat::Tool {
+		tool_type: "function".into(),
+		function: llm::chat::FunctionTool {
+			name: tool.name.to_string(),
+			description: tool
+				.description
+				.as_deref()
+				.unwrap_or_default()
+				.to_string(),
+			parameters: serde_json::Value::Object(
+				(*tool.input_schema).clone(),
+			),
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn tool(name: &str, schema: serde_json::Value) -> McpTool {
+		serde_json::from_value(serde_json::json!({
+			"name": name,
+			"description": "a tool",
+			"inputSchema": schema,
+		}))
+		.unwrap()
+	}
+
+	fn object() -> serde_json::Value {
+		serde_json::json!({"type": "object", "properties": {}})
+	}
+
+	#[test]
+	fn test_validate_tools() {
+		let config = Config {
+			max_registered_tools: 2,
+			max_tool_list_bytes: 1024,
+			..Default::default()
+		};
+
+		assert_eq!(
+			validate_tools(
+				&[
+					tool("contact_info", object()),
+					tool("send-it", object())
+				],
+				&config
+			),
+			Ok(())
+		);
+		assert_eq!(
+			validate_tools(
+				&[
+					tool("a", object()),
+					tool("b", object()),
+					tool("c", object())
+				],
+				&config
+			),
+			Err(RegistrationError::TooManyTools(3, 2))
+		);
+		assert!(matches!(
+			validate_tools(
+				&[tool(
+					"a",
+					serde_json::json!({"type": "object", "description": "x".repeat(2048)})
+				)],
+				&config
+			),
+			Err(RegistrationError::TooLarge(_, 1024))
+		));
+		assert_eq!(
+			validate_tools(&[tool("send message", object())], &config),
+			Err(RegistrationError::InvalidName("send message".into()))
+		);
+		assert_eq!(
+			validate_tools(
+				&[tool("a", object()), tool("a", object())],
+				&config
+			),
+			Err(RegistrationError::Duplicate("a".into()))
+		);
+		assert_eq!(
+			validate_tools(
+				&[tool("a", serde_json::json!({"type": "string"}))],
+				&config
+			),
+			Err(RegistrationError::InvalidSchema("a".into()))
+		);
+	}
+
+	#[test]
+	fn test_registered_action() {
+		let mut send = tool("send", object());
+		assert!(is_registered_action(&send));
+
+		let annotate = |x| Some(serde_json::from_value(x).unwrap());
+		send.annotations =
+			annotate(serde_json::json!({"destructiveHint": true}));
+		assert!(is_registered_action(&send));
+		send.annotations =
+			annotate(serde_json::json!({"readOnlyHint": false}));
+		assert!(is_registered_action(&send));
+		send.annotations =
+			annotate(serde_json::json!({"readOnlyHint": true}));
+		assert!(!is_registered_action(&send));
+	}
+}
@@ -0,0 +1,120 @@
+use super::{Config, LimitsConfig, ServerState, logging};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::signal::unix::{SignalKind, signal};
+
+// NOTE: SIGHUP, or POST /admin/reload, reads the config again the way it was read at startup and
+// swaps it in. A session keeps the config it started with (see Session.config), so a conversation
+// doesn't change model halfway; new sessions get the new one. Log levels and filters change at
+// once. Some settings are only read at startup, see restart_needed; a reload changing them is
+// still done, and says which need a restart. A config that doesn't load or validate changes
+// nothing.
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reload {
+	// reloads since the server started, this one included
+	pub generation: u64,
+	// settings that changed but keep their old value until a restart
+	pub restart_needed: Vec<String>,
+}
+
+// reloads, and logs how it went
+pub(crate) async fn reload(
+	state: &ServerState,
+) -> anyhow::Result<Reload> {
+	let result = swap(state).await;
+	match &result {
+		Ok(x) => {
+			tracing::info!("configuration reloaded: {}", x.generation);
+			if !x.restart_needed.is_empty() {
+				tracing::warn!(
+					"changes to {} take effect after a restart",
+					x.restart_needed.join(", ")
+				);
+			}
+		}
+		Err(e) => tracing::error!("configuration not reloaded: {}", e),
+	}
+	result
+}
+
+async fn swap(state: &ServerState) -> anyhow::Result<Reload> {
+	let Some(loader) = &state.loader else {
+		return Err(anyhow!(
+			"the server was started without a config to reload"
+		));
+	};
+
+	// one at a time, so the config in use is the last one read
+	let mut generation = state.reloads.lock().await;
+	let config = loader.load()?;
+	logging::reload(&config.logging, config.log_level.clone().into())?;
+
+	let restart_needed = restart_needed(&state.config(), &config);
+	state.set_config(config);
+	*generation += 1;
+	Ok(Reload {
+		generation: *generation,
+		restart_needed,
+	})
+}
+
+// settings read once at startup that are different in the new config
+fn restart_needed(old: &Config, new: &Config) -> Vec<String> {
+	[
+		("listen", old.listen != new.listen),
+		(
+			"logging.format",
+			differ(&old.logging.format, &new.logging.format),
+		),
+		(
+			"logging.debug_content",
+			old.logging.debug_content != new.logging.debug_content,
+		),
+		("tracing", differ(&old.tracing, &new.tracing)),
+		("tls", old.tls != new.tls),
+		(
+			"admin.listen",
+			old.admin.as_ref().map(|x| x.listen)
+				!= new.admin.as_ref().map(|x| x.listen),
+		),
+		(
+			"admin.cors",
+			old.admin.as_ref().map(|x| &x.cors)
+				!= new.admin.as_ref().map(|x| &x.cors),
+		),
+		("cors", old.cors != new.cors),
+		// max_stream_secs applies from the next stream on
+		(
+			"limits",
+			LimitsConfig {
+				max_stream_secs: new.limits.max_stream_secs,
+				..old.limits.clone()
+			} != new.limits,
+		),
+	]
+	.into_iter()
+	.filter(|x| x.1)
+	.map(|x| x.0.to_string())
+	.collect()
+}
+
+fn differ<T: Serialize>(a: &T, b: &T) -> bool {
+	serde_json::to_value(a).ok() != serde_json::to_value(b).ok()
+}
+
+// reloads on every SIGHUP, for as long as the process runs
+pub(crate) async fn on_hangup(state: Arc<ServerState>) {
+	let mut hangup = match signal(SignalKind::hangup()) {
+		Ok(x) => x,
+		Err(e) => {
+			tracing::warn!("could not install SIGHUP handler: {}", e);
+			return;
+		}
+	};
+
+	while hangup.recv().await.is_some() {
+		let _ = reload(&state).await;
+	}
+}
@@ -0,0 +1,176 @@
+use super::logging;
+use opentelemetry::{
+	global,
+	propagation::{Extractor, TextMapPropagator},
+	trace::TracerProvider as _,
+};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+	Resource,
+	propagation::TraceContextPropagator,
+	trace::{Sampler, SdkTracerProvider},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::OnceLock};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+	Layer,
+	filter::{FilterExt, LevelFilter, filter_fn},
+	registry::LookupSpan,
+};
+
+// NOTE: spans are exported over OTLP/HTTP when an endpoint is configured. A conversation is one
+// trace: the session span is opened by the request that created the session, and turns, LLM
+// requests and tool calls are recorded under it, however many times the phone reconnects in
+// between. Requests carrying a W3C traceparent header continue the phone's trace. Tool calls
+// hand their traceparent to the phone in the McpRequest, and the McpResponse brings it back.
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+fn default_service_name() -> String {
+	"allelo-ai-api".into()
+}
+
+fn default_sample_ratio() -> f64 {
+	1.0
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+	// e.g. http://localhost:4318/v1/traces; nothing is exported without it
+	#[serde(default)]
+	pub otlp_endpoint: Option<String>,
+	#[serde(default = "default_service_name")]
+	pub service_name: String,
+	// the share of new traces kept; traces started by the phone follow its decision
+	#[serde(default = "default_sample_ratio")]
+	pub sample_ratio: f64,
+}
+
+impl Default for TracingConfig {
+	fn default() -> Self {
+		Self {
+			otlp_endpoint: None,
+			service_name: default_service_name(),
+			sample_ratio: default_sample_ratio(),
+		}
+	}
+}
+
+pub fn provider(
+	config: &TracingConfig, endpoint: &str,
+) -> anyhow::Result<SdkTracerProvider> {
+	let exporter = opentelemetry_otlp::SpanExporter::builder()
+		.with_http()
+		.with_endpoint(endpoint)
+		.build()?;
+
+	Ok(SdkTracerProvider::builder()
+		.with_batch_exporter(exporter)
+		.with_sampler(Sampler::ParentBased(Box::new(
+			Sampler::TraceIdRatioBased(config.sample_ratio),
+		)))
+		.with_resource(
+			Resource::builder()
+				.with_service_name(config.service_name.clone())
+				.build(),
+		)
+		.build())
+}
+
+// the layer exporting spans to the provider; the user's data is never exported, see super::logging
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S> + use<S>
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+{
+	tracing_opentelemetry::layer()
+		.with_tracer(provider.tracer("allelo-mcp"))
+		.with_filter(
+			LevelFilter::INFO
+				.and(filter_fn(|x| !logging::has_content(x))),
+		)
+}
+
+// a layer for the global subscriber, if export is configured
+pub(crate) fn init<S>(
+	config: &TracingConfig,
+) -> anyhow::Result<Option<impl Layer<S> + use<S>>>
+where
+	S: Subscriber + for<'a> LookupSpan<'a>,
+{
+	let Some(endpoint) = &config.otlp_endpoint else {
+		return Ok(None);
+	};
+
+	let provider = provider(config, endpoint)?;
+	let layer = layer(&provider);
+	global::set_tracer_provider(provider.clone());
+	let _ = PROVIDER.set(provider);
+	Ok(Some(layer))
+}
+
+// exports what is left; for shutting down
+pub fn shutdown() {
+	if let Some(provider) = PROVIDER.get()
+		&& let Err(e) = provider.shutdown()
+	{
+		tracing::warn!("could not export the last spans: {}", e);
+	}
+}
+
+struct HeaderExtractor<'a>(&'a http::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+	fn get(&self, key: &str) -> Option<&str> {
+		self.0.get(key).and_then(|x| x.to_str().ok())
+	}
+
+	fn keys(&self) -> Vec<&str> {
+		self.0.keys().map(|x| x.as_str()).collect()
+	}
+}
+
+// for tower_http's TraceLayer: a span per request, continuing the caller's trace if it sent one
+pub(crate) fn request_span<B>(
+	request: &http::Request<B>,
+) -> tracing::Span {
+	let span = tracing::info_span!(
+		"request",
+		method = %request.method(),
+		path = %request.uri().path(),
+	);
+	let _ = span.set_parent(
+		TraceContextPropagator::new()
+			.extract(&HeaderExtractor(request.headers())),
+	);
+	span
+}
+
+// the span of a new session, in the trace of the request opening it; it outlives the request, so it
+// isn't the request span's child, which would keep that open as long
+pub(crate) fn session_span(id: uuid::Uuid) -> tracing::Span {
+	let span =
+		tracing::info_span!(parent: None, "session", session.id = %id);
+	let _ = span.set_parent(tracing::Span::current().context());
+	span
+}
+
+// the W3C traceparent of the span, for whoever continues the trace
+pub(crate) fn traceparent(span: &tracing::Span) -> Option<String> {
+	let mut carrier: HashMap<String, String> = HashMap::new();
+	TraceContextPropagator::new()
+		.inject_context(&span.context(), &mut carrier);
+	carrier.remove("traceparent")
+}
+
+// makes the span a child of the one named by a traceparent
+pub(crate) fn continue_trace(span: &tracing::Span, traceparent: &str) {
+	let carrier = HashMap::from([(
+		"traceparent".to_string(),
+		traceparent.into(),
+	)]);
+	let _ = span
+		.set_parent(TraceContextPropagator::new().extract(&carrier));
+}
@@ -3,7 +3,17 @@ use crate::testutil::{
 	default_api_url, shutdown_handle, start_api_server,
 };
 
-use reqwest_eventsource::Event;
+use crate::api::client::{PromptEvent, PromptStream};
+
+// the next PromptResponse, skipping notices about the connection
+async fn next_response(r: &mut PromptStream) -> PromptResponse {
+	loop {
+		match r.next().await.unwrap().unwrap() {
+			PromptEvent::Response(x) => return x,
+			_ => {}
+		}
+	}
+}
 
 #[tokio::test]
 async fn test_sse() {
@@ -18,93 +28,1449 @@ async fn test_sse() {
 		.prompt(Prompt {
 			connection_id: Default::default(),
 			prompt: Some("hello, world".into()),
+			..Default::default()
+		})
+		.unwrap();
+
+	let id = match next_response(&mut r).await {
+		PromptResponse::Connection(id) => id,
+		x => panic!("expected a connection, got {:?}", x),
+	};
+	assert_eq!(r.connection_id(), Some(id));
+
+	for _ in 0..10 {
+		assert!(matches!(
+			next_response(&mut r).await,
+			PromptResponse::PromptResponse(_)
+		));
+	}
+
+	let cursor = r.cursor();
+	assert!(cursor.is_some());
+	drop(r);
+
+	// resumes after the last event seen, on a new stream
+	let mut r = client
+		.prompt(Prompt {
+			connection_id: Some(id),
+			prompt: None,
+			cursor,
+			..Default::default()
+		})
+		.unwrap();
+
+	assert!(matches!(
+		next_response(&mut r).await,
+		PromptResponse::Connection(x) if x == id
+	));
+
+	for _ in 0..10 {
+		assert!(matches!(
+			next_response(&mut r).await,
+			PromptResponse::PromptResponse(_)
+		));
+	}
+	assert!(r.cursor() > cursor);
+
+	shutdown_handle(handle);
+}
+
+#[tokio::test]
+async fn test_client_mcp() {
+	use crate::mcp::{
+		service::Service,
+		store::{Contact, MemoryStore, StoreData},
+	};
+
+	let store = MemoryStore::from(StoreData {
+		contacts: vec![Contact {
+			id: "1".into(),
+			name: "Sam Reyes".into(),
+			..Default::default()
+		}],
+		..Default::default()
+	});
+	let client = super::super::client::Client::with_service(
+		default_api_url(),
+		Service::new(std::sync::Arc::new(store)),
+	)
+	.await
+	.unwrap();
+
+	let response = client
+		.call_mcp(
+			&serde_json::json!({
+				"jsonrpc": "2.0",
+				"id": "call-1",
+				"method": "tools/call",
+				"params": {
+					"name": "contact_info",
+					"arguments": {"name": "sam"},
+				},
+			})
+			.to_string(),
+		)
+		.await
+		.unwrap();
+
+	assert_eq!(rpc_id(&response).unwrap(), "call-1");
+	assert!(
+		super::dispatch::tool_result(&response)
+			.unwrap()
+			.contains("Sam Reyes")
+	);
+}
+
+#[tokio::test]
+async fn test_client_api() {
+	use super::super::client::{Client, ClientError, ClientOptions};
+	use crate::mcp::{
+		search::DocumentKind,
+		store::{Contact, MemoryStore, StoreData},
+	};
+
+	let handle = start_api_server(Config {
+		listen: "127.0.0.1:8997".parse().unwrap(),
+		..Default::default()
+	})
+	.await
+	.unwrap();
+	let client = Client::new("http://127.0.0.1:8997".parse().unwrap())
+		.await
+		.unwrap()
+		.with_options(
+			&ClientOptions::default().with_bearer_token("abc"),
+		)
+		.unwrap();
+
+	// opened by someone; without principal_header, a session is anyone's who has its id
+	let id = {
+		let mut broker = broker::GLOBAL_BROKER.lock().await;
+		let id = broker.create().unwrap();
+		broker.set_owner(id, "10.0.0.1");
+		id
+	};
+	let search = Search {
+		connection_id: id,
+		input: "sam".into(),
+		..Default::default()
+	};
+	assert!(
+		client
+			.search(search.clone())
+			.await
+			.unwrap()
+			.results
+			.is_empty()
+	);
+
+	let store = MemoryStore::from(StoreData {
+		contacts: vec![Contact {
+			id: "1".into(),
+			name: "Sam Reyes".into(),
+			..Default::default()
+		}],
+		..Default::default()
+	});
+	assert_eq!(client.index_store(id, &store).await.unwrap(), 1);
+	assert_eq!(client.index_store(id, &store).await.unwrap(), 1);
+	let results = client.search(search.clone()).await.unwrap().results;
+	assert_eq!(results.len(), 1);
+	assert_eq!(results[0].kind, DocumentKind::Contact);
+	assert_eq!(results[0].id, "1");
+	assert!(
+		client
+			.search(Search {
+				connection_id: Default::default(),
+				..search
+			})
+			.await
+			.is_err()
+	);
+
+	assert!(
+		client
+			.input(Input {
+				connection_id: id,
+				input: "hello".into(),
+				urgent: true,
+			})
+			.await
+			.unwrap()
+	);
+	let session =
+		broker::GLOBAL_BROKER.lock().await.get_session(id).unwrap();
+	assert!(session.lock().await.interjections.has_urgent());
+	assert_eq!(session.lock().await.interjections.take(), ["hello"]);
+	assert!(
+		client
+			.input(Input {
+				input: "hello".into(),
+				..Default::default()
+			})
+			.await
+			.is_err()
+	);
+	let metrics = client.metrics().await.unwrap();
+	assert!(metrics.contains(
+		"allelo_http_requests_total{route=\"/search\",method=\"POST\",status=\"200\"}"
+	));
+	assert!(metrics.contains("# TYPE allelo_broker_sessions gauge"));
+	let status = client.status().await.unwrap();
+	assert!(status.live && !status.ready);
+	assert!(status.broker.sessions >= 1);
+	// /admin/* is only served on an admin listener
+	let response = reqwest::get("http://127.0.0.1:8997/admin/sessions")
+		.await
+		.unwrap();
+	assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+
+	match client
+		.mcp_response(McpResponse {
+			connection_id: uuid::Uuid::new_v4().to_string(),
+			response: r#"{"jsonrpc":"2.0","id":"1","result":{}}"#
+				.into(),
+			..Default::default()
 		})
 		.await
+	{
+		Err(ClientError::Problem { problem, .. }) => {
+			assert_eq!(problem.detail.as_deref(), Some("stream closed"))
+		}
+		x => panic!("expected a problem, got {:?}", x),
+	}
+
+	shutdown_handle(handle);
+}
+
+#[tokio::test]
+async fn test_client_options() {
+	use super::super::client::{Client, ClientError, ClientOptions};
+	use std::time::Duration;
+	use tokio::io::AsyncReadExt;
+
+	// answers nothing, so every call times out
+	let listener =
+		tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let url = format!("http://{}", listener.local_addr().unwrap());
+	let request = tokio::spawn(async move {
+		let (mut conn, _) = listener.accept().await.unwrap();
+		let mut buf = vec![0; 4096];
+		let n = conn.read(&mut buf).await.unwrap();
+		let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+		tokio::time::sleep(Duration::from_secs(1)).await;
+		request
+	});
+
+	let mut options =
+		ClientOptions::default().with_bearer_token("token");
+	options.timeout = Duration::from_millis(200);
+	options.headers.push(("x-api-key".into(), "abc".into()));
+	let client = Client::new(url.parse().unwrap())
+		.await
+		.unwrap()
+		.with_options(&options)
+		.unwrap();
+
+	assert!(matches!(client.status().await, Err(ClientError::Timeout)));
+
+	let request = request.await.unwrap();
+	assert!(request.contains("x-api-key: abc"));
+	assert!(request.contains("authorization: bearer token"));
+}
+
+#[tokio::test]
+async fn test_client_outbox() {
+	use super::super::client::{
+		Client, ClientError, Outbox, OutboxMessage,
+	};
+
+	let url: url::Url = "http://127.0.0.1:8996".parse().unwrap();
+	let client = Client::new_testing(url, QueryType::RepeatPrompt)
+		.await
+		.unwrap()
+		.with_outbox(Outbox::default());
+
+	// the server isn't up yet, so both prompts wait in the outbox
+	let mut first = client
+		.prompt(Prompt {
+			prompt: Some("first".into()),
+			..Default::default()
+		})
+		.unwrap();
+	let mut second = client
+		.prompt(Prompt {
+			prompt: Some("second".into()),
+			..Default::default()
+		})
 		.unwrap();
 
-	let x = r.recv().await.unwrap().unwrap();
-	assert!(matches!(x, Event::Open));
+	let outbox = client.outbox().unwrap();
+	let items = outbox.items();
+	assert_eq!(items.len(), 2);
+	assert!(matches!(
+		&items[0].message,
+		OutboxMessage::Prompt(x) if x.prompt.as_deref() == Some("first")
+	));
+	assert!(matches!(
+		first.next().await.unwrap().unwrap(),
+		PromptEvent::Disconnected { .. }
+	));
+
+	assert!(outbox.cancel(items[1].id).unwrap());
+	assert!(matches!(
+		second.next().await.unwrap(),
+		Err(ClientError::Cancelled)
+	));
+
+	let handle = start_api_server(Config {
+		listen: "127.0.0.1:8996".parse().unwrap(),
+		..Default::default()
+	})
+	.await
+	.unwrap();
+
+	assert!(matches!(
+		next_response(&mut first).await,
+		PromptResponse::Connection(_)
+	));
+	assert!(
+		matches!(next_response(&mut first).await, PromptResponse::PromptResponse(x) if x == "first")
+	);
+	assert!(outbox.items().is_empty());
+
+	shutdown_handle(handle);
+}
+
+#[tokio::test]
+async fn test_idempotency_key() {
+	use eventsource_stream::Eventsource;
+	use futures_util::StreamExt;
 
-	let x = r.recv().await.unwrap().unwrap();
-	assert!(matches!(x, Event::Message(_)));
+	let handle = start_api_server(Config {
+		listen: "127.0.0.1:8995".parse().unwrap(),
+		..Default::default()
+	})
+	.await
+	.unwrap();
+	let http = reqwest::Client::new();
 
-	let mut id: uuid::Uuid = Default::default();
+	// the session the prompt stream answering this request belongs to
+	async fn connect(
+		http: &reqwest::Client, prompt: &Prompt, key: &str,
+	) -> uuid::Uuid {
+		let response = http
+			.post(
+				"http://127.0.0.1:8995/prompt?query_type=repeat_prompt",
+			)
+			.header("Idempotency-Key", key)
+			.header("Content-Type", "application/json")
+			.body(serde_json::to_vec(prompt).unwrap())
+			.send()
+			.await
+			.unwrap();
+		let mut events =
+			Box::pin(response.bytes_stream().eventsource());
 
-	if let Event::Message(m) = x {
-		let obj: PromptResponse =
-			serde_json::from_str(&m.data).unwrap();
-		assert!(matches!(obj, PromptResponse::Connection(_)));
-		if let PromptResponse::Connection(i) = obj {
-			id = i
+		let event = events.next().await.unwrap().unwrap();
+		match serde_json::from_str(&event.data).unwrap() {
+			PromptResponse::Connection(id) => id,
+			x => panic!("expected a connection, got {:?}", x),
 		}
 	}
 
-	let mut i = 0;
+	let prompt = Prompt {
+		prompt: Some("hello".into()),
+		..Default::default()
+	};
+	let id = connect(&http, &prompt, "prompt-1").await;
+	let retry = Prompt {
+		connection_id: Some(id),
+		..prompt.clone()
+	};
+	assert_eq!(connect(&http, &retry, "prompt-1").await, id);
+	// nor a new prompt's, when callers are only told apart by their address
+	assert_ne!(connect(&http, &prompt, "prompt-1").await, id);
+	assert_ne!(connect(&http, &prompt, "prompt-2").await, id);
+
+	// a retried tool response is taken once
+	let session =
+		broker::GLOBAL_BROKER.lock().await.get_session(id).unwrap();
+	let (s, r) = tokio::sync::oneshot::channel();
+	session.lock().await.calls.insert("call-1".into(), s);
+
+	let response = McpResponse {
+		connection_id: id.to_string(),
+		response: r#"{"jsonrpc":"2.0","id":"call-1","result":{}}"#
+			.into(),
+		..Default::default()
+	};
+	for _ in 0..2 {
+		let status = http
+			.post("http://127.0.0.1:8995/mcp_response")
+			.header("Idempotency-Key", "response-1")
+			.header("Content-Type", "application/json")
+			.body(serde_json::to_vec(&response).unwrap())
+			.send()
+			.await
+			.unwrap()
+			.status();
+		assert!(status.is_success());
+	}
+	assert_eq!(r.await.unwrap(), response.response);
+
+	shutdown_handle(handle);
+}
+
+#[tokio::test]
+async fn test_health() {
+	use crate::api::llm::{LLMClientParams, LLMClientType};
+	use http::StatusCode;
+
+	// an Ollama server that has one model pulled, behind a proxy's path
+	let listener =
+		tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let ollama =
+		format!("http://{}/ollama", listener.local_addr().unwrap());
+	tokio::spawn(async move {
+		axum::serve(
+			listener,
+			Router::new().route(
+				"/ollama/api/tags",
+				get(|| async {
+					r#"{"models":[{"name":"qwen3:30b"}]}"#
+				}),
+			),
+		)
+		.await
+		.unwrap()
+	});
+
+	let config = |port: u16, client_type| Config {
+		listen: format!("127.0.0.1:{}", port).parse().unwrap(),
+		client_type: Some(client_type),
+		client_params: Some(LLMClientParams {
+			base_url: ollama.clone(),
+			api_key: None,
+			timeout: None,
+			force_tools: false,
+		}),
+		..Default::default()
+	};
+	let ready =
+		start_api_server(config(8994, LLMClientType::OllamaQwen3))
+			.await
+			.unwrap();
+	let missing =
+		start_api_server(config(8993, LLMClientType::OllamaQwen25))
+			.await
+			.unwrap();
+
+	let http = reqwest::Client::new();
+	let probe = async |port: u16, path: &str| {
+		let response = http
+			.get(format!("http://127.0.0.1:{}{}", port, path))
+			.send()
+			.await
+			.unwrap();
+		(response.status(), response.text().await.unwrap())
+	};
 
-	while let Some(Ok(m)) = r.recv().await {
-		if i == 10 {
+	assert_eq!(probe(8994, "/healthz").await.0, StatusCode::OK);
+	assert_eq!(probe(8994, "/readyz").await.0, StatusCode::OK);
+
+	// alive, but not fit to take prompts
+	assert_eq!(probe(8993, "/healthz").await.0, StatusCode::OK);
+	let (code, body) = probe(8993, "/readyz").await;
+	assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+	assert!(body.contains("llm_model"));
+
+	let status: Status =
+		serde_json::from_str(&probe(8993, "/status").await.1).unwrap();
+	assert!(status.live && !status.ready);
+	assert!(
+		status
+			.components
+			.iter()
+			.any(|x| x.name == "llm_backend" && x.healthy)
+	);
+
+	shutdown_handle(ready);
+	shutdown_handle(missing);
+}
+
+#[tokio::test]
+async fn test_tracing() {
+	use crate::api::llm::{LLMClientParams, LLMClientType};
+	use eventsource_stream::Eventsource;
+	use futures_util::StreamExt;
+	use opentelemetry_proto::tonic::collector::trace::v1::ExportTraceServiceRequest;
+	use prost::Message;
+	use tracing_subscriber::layer::SubscriberExt;
+
+	// an OTLP collector keeping the spans it is sent
+	let listener =
+		tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let collector =
+		format!("http://{}/v1/traces", listener.local_addr().unwrap());
+	let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+	let sink = received.clone();
+	tokio::spawn(async move {
+		axum::serve(
+			listener,
+			Router::new().route(
+				"/v1/traces",
+				post(async move |body: axum::body::Bytes| {
+					let request =
+						ExportTraceServiceRequest::decode(body)
+							.unwrap();
+					sink.lock().unwrap().extend(
+						request
+							.resource_spans
+							.into_iter()
+							.flat_map(|x| x.scope_spans)
+							.flat_map(|x| x.spans),
+					);
+				}),
+			),
+		)
+		.await
+		.unwrap()
+	});
+
+	// a model that calls a tool, then answers
+	let listener =
+		tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let ollama = format!("http://{}", listener.local_addr().unwrap());
+	let called = Arc::new(std::sync::atomic::AtomicBool::new(false));
+	tokio::spawn(async move {
+		axum::serve(
+			listener,
+			Router::new().route(
+				"/api/chat",
+				post(
+					async move |axum::Json(body): axum::Json<
+						serde_json::Value,
+					>| {
+						let message = if body["stream"] == true {
+							serde_json::json!({"content": "done"})
+						} else if !called.swap(
+							true,
+							std::sync::atomic::Ordering::SeqCst,
+						) {
+							serde_json::json!({
+								"content": "",
+								"tool_calls": [{
+									"function": {"name": "test_tool", "arguments": {}},
+								}],
+							})
+						} else {
+							serde_json::json!({"content": ""})
+						};
+						format!(
+							"{}\n",
+							serde_json::json!({"message": message})
+						)
+					},
+				),
+			),
+		)
+		.await
+		.unwrap()
+	});
+
+	let provider =
+		telemetry::provider(&TracingConfig::default(), &collector)
+			.unwrap();
+	let _subscriber = tracing::subscriber::set_default(
+		tracing_subscriber::registry()
+			.with(telemetry::layer(&provider)),
+	);
+
+	let handle = start_api_server(Config {
+		listen: "127.0.0.1:8992".parse().unwrap(),
+		client_type: Some(LLMClientType::OllamaQwen3),
+		client_params: Some(LLMClientParams {
+			base_url: ollama,
+			api_key: None,
+			timeout: None,
+			force_tools: false,
+		}),
+		..Default::default()
+	})
+	.await
+	.unwrap();
+
+	// the phone's trace
+	let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+	let http = reqwest::Client::new();
+	let response = http
+		.post("http://127.0.0.1:8992/prompt")
+		.header(
+			"traceparent",
+			format!("00-{}-00f067aa0ba902b7-01", trace_id),
+		)
+		.header("Content-Type", "application/json")
+		.body(
+			serde_json::to_vec(&Prompt {
+				prompt: Some("hello".into()),
+				..Default::default()
+			})
+			.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+	let mut events = Box::pin(response.bytes_stream().eventsource());
+	let mut next = async || {
+		serde_json::from_str::<PromptResponse>(
+			&events.next().await.unwrap().unwrap().data,
+		)
+		.unwrap()
+	};
+	let PromptResponse::Connection(id) = next().await else {
+		panic!("expected a connection")
+	};
+
+	// the phone answers the tool call, passing its trace context back
+	let request = loop {
+		if let PromptResponse::McpRequest(x) = next().await {
+			break x;
+		}
+	};
+	let traceparent = request.traceparent.clone().unwrap();
+	assert!(traceparent.contains(trace_id), "{}", traceparent);
+	let call: serde_json::Value =
+		serde_json::from_str(&request.command).unwrap();
+	let response = http
+		.post("http://127.0.0.1:8992/mcp_response")
+		.header("Content-Type", "application/json")
+		.body(
+			serde_json::to_vec(&McpResponse {
+				connection_id: id.to_string(),
+				response: serde_json::json!({
+					"jsonrpc": "2.0",
+					"id": call["id"],
+					"result": {"content": [{"type": "text", "text": "ok"}]},
+				})
+				.to_string(),
+				traceparent: Some(traceparent),
+			})
+			.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+	assert!(response.status().is_success());
+	loop {
+		if let PromptResponse::PromptResponse(x) = next().await {
+			assert_eq!(x, "done");
 			break;
 		}
+	}
+	// the request span closes with the stream, and the session span with the session
+	drop(events);
+	broker::GLOBAL_BROKER.lock().await.expire(id);
 
-		match m {
-			Event::Open => {}
-			Event::Message(m) => {
-				let obj: PromptResponse =
-					serde_json::from_str(&m.data).unwrap();
-				eprintln!("{}", m.data);
-				assert!(matches!(
-					obj,
-					PromptResponse::PromptResponse(_)
-				));
-				i += 1;
-			}
+	// OTLP/HTTP sends protobuf, with trace ids as raw bytes
+	let trace_id: Vec<u8> = (0..trace_id.len())
+		.step_by(2)
+		.map(|i| u8::from_str_radix(&trace_id[i..i + 2], 16).unwrap())
+		.collect();
+	let names =
+		["session", "turn", "llm.chat", "mcp.request", "mcp.response"];
+	let mut spans = Vec::new();
+	for _ in 0..50 {
+		tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+		// the exporter blocks on a collector served by this thread
+		let flushing = provider.clone();
+		tokio::task::spawn_blocking(move || flushing.force_flush())
+			.await
+			.unwrap()
+			.unwrap();
+		spans = received.lock().unwrap().clone();
+		if names.iter().all(|x| spans.iter().any(|y| y.name == *x)) {
+			break;
 		}
 	}
 
-	r.close();
+	let span = |name: &str| {
+		spans
+			.iter()
+			.find(|x| x.name == name)
+			.unwrap_or_else(|| panic!("no {} span was exported", name))
+	};
+	for name in names {
+		assert_eq!(span(name).trace_id, trace_id, "{}", name);
+	}
+	// the response is a child of the request the phone answered
+	assert_eq!(
+		span("mcp.response").parent_span_id,
+		span("mcp.request").span_id
+	);
 
-	let mut r = client
-		.prompt(Prompt {
-			connection_id: Some(id),
-			prompt: None,
-		})
+	shutdown_handle(handle);
+}
+
+#[test]
+fn test_default_config() {
+	let config: Config =
+		serde_yaml_ng::from_str(DEFAULT_CONFIG).unwrap();
+	assert_eq!(
+		serde_json::to_value(&config).unwrap(),
+		serde_json::to_value(Config::default()).unwrap()
+	);
+}
+
+#[test]
+fn test_config() {
+	let env = |vars: &[(&str, &str)]| {
+		vars.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect::<Vec<_>>()
+	};
+
+	// the example, and nothing at all
+	Config::parse(include_str!("../../../example_config.yaml"), [])
+		.unwrap();
+	assert_eq!(
+		serde_json::to_value(Config::parse("", []).unwrap()).unwrap(),
+		serde_json::to_value(Config::default()).unwrap()
+	);
+
+	// the environment over the file
+	let config = Config::parse(
+		"log_level: debug\nclient_type: ollama_qwen3\n",
+		env(&[
+			("ALLELO_LOG_LEVEL", "warn"),
+			("ALLELO_TOOL_TIMEOUT_SECS", "5"),
+			("ALLELO_CLIENT_PARAMS__BASE_URL", "http://ollama:11434"),
+			("ALLELO_MODEL", "not a config key"),
+		]),
+	)
+	.unwrap();
+	assert!(matches!(config.log_level, LogLevel::Warn));
+	assert_eq!(config.tool_timeout_secs, 5);
+	assert_eq!(
+		config.client_params.unwrap().base_url,
+		"http://ollama:11434"
+	);
+
+	// secrets from a file or another variable
+	let path = std::env::temp_dir()
+		.join(format!("allelo-api-key-{}", uuid::Uuid::new_v4()));
+	std::fs::write(&path, "from-file\n").unwrap();
+	let params = |key: &str| {
+		format!(
+			"client_type: ollama_qwen3\nclient_params:\n  base_url: http://localhost:11434\n  {}\n",
+			key
+		)
+	};
+	let config = Config::parse(
+		&params(&format!("api_key_file: {}", path.display())),
+		[],
+	)
+	.unwrap();
+	assert_eq!(
+		config.client_params.unwrap().api_key.as_deref(),
+		Some("from-file")
+	);
+	std::fs::remove_file(&path).unwrap();
+
+	let config = Config::parse(
+		&params("api_key_env: LLM_KEY"),
+		env(&[("LLM_KEY", "from-env")]),
+	)
+	.unwrap();
+	assert_eq!(
+		config.client_params.unwrap().api_key.as_deref(),
+		Some("from-env")
+	);
+
+	// strings from the environment are taken as they are, even when they look like something else
+	let config = Config::parse(
+		"admin:\n  listen: 127.0.0.1:9000\n",
+		env(&[
+			("ALLELO_ADMIN__TOKEN", "012345"),
+			("ALLELO_CLIENT_TYPE", "ollama_qwen3"),
+			(
+				"ALLELO_CLIENT_PARAMS__BASE_URL",
+				"http://localhost:11434",
+			),
+			("ALLELO_CLIENT_PARAMS__API_KEY", "yes"),
+		]),
+	)
+	.unwrap();
+	assert_eq!(config.admin.unwrap().token.as_deref(), Some("012345"));
+	assert_eq!(
+		config.client_params.unwrap().api_key.as_deref(),
+		Some("yes")
+	);
+	let config = Config::parse(
+		"admin:\n  listen: 127.0.0.1:9000\n",
+		env(&[("ALLELO_ADMIN__TOKEN", "null")]),
+	)
+	.unwrap();
+	assert_eq!(config.admin.unwrap().token.as_deref(), Some("null"));
+
+	// problems name their key
+	let problems = |yaml: &str| match Config::parse(yaml, []) {
+		Err(ConfigError::Invalid(x)) => {
+			x.into_iter().map(|x| x.key).collect::<Vec<_>>()
+		}
+		x => panic!("expected problems, got {:?}", x),
+	};
+	assert_eq!(problems("lisen: 127.0.0.1:8000"), ["config"]);
+	assert_eq!(problems("client_type: ollama_vicuna"), ["client_type"]);
+	assert_eq!(
+		problems("client_params:\n  base_url: x\n  force_tool: true"),
+		["client_params"]
+	);
+	assert_eq!(
+		problems(
+			"client_params:\n  base_url: x\ntracing:\n  sample_ratio: 2"
+		),
+		[
+			"client_type",
+			"client_params.base_url",
+			"tracing.sample_ratio"
+		]
+	);
+	assert_eq!(
+		problems(&params("api_key: a\n  api_key_env: LLM_KEY")),
+		["client_params.api_key"]
+	);
+	assert_eq!(
+		problems(
+			"quotas:\n  default:\n    max_sessions: 0\n  tiers:\n    gold:\n      rate: { per_minute: 0, burst: 5 }"
+		),
+		[
+			"quotas.tiers.gold.rate.per_minute",
+			"quotas.default.max_sessions"
+		]
+	);
+}
+
+#[tokio::test]
+async fn test_reload() {
+	use eventsource_stream::Eventsource;
+	use futures_util::StreamExt;
+
+	// what the loader reads next
+	let yaml = Arc::new(std::sync::Mutex::new(String::new()));
+	let source = yaml.clone();
+	let loader = ConfigLoader::new(move || {
+		Ok(Config::parse(&source.lock().unwrap(), [])?)
+	});
+
+	let config = Config {
+		listen: "127.0.0.1:8991".parse().unwrap(),
+		admin: Some(AdminConfig {
+			listen: "127.0.0.1:8984".parse().unwrap(),
+			token: None,
+			cors: Default::default(),
+		}),
+		..Default::default()
+	};
+	let server =
+		Server::with_loader(config, Some(loader)).await.unwrap();
+	let state = server.state.clone();
+	let handle = axum_server::Handle::new();
+	let h = handle.clone();
+	tokio::spawn(async move { server.start_with_handle(h).await });
+	tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+	let http = reqwest::Client::new();
+	let reload = async |body: &str| {
+		*yaml.lock().unwrap() =
+			format!("admin:\n  listen: 127.0.0.1:8984\n{}", body);
+		let response = http
+			.post("http://127.0.0.1:8984/admin/reload")
+			.send()
+			.await
+			.unwrap();
+		(
+			response.status().is_success(),
+			response.text().await.unwrap(),
+		)
+	};
+
+	// a session started before the reload
+	let response = http
+		.post("http://127.0.0.1:8991/prompt?query_type=repeat_prompt")
+		.header("Content-Type", "application/json")
+		.body(
+			serde_json::to_vec(&Prompt {
+				prompt: Some("hello".into()),
+				..Default::default()
+			})
+			.unwrap(),
+		)
+		.send()
 		.await
 		.unwrap();
+	let mut events = Box::pin(response.bytes_stream().eventsource());
+	let id = match serde_json::from_str(
+		&events.next().await.unwrap().unwrap().data,
+	)
+	.unwrap()
+	{
+		PromptResponse::Connection(id) => id,
+		x => panic!("expected a connection, got {:?}", x),
+	};
+
+	let (ok, body) =
+		reload("listen: 127.0.0.1:8991\ntool_timeout_secs: 5").await;
+	assert!(ok, "{}", body);
+	let report: Reload = serde_json::from_str(&body).unwrap();
+	assert_eq!(report.generation, 1);
+	assert!(report.restart_needed.is_empty());
+	assert_eq!(state.config().tool_timeout_secs, 5);
+
+	let session =
+		broker::GLOBAL_BROKER.lock().await.get_session(id).unwrap();
+	let started_with = session.lock().await.config.clone().unwrap();
+	assert_eq!(started_with.tool_timeout_secs, 60);
+
+	// a bad config changes nothing
+	let (ok, body) = reload("tool_timeout_secs: 0").await;
+	assert!(!ok);
+	assert!(body.contains("tool_timeout_secs"), "{}", body);
+	assert_eq!(state.config().tool_timeout_secs, 5);
 
-	let x = r.recv().await.unwrap().unwrap();
-	assert!(matches!(x, Event::Open));
+	let (ok, body) = reload("listen: 127.0.0.1:8990").await;
+	assert!(ok, "{}", body);
+	let report: Reload = serde_json::from_str(&body).unwrap();
+	assert_eq!(report.generation, 2);
+	assert_eq!(report.restart_needed, ["listen"]);
 
-	let x = r.recv().await.unwrap().unwrap();
-	assert!(matches!(x, Event::Message(_)));
+	drop(events);
+	shutdown_handle(handle);
+}
+
+// a self-signed certificate and its key, as PEM
+fn self_signed(name: &str) -> (String, String) {
+	let x =
+		rcgen::generate_simple_self_signed(vec![name.into()]).unwrap();
+	(x.cert.pem(), x.signing_key.serialize_pem())
+}
 
-	if let Event::Message(m) = x {
-		let obj: PromptResponse =
-			serde_json::from_str(&m.data).unwrap();
-		assert!(matches!(obj, PromptResponse::Connection(_)));
+// a TLS connection to the server on 8989 trusting only the certificate in roots
+async fn connect_tls(
+	roots: &str, client: Option<&(String, String)>, alpn: &[&[u8]],
+) -> std::io::Result<
+	tokio_rustls::client::TlsStream<tokio::net::TcpStream>,
+> {
+	use rustls::pki_types::{
+		CertificateDer, PrivateKeyDer, ServerName, pem::PemObject,
+	};
+
+	let mut store = rustls::RootCertStore::empty();
+	for x in CertificateDer::pem_slice_iter(roots.as_bytes()) {
+		store.add(x.unwrap()).unwrap();
 	}
+	let builder = rustls::ClientConfig::builder_with_provider(
+		Arc::new(rustls::crypto::ring::default_provider()),
+	)
+	.with_safe_default_protocol_versions()
+	.unwrap()
+	.with_root_certificates(store);
+	let mut config = match client {
+		Some((cert, key)) => builder
+			.with_client_auth_cert(
+				vec![
+					CertificateDer::from_pem_slice(cert.as_bytes())
+						.unwrap(),
+				],
+				PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+			)
+			.unwrap(),
+		None => builder.with_no_client_auth(),
+	};
+	config.alpn_protocols = alpn.iter().map(|x| x.to_vec()).collect();
+
+	let tcp = tokio::net::TcpStream::connect("127.0.0.1:8989").await?;
+	tokio_rustls::TlsConnector::from(Arc::new(config))
+		.connect(ServerName::try_from("localhost").unwrap(), tcp)
+		.await
+}
+
+// GET /healthz over HTTP/1.1; with TLS 1.3 a refused client certificate shows up here, after the
+// handshake
+async fn healthz_tls(
+	roots: &str, client: Option<&(String, String)>,
+) -> std::io::Result<String> {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	let mut stream = connect_tls(roots, client, &[b"http/1.1"]).await?;
+	stream
+		.write_all(
+			b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+		)
+		.await?;
+	let mut response = Vec::new();
+	stream.read_to_end(&mut response).await?;
+	Ok(String::from_utf8_lossy(&response).into())
+}
+
+#[tokio::test]
+async fn test_tls() {
+	let dir = std::env::temp_dir()
+		.join(format!("allelo-tls-{}", uuid::Uuid::new_v4()));
+	std::fs::create_dir_all(&dir).unwrap();
+	let server = self_signed("localhost");
+	let phone = self_signed("phone");
+	std::fs::write(dir.join("cert.pem"), &server.0).unwrap();
+	std::fs::write(dir.join("key.pem"), &server.1).unwrap();
+	std::fs::write(dir.join("ca.pem"), &phone.0).unwrap();
+
+	let handle = start_api_server(Config {
+		listen: "127.0.0.1:8989".parse().unwrap(),
+		tls: Some(TlsConfig {
+			cert_file: dir.join("cert.pem"),
+			key_file: dir.join("key.pem"),
+			client_ca_file: Some(dir.join("ca.pem")),
+			reload_secs: 1,
+		}),
+		..Default::default()
+	})
+	.await
+	.unwrap();
+	tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+	// HTTP/2 is preferred
+	let stream =
+		connect_tls(&server.0, Some(&phone), &[b"h2", b"http/1.1"])
+			.await
+			.unwrap();
+	assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+	drop(stream);
 
-	let mut i = 0;
+	let response = healthz_tls(&server.0, Some(&phone)).await.unwrap();
+	assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
 
-	while let Some(Ok(m)) = r.recv().await {
-		if i == 10 {
+	// no certificate, or one from somebody else
+	assert!(healthz_tls(&server.0, None).await.is_err());
+	let other = self_signed("phone");
+	assert!(healthz_tls(&server.0, Some(&other)).await.is_err());
+
+	// a renewed certificate is picked up; only it is trusted from here on
+	let renewed = self_signed("localhost");
+	std::fs::write(dir.join("cert.pem"), &renewed.0).unwrap();
+	std::fs::write(dir.join("key.pem"), &renewed.1).unwrap();
+	let mut reloaded = false;
+	for _ in 0..50 {
+		tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+		if healthz_tls(&renewed.0, Some(&phone)).await.is_ok() {
+			reloaded = true;
 			break;
 		}
+	}
+	assert!(reloaded);
 
-		match m {
-			Event::Open => {}
-			Event::Message(m) => {
-				let obj: PromptResponse =
-					serde_json::from_str(&m.data).unwrap();
-				eprintln!("{}", m.data);
-				assert!(matches!(
-					obj,
-					PromptResponse::PromptResponse(_)
-				));
-				i += 1;
-			}
+	shutdown_handle(handle);
+	std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_admin() {
+	use eventsource_stream::Eventsource;
+	use futures_util::StreamExt;
+
+	let handle = start_api_server(Config {
+		listen: "127.0.0.1:8988".parse().unwrap(),
+		admin: Some(AdminConfig {
+			listen: "127.0.0.1:8987".parse().unwrap(),
+			token: Some("let-me-in".into()),
+			cors: Default::default(),
+		}),
+		..Default::default()
+	})
+	.await
+	.unwrap();
+	tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+	let http = reqwest::Client::new();
+	let get = async |url: &str, token: Option<&str>| {
+		let mut request = http.get(url);
+		if let Some(token) = token {
+			request = request.bearer_auth(token);
 		}
+		request.send().await.unwrap()
+	};
+
+	// the operators' routes are only on the admin listener, and want the token
+	let response = get("http://127.0.0.1:8988/status", None).await;
+	assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+	let response = get("http://127.0.0.1:8988/healthz", None).await;
+	assert!(response.status().is_success());
+	let response = get("http://127.0.0.1:8987/healthz", None).await;
+	assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+	for token in [None, Some("let-me-out")] {
+		let response = get("http://127.0.0.1:8987/status", token).await;
+		assert_eq!(
+			response.status(),
+			reqwest::StatusCode::UNAUTHORIZED
+		);
+		assert_eq!(response.headers()["www-authenticate"], "Bearer");
 	}
+	let response =
+		get("http://127.0.0.1:8987/status", Some("let-me-in")).await;
+	assert!(response.status().is_success());
+
+	// a session, listed and closed by an operator
+	let response = http
+		.post("http://127.0.0.1:8988/prompt?query_type=repeat_prompt")
+		.header("Content-Type", "application/json")
+		.body(
+			serde_json::to_vec(&Prompt {
+				prompt: Some("hello".into()),
+				..Default::default()
+			})
+			.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+	let mut events = Box::pin(response.bytes_stream().eventsource());
+	let id = match serde_json::from_str(
+		&events.next().await.unwrap().unwrap().data,
+	)
+	.unwrap()
+	{
+		PromptResponse::Connection(id) => id,
+		x => panic!("expected a connection, got {:?}", x),
+	};
+
+	let sessions: Vec<SessionInfo> = serde_json::from_str(
+		&get("http://127.0.0.1:8987/admin/sessions", Some("let-me-in"))
+			.await
+			.text()
+			.await
+			.unwrap(),
+	)
+	.unwrap();
+	assert!(sessions.iter().any(|x| x.id == id));
+
+	let close = async || {
+		http.delete(format!(
+			"http://127.0.0.1:8987/admin/sessions/{}",
+			id
+		))
+		.bearer_auth("let-me-in")
+		.send()
+		.await
+		.unwrap()
+	};
+	assert!(close().await.status().is_success());
+	assert!(!close().await.status().is_success());
+
+	// the session's stream ends
+	tokio::time::timeout(std::time::Duration::from_secs(5), async {
+		while events.next().await.is_some() {}
+	})
+	.await
+	.unwrap();
+
+	// and both listeners stop together
+	shutdown_handle(handle);
+	tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+	for port in [8988, 8987] {
+		assert!(
+			tokio::net::TcpStream::connect(("127.0.0.1", port))
+				.await
+				.is_err()
+		);
+	}
+}
+
+#[tokio::test]
+async fn test_limits() {
+	use super::super::client::{Client, Problem};
+
+	let handle = start_api_server(Config {
+		listen: "127.0.0.1:8986".parse().unwrap(),
+		max_tool_list_bytes: 1024,
+		cors: CorsConfig {
+			allow_origins: vec!["https://app.example.com".into()],
+			..Default::default()
+		},
+		limits: LimitsConfig {
+			prompt_body_bytes: 2048,
+			tool_response_body_bytes: 1024,
+			max_stream_secs: 1,
+			..Default::default()
+		},
+		..Default::default()
+	})
+	.await
+	.unwrap();
+	tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+	// bodies over the limit of their route
+	let http = reqwest::Client::new();
+	let body = |size: usize| {
+		serde_json::to_vec(&Prompt {
+			prompt: Some("x".repeat(size)),
+			..Default::default()
+		})
+		.unwrap()
+	};
+	for (route, size) in [("prompt", 4096), ("mcp_response", 2048)] {
+		let response = http
+			.post(format!("http://127.0.0.1:8986/{}", route))
+			.header("Content-Type", "application/json")
+			.body(body(size))
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(
+			response.status(),
+			reqwest::StatusCode::PAYLOAD_TOO_LARGE
+		);
+		let problem: Problem =
+			serde_json::from_str(&response.text().await.unwrap())
+				.unwrap();
+		assert_eq!(problem.status, Some(413));
+	}
+
+	// only the allowed origin
+	for (origin, allowed) in [
+		("https://app.example.com", true),
+		("https://elsewhere.example.com", false),
+	] {
+		let response = http
+			.request(
+				http::Method::OPTIONS,
+				"http://127.0.0.1:8986/prompt",
+			)
+			.header("Origin", origin)
+			.header("Access-Control-Request-Method", "POST")
+			.send()
+			.await
+			.unwrap();
+		assert_eq!(
+			response
+				.headers()
+				.get("access-control-allow-origin")
+				.is_some(),
+			allowed
+		);
+	}
+
+	// a stream ends at max_stream_secs, and the client says why before resuming it
+	let client = Client::new_testing(
+		"http://127.0.0.1:8986".parse().unwrap(),
+		QueryType::RepeatPrompt,
+	)
+	.await
+	.unwrap();
+	// without the client's own tools, which are over this test's limits
+	let mut r = client
+		.prompt(Prompt {
+			prompt: Some("hello".into()),
+			tools: Some(Vec::new()),
+			..Default::default()
+		})
+		.unwrap();
+	let error = tokio::time::timeout(
+		std::time::Duration::from_secs(5),
+		async {
+			loop {
+				if let PromptEvent::Disconnected { error, .. } =
+					r.next().await.unwrap().unwrap()
+				{
+					return error;
+				}
+			}
+		},
+	)
+	.await
+	.unwrap();
+	assert!(error.contains("503"), "{}", error);
+	assert!(error.contains("after 1 seconds"), "{}", error);
+
+	drop(r);
+	shutdown_handle(handle);
+}
+
+#[tokio::test]
+async fn test_quotas() {
+	use super::super::client::Problem;
+	use eventsource_stream::Eventsource;
+	use futures_util::StreamExt;
+
+	let handle = start_api_server(Config {
+		listen: "127.0.0.1:8985".parse().unwrap(),
+		quotas: serde_yaml_ng::from_str(
+			r#"
+principal_header: x-principal
+tier_header: x-tier
+default:
+  rate: { per_minute: 6, burst: 2 }
+  max_sessions: 1
+  max_generations: 1
+tiers:
+  gold:
+    rate: { per_minute: 600, burst: 100 }
+    max_generations: 2
+"#,
+		)
+		.unwrap(),
+		..Default::default()
+	})
+	.await
+	.unwrap();
+	tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+	// principals of their own, since sessions are counted over the whole process
+	let principal = || format!("tester-{}", uuid::Uuid::new_v4());
+	let http = reqwest::Client::new();
+	let prompt =
+		async |principal: &str, tier: &str, id: Option<uuid::Uuid>| {
+			http.post(
+				"http://127.0.0.1:8985/prompt?query_type=repeat_prompt",
+			)
+			.header("Content-Type", "application/json")
+			.header("x-principal", principal)
+			.header("x-tier", tier)
+			.body(
+				serde_json::to_vec(&Prompt {
+					connection_id: id,
+					prompt: Some("hello".into()),
+					..Default::default()
+				})
+				.unwrap(),
+			)
+			.send()
+			.await
+			.unwrap()
+		};
+	let refused = async |response: reqwest::Response, retry: &str| {
+		assert_eq!(
+			response.status(),
+			reqwest::StatusCode::TOO_MANY_REQUESTS
+		);
+		assert_eq!(response.headers()["retry-after"], retry);
+		let problem: Problem =
+			serde_json::from_str(&response.text().await.unwrap())
+				.unwrap();
+		assert_eq!(problem.status, Some(429));
+		problem.detail.unwrap_or_default()
+	};
+
+	// the bucket holds burst, then refills at per_minute
+	let p = principal();
+	for _ in 0..2 {
+		let response = http
+			.put("http://127.0.0.1:8985/input")
+			.header("x-principal", &p)
+			.send()
+			.await
+			.unwrap();
+		assert_ne!(
+			response.status(),
+			reqwest::StatusCode::TOO_MANY_REQUESTS
+		);
+	}
+	let response = http
+		.put("http://127.0.0.1:8985/input")
+		.header("x-principal", &p)
+		.send()
+		.await
+		.unwrap();
+	refused(response, "10").await;
+	// the probes aren't counted
+	let response = http
+		.get("http://127.0.0.1:8985/healthz")
+		.header("x-principal", &p)
+		.send()
+		.await
+		.unwrap();
+	assert!(response.status().is_success());
+
+	// one prompt answered at a time
+	let p = principal();
+	let first = prompt(&p, "", None).await;
+	assert!(first.status().is_success());
+	let detail = refused(prompt(&p, "", None).await, "5").await;
+	assert!(detail.contains("prompts"), "{}", detail);
+
+	// gold has two, but still one session
+	let p = principal();
+	let first = prompt(&p, "gold", None).await;
+	assert!(first.status().is_success());
+	let detail = refused(prompt(&p, "gold", None).await, "60").await;
+	assert!(detail.contains("sessions"), "{}", detail);
+
+	// another prompt on the session it has is fine
+	let mut events = Box::pin(first.bytes_stream().eventsource());
+	let id = match serde_json::from_str(
+		&events.next().await.unwrap().unwrap().data,
+	)
+	.unwrap()
+	{
+		PromptResponse::Connection(id) => id,
+		x => panic!("expected a connection, got {:?}", x),
+	};
+	assert!(prompt(&p, "gold", Some(id)).await.status().is_success());
+
+	// but nobody else's
+	let other = principal();
+	assert_eq!(
+		prompt(&other, "gold", Some(id)).await.status(),
+		reqwest::StatusCode::NOT_FOUND
+	);
+	let response = http
+		.put("http://127.0.0.1:8985/input")
+		.header("Content-Type", "application/json")
+		.header("x-principal", &other)
+		.body(
+			serde_json::to_vec(&Input {
+				connection_id: id,
+				input: "and then?".into(),
+				urgent: false,
+			})
+			.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+	// nor answer what the server asked the owner's phone
+	let response = http
+		.post("http://127.0.0.1:8985/mcp_response")
+		.header("Content-Type", "application/json")
+		.header("x-principal", &other)
+		.body(
+			serde_json::to_vec(&McpResponse {
+				connection_id: id.to_string(),
+				response: r#"{"jsonrpc":"2.0","id":"1","result":{}}"#
+					.into(),
+				..Default::default()
+			})
+			.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+	let response = http
+		.post("http://127.0.0.1:8985/confirm")
+		.header("Content-Type", "application/json")
+		.header("x-principal", &other)
+		.body(
+			serde_json::to_vec(&Confirmation {
+				connection_id: id,
+				id: uuid::Uuid::new_v4(),
+				approved: true,
+			})
+			.unwrap(),
+		)
+		.send()
+		.await
+		.unwrap();
+	assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
 
-	assert_eq!(i, 10);
 	shutdown_handle(handle);
 }
@@ -0,0 +1,205 @@
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+	RootCertStore, ServerConfig,
+	crypto::ring,
+	pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+	server::WebPkiClientVerifier,
+};
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+// NOTE: with tls set, the listener speaks HTTPS only, offering HTTP/2 and HTTP/1.1 over ALPN. With
+// client_ca_file set too, a client has to present a certificate issued by one of those CAs before
+// it gets to send a request. The files are checked every reload_secs and read again when they
+// change, so a renewed certificate is used for new connections without a restart. Files that
+// don't make a working config, e.g. a certificate renewed before its key, are logged and the
+// certificate in use is kept until they change again.
+
+const DEFAULT_RELOAD_SECS: u64 = 10;
+
+fn default_reload_secs() -> u64 {
+	DEFAULT_RELOAD_SECS
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+	// PEM, the certificate followed by the rest of its chain
+	pub cert_file: PathBuf,
+	// PEM, PKCS#8, PKCS#1 or SEC1
+	pub key_file: PathBuf,
+	// PEM, the CAs client certificates have to be issued by
+	#[serde(default)]
+	pub client_ca_file: Option<PathBuf>,
+	// how often the files are checked for changes
+	#[serde(default = "default_reload_secs")]
+	pub reload_secs: u64,
+}
+
+// what the files held when they were last read, to tell when they change
+#[derive(Debug, Clone, PartialEq)]
+struct Files {
+	cert: Vec<u8>,
+	key: Vec<u8>,
+	client_ca: Option<Vec<u8>>,
+}
+
+impl Files {
+	fn read(config: &TlsConfig) -> anyhow::Result<Self> {
+		let read = |x: &PathBuf| {
+			std::fs::read(x).with_context(|| {
+				format!("could not read {}", x.display())
+			})
+		};
+
+		Ok(Self {
+			cert: read(&config.cert_file)?,
+			key: read(&config.key_file)?,
+			client_ca: config
+				.client_ca_file
+				.as_ref()
+				.map(read)
+				.transpose()?,
+		})
+	}
+
+	fn server_config(&self) -> anyhow::Result<ServerConfig> {
+		let provider = Arc::new(ring::default_provider());
+		let certs = CertificateDer::pem_slice_iter(&self.cert)
+			.collect::<Result<Vec<_>, _>>()
+			.context("bad certificate")?;
+		let key = PrivateKeyDer::from_pem_slice(&self.key)
+			.context("bad private key")?;
+
+		let builder =
+			ServerConfig::builder_with_provider(provider.clone())
+				.with_safe_default_protocol_versions()?;
+		let builder = match &self.client_ca {
+			Some(pem) => {
+				let mut roots = RootCertStore::empty();
+				for cert in CertificateDer::pem_slice_iter(pem) {
+					roots.add(
+						cert.context("bad client CA certificate")?,
+					)?;
+				}
+				builder.with_client_cert_verifier(
+					WebPkiClientVerifier::builder_with_provider(
+						Arc::new(roots),
+						provider,
+					)
+					.build()?,
+				)
+			}
+			None => builder.with_no_client_auth(),
+		};
+
+		let mut config = builder
+			.with_single_cert(certs, key)
+			.context("could not use the certificate and key")?;
+		config.alpn_protocols =
+			vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+		Ok(config)
+	}
+}
+
+// whether the files make a working config, for validating one
+pub fn check(config: &TlsConfig) -> anyhow::Result<()> {
+	Files::read(config)?.server_config()?;
+	Ok(())
+}
+
+// the listener's config, kept up to date with the files for as long as the process runs
+pub(crate) fn rustls_config(
+	config: &TlsConfig,
+) -> anyhow::Result<RustlsConfig> {
+	let files = Files::read(config)?;
+	let rustls =
+		RustlsConfig::from_config(Arc::new(files.server_config()?));
+	tokio::spawn(watch(config.clone(), files, rustls.clone()));
+	Ok(rustls)
+}
+
+async fn watch(
+	config: TlsConfig, mut files: Files, rustls: RustlsConfig,
+) {
+	let mut interval =
+		tokio::time::interval(Duration::from_secs(config.reload_secs));
+	interval.tick().await;
+
+	loop {
+		interval.tick().await;
+		let new = match Files::read(&config) {
+			Ok(x) if x != files => x,
+			Ok(_) => continue,
+			Err(e) => {
+				tracing::warn!(
+					"TLS certificates not reloaded: {:#}",
+					e
+				);
+				continue;
+			}
+		};
+
+		match new.server_config() {
+			Ok(x) => {
+				rustls.reload_from_config(Arc::new(x));
+				tracing::info!("TLS certificates reloaded");
+			}
+			Err(e) => {
+				tracing::warn!("TLS certificates not reloaded: {:#}", e)
+			}
+		}
+		files = new;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_check() {
+		let dir = std::env::temp_dir()
+			.join(format!("allelo-tls-{}", uuid::Uuid::new_v4()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let config = TlsConfig {
+			cert_file: dir.join("cert.pem"),
+			key_file: dir.join("key.pem"),
+			client_ca_file: None,
+			reload_secs: DEFAULT_RELOAD_SECS,
+		};
+		let generate = || {
+			rcgen::generate_simple_self_signed(vec!["localhost".into()])
+				.unwrap()
+		};
+
+		let x = generate();
+		std::fs::write(&config.cert_file, x.cert.pem()).unwrap();
+		std::fs::write(&config.key_file, x.signing_key.serialize_pem())
+			.unwrap();
+		check(&config).unwrap();
+
+		// another certificate's key
+		std::fs::write(
+			&config.key_file,
+			generate().signing_key.serialize_pem(),
+		)
+		.unwrap();
+		assert!(check(&config).is_err());
+
+		std::fs::write(&config.key_file, "not a key").unwrap();
+		let e = check(&config).unwrap_err();
+		assert!(
+			format!("{:#}", e).contains("bad private key"),
+			"{:#}",
+			e
+		);
+
+		std::fs::remove_file(&config.key_file).unwrap();
+		let e = check(&config).unwrap_err();
+		assert!(format!("{:#}", e).contains("key.pem"), "{:#}", e);
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -1,21 +1,153 @@
-use allelo_mcp::api::server::{Config, Server};
+use allelo_mcp::api::{
+	llm::{LLMClientParams, LLMClientType},
+	server::{Config, ConfigLoader, DEFAULT_CONFIG, Server},
+};
 
 use anyhow::Result;
+use clap::{Parser, Subcommand};
+use serde::{
+	Deserialize,
+	de::{IntoDeserializer, value::StrDeserializer},
+};
+use std::{net::SocketAddr, path::PathBuf};
+
+// NOTE: every flag can be given in the environment variable next to it instead, e.g. in a
+// container; every setting of the config file can be, too, see Config. Flags win over the
+// environment, and both win over the config file.
+
+#[derive(Debug, Parser)]
+#[command(
+	version,
+	about = "Answers prompts from the phone with an LLM, and sends the model's tool calls to the phone"
+)]
+struct Cli {
+	#[command(subcommand)]
+	command: Option<Command>,
+	#[arg(
+		short,
+		long,
+		global = true,
+		env = "ALLELO_CONFIG",
+		help = "Config file; the defaults without one, see print-default-config"
+	)]
+	config: Option<PathBuf>,
+	#[arg(
+		long,
+		global = true,
+		help = "Address to listen on; also ALLELO_LISTEN"
+	)]
+	listen: Option<SocketAddr>,
+	#[arg(
+		long,
+		global = true,
+		help = "One of: error, warn, info, debug, trace; also ALLELO_LOG_LEVEL"
+	)]
+	log_level: Option<tracing::Level>,
+	#[arg(
+		long,
+		global = true,
+		env = "ALLELO_MODEL",
+		value_parser = client_type,
+		help = "Client type, e.g. ollama_qwen3"
+	)]
+	model: Option<LLMClientType>,
+	#[arg(
+		long,
+		global = true,
+		env = "ALLELO_BASE_URL",
+		help = "URL of the LLM backend"
+	)]
+	base_url: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+	#[command(about = "Run the server; the default")]
+	Serve,
+	#[command(
+		about = "Check the config, and print it with flags and environment applied"
+	)]
+	CheckConfig,
+	#[command(about = "Print the default config, with comments")]
+	PrintDefaultConfig,
+}
+
+// client types are named like in the config file
+fn client_type(name: &str) -> Result<LLMClientType, String> {
+	let name: StrDeserializer<serde::de::value::Error> =
+		name.into_deserializer();
+	LLMClientType::deserialize(name).map_err(|e| e.to_string())
+}
+
+impl Cli {
+	// the config file, or the defaults, with the flags applied
+	fn config(&self) -> Result<Config> {
+		let mut config = match &self.config {
+			Some(x) => Config::load(x)?,
+			None => Config::from_env()?,
+		};
+
+		if let Some(x) = self.listen {
+			config.listen = x;
+		}
+		if let Some(x) = self.log_level {
+			config.log_level = x.into();
+		}
+		if let Some(x) = &self.model {
+			config.client_type = Some(x.clone());
+		}
+		if let Some(x) = &self.base_url {
+			match &mut config.client_params {
+				Some(params) => params.base_url = x.clone(),
+				None => {
+					config.client_params = Some(LLMClientParams {
+						base_url: x.clone(),
+						api_key: None,
+						timeout: None,
+						force_tools: false,
+					})
+				}
+			}
+		}
+		Ok(config)
+	}
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-	// FIXME: replace this with clap later
-	let config = if std::env::args().len() < 2 {
-		Config::default()
-	} else {
-		Config::from_file(
-			std::env::args()
-				.skip(1)
-				.next()
-				.expect("Could not parse configuration file")
-				.into(),
-		)?
-	};
-
-	Server::new(config).await?.start().await
+	let mut cli = Cli::parse();
+
+	match cli.command.take() {
+		None | Some(Command::Serve) => {
+			let config = cli.config()?;
+			config.init_logging()?;
+			tracing::info!("Configuration parsed successfully.");
+
+			// SIGHUP reads the file and the environment again, and applies the same flags
+			let loader = ConfigLoader::new(move || cli.config());
+			Server::with_loader(config, Some(loader))
+				.await?
+				.start()
+				.await
+		}
+		Some(Command::CheckConfig) => {
+			let mut config = cli.config()?;
+			if let Some(params) = &mut config.client_params
+				&& params.api_key.is_some()
+			{
+				params.api_key = Some("[hidden]".into());
+			}
+			if let Some(admin) = &mut config.admin
+				&& admin.token.is_some()
+			{
+				admin.token = Some("[hidden]".into());
+			}
+			print!("{}", serde_yaml_ng::to_string(&config)?);
+			Ok(())
+		}
+		Some(Command::PrintDefaultConfig) => {
+			print!("{}", DEFAULT_CONFIG);
+			Ok(())
+		}
+	}
 }
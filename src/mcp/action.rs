@@ -0,0 +1,36 @@
+use rmcp::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+// NOTE: arguments of the tools that change something on the user's behalf. The proxy asks the
+// user to approve the exact arguments before it ever forwards one of these calls, see
+// crate::mcp::tool::action_list.
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct SendMessage {
+	#[schemars(
+		description = "The full name or nickname of the contact or friend, or the name of the group, to send the message to"
+	)]
+	pub name: String,
+	#[schemars(description = "The text of the message")]
+	pub body: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ReactToMessage {
+	#[schemars(
+		description = "The id of the message, as returned by chat_messages or group_chat"
+	)]
+	pub message_id: String,
+	#[schemars(description = "The reaction, usually a single emoji")]
+	pub reaction: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct CreateGroup {
+	#[schemars(description = "The name of the new group")]
+	pub name: String,
+	#[schemars(
+		description = "The full names or nicknames of the contacts or friends to add to the group"
+	)]
+	pub members: Vec<String>,
+}
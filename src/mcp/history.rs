@@ -0,0 +1,263 @@
+use super::store::ChatMessage;
+use chrono::{DateTime, Utc};
+use rmcp::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+pub(crate) const DEFAULT_PAGE_SIZE: usize = 20;
+pub(crate) const MAX_PAGE_SIZE: usize = 100;
+
+// NOTE: histories are always walked newest first. The cursor is the position of the last message
+// handed out, so new messages arriving between calls don't shift the pages the model is reading.
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct HistoryWindow {
+	#[schemars(
+		description = "The next_cursor value of a previous call, to continue paging into older messages"
+	)]
+	#[serde(default)]
+	pub cursor: Option<String>,
+	#[schemars(
+		description = "Only messages sent at or after this RFC 3339 timestamp"
+	)]
+	#[serde(default)]
+	pub since: Option<String>,
+	#[schemars(
+		description = "Only messages sent before this RFC 3339 timestamp"
+	)]
+	#[serde(default)]
+	pub until: Option<String>,
+	#[schemars(
+		description = "The maximum number of messages to return, 20 by default and at most 100"
+	)]
+	#[serde(default)]
+	pub limit: Option<usize>,
+	#[schemars(
+		description = "Only messages containing at least one of these keywords"
+	)]
+	#[serde(default)]
+	pub keywords: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ChatHistoryQuery {
+	#[schemars(
+		description = "The name of the contact or friend, a nickname or part of a name"
+	)]
+	pub name: String,
+	#[serde(flatten)]
+	pub window: HistoryWindow,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct GroupHistoryQuery {
+	#[schemars(description = "The name of the group")]
+	pub name: String,
+	#[serde(flatten)]
+	pub window: HistoryWindow,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Page<T> {
+	pub items: Vec<T>,
+	pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum HistoryError {
+	#[error("invalid timestamp '{0}', expected RFC 3339")]
+	InvalidTimestamp(String),
+	#[error("invalid cursor '{0}'")]
+	InvalidCursor(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Cursor {
+	sent_at: i64,
+	id: String,
+}
+
+impl Cursor {
+	fn parse(s: &str) -> Result<Self, HistoryError> {
+		let (sent_at, id) = s
+			.split_once(':')
+			.ok_or_else(|| HistoryError::InvalidCursor(s.into()))?;
+
+		Ok(Self {
+			sent_at: sent_at
+				.parse()
+				.map_err(|_| HistoryError::InvalidCursor(s.into()))?,
+			id: id.into(),
+		})
+	}
+
+	// true if the message comes after the cursor when walking newest first
+	fn precedes(&self, msg: &ChatMessage) -> bool {
+		(msg.sent_at.timestamp_millis(), msg.id.as_str())
+			< (self.sent_at, self.id.as_str())
+	}
+}
+
+impl From<&ChatMessage> for Cursor {
+	fn from(value: &ChatMessage) -> Self {
+		Self {
+			sent_at: value.sent_at.timestamp_millis(),
+			id: value.id.clone(),
+		}
+	}
+}
+
+impl std::fmt::Display for Cursor {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}:{}", self.sent_at, self.id)
+	}
+}
+
+fn parse_timestamp(
+	s: &Option<String>,
+) -> Result<Option<DateTime<Utc>>, HistoryError> {
+	s.as_ref()
+		.map(|s| {
+			DateTime::parse_from_rfc3339(s)
+				.map(|x| x.with_timezone(&Utc))
+				.map_err(|_| HistoryError::InvalidTimestamp(s.clone()))
+		})
+		.transpose()
+}
+
+impl HistoryWindow {
+	pub fn limit(&self) -> usize {
+		self.limit
+			.unwrap_or(DEFAULT_PAGE_SIZE)
+			.clamp(1, MAX_PAGE_SIZE)
+	}
+
+	pub fn apply(
+		&self, mut messages: Vec<ChatMessage>,
+	) -> Result<Page<ChatMessage>, HistoryError> {
+		let since = parse_timestamp(&self.since)?;
+		let until = parse_timestamp(&self.until)?;
+		let cursor =
+			self.cursor.as_deref().map(Cursor::parse).transpose()?;
+		let keywords: Vec<String> =
+			self.keywords.iter().map(|x| x.to_lowercase()).collect();
+		let limit = self.limit();
+
+		messages.sort_by(|a, b| {
+			(b.sent_at.timestamp_millis(), &b.id)
+				.cmp(&(a.sent_at.timestamp_millis(), &a.id))
+		});
+
+		let mut items: Vec<ChatMessage> = messages
+			.into_iter()
+			.filter(|x| cursor.as_ref().is_none_or(|c| c.precedes(x)))
+			.filter(|x| since.is_none_or(|since| x.sent_at >= since))
+			.filter(|x| until.is_none_or(|until| x.sent_at < until))
+			.filter(|x| {
+				let body = x.body.to_lowercase();
+				keywords.is_empty()
+					|| keywords.iter().any(|k| body.contains(k))
+			})
+			.take(limit + 1)
+			.collect();
+
+		let next_cursor = if items.len() > limit {
+			items.truncate(limit);
+			items.last().map(|x| Cursor::from(x).to_string())
+		} else {
+			None
+		};
+
+		Ok(Page { items, next_cursor })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+
+	fn messages(count: i64) -> Vec<ChatMessage> {
+		(0..count)
+			.map(|x| ChatMessage {
+				id: format!("m{}", x),
+				sender: "me".into(),
+				body: if x % 2 == 0 {
+					format!("lunch at noon #{}", x)
+				} else {
+					format!("see you later #{}", x)
+				},
+				sent_at: Utc
+					.timestamp_opt(1_700_000_000 + x * 60, 0)
+					.unwrap(),
+				reactions: Vec::new(),
+			})
+			.collect()
+	}
+
+	#[test]
+	fn test_history_pagination() {
+		let all = messages(45);
+		let mut window = HistoryWindow {
+			limit: Some(20),
+			..Default::default()
+		};
+
+		let mut seen = Vec::new();
+		loop {
+			let page = window.apply(all.clone()).unwrap();
+			assert!(page.items.len() <= 20);
+			seen.extend(page.items.iter().map(|x| x.id.clone()));
+			match page.next_cursor {
+				Some(cursor) => window.cursor = Some(cursor),
+				None => break,
+			}
+		}
+
+		let expected: Vec<String> =
+			(0..45).rev().map(|x| format!("m{}", x)).collect();
+		assert_eq!(seen, expected);
+	}
+
+	#[test]
+	fn test_history_filters() {
+		let all = messages(10);
+
+		let page = HistoryWindow {
+			keywords: vec!["LUNCH".into()],
+			..Default::default()
+		}
+		.apply(all.clone())
+		.unwrap();
+		assert_eq!(page.items.len(), 5);
+		assert!(page.next_cursor.is_none());
+
+		let page = HistoryWindow {
+			since: Some("2023-11-14T22:15:00Z".into()),
+			until: Some("2023-11-14T22:18:00Z".into()),
+			..Default::default()
+		}
+		.apply(all.clone())
+		.unwrap();
+		let ids: Vec<&str> =
+			page.items.iter().map(|x| x.id.as_str()).collect();
+		assert_eq!(ids, vec!["m4", "m3", "m2"]);
+
+		assert_eq!(
+			HistoryWindow {
+				since: Some("yesterday".into()),
+				..Default::default()
+			}
+			.apply(all.clone()),
+			Err(HistoryError::InvalidTimestamp("yesterday".into()))
+		);
+
+		assert_eq!(
+			HistoryWindow {
+				cursor: Some("garbage".into()),
+				..Default::default()
+			}
+			.apply(all),
+			Err(HistoryError::InvalidCursor("garbage".into()))
+		);
+	}
+}
@@ -1,4 +1,10 @@
+pub mod action;
+pub mod history;
+pub mod policy;
+pub mod resolve;
+pub mod search;
 pub mod service;
+pub mod store;
 #[cfg(test)]
 pub(crate) mod test_service;
 pub mod tool;
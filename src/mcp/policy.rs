@@ -0,0 +1,246 @@
+use super::resolve::tokenize;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// NOTE: the same policy is applied twice: by the phone's Service before a tool runs, and by the
+// proxy before a tool call is even sent to the phone. Either side can be the one configured;
+// whichever says no wins. The most specific rule applies: a rule naming the tool beats a "*" rule,
+// and a rule with a target beats one without.
+
+#[derive(
+	Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Default,
+)]
+pub enum Consent {
+	#[default]
+	#[serde(rename = "allow")]
+	Allow,
+	#[serde(rename = "ask_once")]
+	AskOnce,
+	#[serde(rename = "ask_always")]
+	AskAlways,
+	#[serde(rename = "deny")]
+	Deny,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PolicyRule {
+	// tool name, or "*" for every tool
+	pub tool: String,
+	// contact or group the rule is limited to, matched against the name the tool is called with
+	#[serde(default)]
+	pub target: Option<String>,
+	pub consent: Consent,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Policy {
+	#[serde(default)]
+	pub default: Consent,
+	#[serde(default)]
+	pub rules: Vec<PolicyRule>,
+}
+
+// one decision about letting a tool call through, and so its result go to the LLM
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PolicyDecision {
+	pub tool: String,
+	pub target: Option<String>,
+	pub consent: Consent,
+	pub allowed: bool,
+	pub decided_at: DateTime<Utc>,
+}
+
+// the contact or group a call is about, if the tool takes one
+pub fn target(arguments: &serde_json::Value) -> Option<String> {
+	arguments
+		.get("name")
+		.and_then(|x| x.as_str())
+		.map(|x| x.to_string())
+}
+
+impl Policy {
+	// aliases are the names the target of the call is known by; the name the tool was called with
+	// and, when the caller can resolve it, the contact's full name and nicknames.
+	pub fn consent(&self, tool: &str, aliases: &[String]) -> Consent {
+		let aliases: Vec<Vec<String>> =
+			aliases.iter().map(|x| tokenize(x)).collect();
+
+		let mut best: Option<(u8, Consent)> = None;
+
+		for rule in &self.rules {
+			let mut rank = if rule.tool == tool {
+				2
+			} else if rule.tool == "*" {
+				0
+			} else {
+				continue;
+			};
+
+			if let Some(target) = &rule.target {
+				if !aliases.contains(&tokenize(target)) {
+					continue;
+				}
+				rank += 1;
+			}
+
+			if best.is_none_or(|(x, _)| rank > x) {
+				best = Some((rank, rule.consent));
+			}
+		}
+
+		best.map(|(_, x)| x).unwrap_or(self.default)
+	}
+}
+
+// per-session record of decisions; also remembers what was granted under ask_once
+#[derive(Debug, Clone, Default)]
+pub struct ConsentLog {
+	granted: HashSet<(String, Option<String>)>,
+	decisions: Vec<PolicyDecision>,
+}
+
+impl ConsentLog {
+	// Some(allowed) if the decision doesn't need the user, None if they have to be asked
+	pub fn decided(
+		&self, consent: Consent, tool: &str, target: Option<&str>,
+	) -> Option<bool> {
+		match consent {
+			Consent::Allow => Some(true),
+			Consent::Deny => Some(false),
+			Consent::AskOnce
+				if self.granted.contains(&(
+					tool.to_string(),
+					target.map(|x| x.to_string()),
+				)) =>
+			{
+				Some(true)
+			}
+			Consent::AskOnce | Consent::AskAlways => None,
+		}
+	}
+
+	pub fn record(&mut self, decision: PolicyDecision) {
+		if decision.allowed && decision.consent == Consent::AskOnce {
+			self.granted.insert((
+				decision.tool.clone(),
+				decision.target.clone(),
+			));
+		}
+
+		self.decisions.push(decision);
+	}
+
+	pub fn decisions(&self) -> &[PolicyDecision] {
+		&self.decisions
+	}
+}
+
+// asks the user whether a tool call may go through, for ask_once and ask_always rules
+#[async_trait::async_trait]
+pub trait ConsentHandler: std::fmt::Debug + Send + Sync {
+	async fn ask(
+		&self, tool: &str, target: Option<&str>,
+		arguments: &serde_json::Value,
+	) -> bool;
+}
+
+// for hosts that have no way to ask: anything that needs asking is denied
+#[derive(Debug, Clone, Default)]
+pub struct DenyConsent;
+
+#[async_trait::async_trait]
+impl ConsentHandler for DenyConsent {
+	async fn ask(
+		&self, _tool: &str, _target: Option<&str>,
+		_arguments: &serde_json::Value,
+	) -> bool {
+		false
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn policy() -> Policy {
+		serde_yaml_ng::from_str(
+			r#"
+default: allow
+rules:
+  - tool: "*"
+    target: Mom
+    consent: ask_always
+  - tool: chat_messages
+    consent: ask_once
+  - tool: chat_messages
+    target: Jonathan Smith
+    consent: deny
+  - tool: contact_status
+    consent: deny
+"#,
+		)
+		.unwrap()
+	}
+
+	fn aliases(names: &[&str]) -> Vec<String> {
+		names.iter().map(|x| x.to_string()).collect()
+	}
+
+	#[test]
+	fn test_policy_consent() {
+		let policy = policy();
+		assert_eq!(policy.consent("all_contacts", &[]), Consent::Allow);
+		assert_eq!(
+			policy.consent("contact_info", &aliases(&["mom"])),
+			Consent::AskAlways
+		);
+		assert_eq!(
+			policy.consent("chat_messages", &aliases(&["sam"])),
+			Consent::AskOnce
+		);
+		assert_eq!(
+			policy.consent(
+				"chat_messages",
+				&aliases(&["jon", "Jonathan Smith"])
+			),
+			Consent::Deny
+		);
+		assert_eq!(
+			policy.consent("contact_status", &aliases(&["mom"])),
+			Consent::Deny
+		);
+	}
+
+	#[test]
+	fn test_consent_log() {
+		let mut log = ConsentLog::default();
+		assert_eq!(log.decided(Consent::Allow, "x", None), Some(true));
+		assert_eq!(log.decided(Consent::Deny, "x", None), Some(false));
+		assert_eq!(
+			log.decided(Consent::AskOnce, "x", Some("sam")),
+			None
+		);
+
+		log.record(PolicyDecision {
+			tool: "x".into(),
+			target: Some("sam".into()),
+			consent: Consent::AskOnce,
+			allowed: true,
+			decided_at: Utc::now(),
+		});
+		assert_eq!(
+			log.decided(Consent::AskOnce, "x", Some("sam")),
+			Some(true)
+		);
+		assert_eq!(
+			log.decided(Consent::AskOnce, "x", Some("jon")),
+			None
+		);
+		assert_eq!(
+			log.decided(Consent::AskAlways, "x", Some("sam")),
+			None
+		);
+		assert_eq!(log.decisions().len(), 1);
+	}
+}
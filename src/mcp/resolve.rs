@@ -0,0 +1,388 @@
+use super::store::Contact;
+use rmcp::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+// NOTE: the model passes whatever the user said: "mom", "Jon", "jonathan s", "Zoë". Names are
+// folded to lowercase ASCII tokens, and each query token has to match a token of the contact's
+// name or one of its nicknames, either exactly, through a common diminutive, as a prefix (for
+// initials) or within a small edit distance. When several contacts score the same the model gets
+// a Disambiguation back and is expected to ask the user. Actions are approved by the user with
+// the name the model gave, so they only go to a contact called exactly that, see resolve_exact.
+
+// scores closer than this are considered a tie
+const AMBIGUITY_MARGIN: f32 = 0.01;
+// maximum number of candidates offered when a name is ambiguous
+const MAX_CANDIDATES: usize = 5;
+
+const EXACT_SCORE: f32 = 1.0;
+const DIMINUTIVE_SCORE: f32 = 0.9;
+const PREFIX_SCORE: f32 = 0.8;
+const EDIT_SCORE: f32 = 0.7;
+// bonus for matching all of a name instead of just part of it
+const COVERAGE_BONUS: f32 = 0.05;
+
+const DIMINUTIVES: &[&[&str]] = &[
+	&["jon", "jonathan", "john", "johnny", "jack"],
+	&["bob", "bobby", "rob", "robbie", "robert", "bert"],
+	&["bill", "billy", "will", "willy", "william", "liam"],
+	&["liz", "lizzie", "beth", "betty", "eliza", "elizabeth"],
+	&["mike", "mikey", "mick", "michael"],
+	&["sam", "sammy", "samuel", "samantha"],
+	&["kate", "katie", "kat", "kathy", "katherine", "catherine"],
+	&["jim", "jimmy", "jamie", "james"],
+	&["dave", "davey", "david"],
+	&["dan", "danny", "daniel"],
+	&["chris", "christopher", "christine", "christina"],
+	&["alex", "alexander", "alexandra", "sasha"],
+	&["tom", "tommy", "thomas"],
+	&["nick", "nicky", "nicholas"],
+	&["steve", "stevie", "steven", "stephen"],
+	&["matt", "matty", "matthew"],
+	&["tony", "anthony"],
+	&["joe", "joey", "joseph"],
+	&["ben", "benny", "benjamin"],
+	&["pat", "patty", "patrick", "patricia"],
+	&["meg", "maggie", "peggy", "margaret"],
+	&["jen", "jenny", "jennifer"],
+	&["ed", "eddie", "ted", "edward"],
+	&["rick", "ricky", "rich", "richie", "richard"],
+	&["andy", "drew", "andrew"],
+	&["greg", "gregory"],
+	&["abby", "abigail"],
+	&["sue", "susie", "susan", "suzanne"],
+	&["deb", "debbie", "deborah"],
+	&["vicky", "victoria"],
+	&["jess", "jessie", "jessica"],
+	&["fred", "freddie", "frederick"],
+];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ContactQuery {
+	#[schemars(
+		description = "The name of the contact or friend, a nickname or part of a name"
+	)]
+	pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Candidate {
+	pub id: String,
+	pub name: String,
+	pub nicknames: Vec<String>,
+}
+
+impl From<&Contact> for Candidate {
+	fn from(value: &Contact) -> Self {
+		Self {
+			id: value.id.clone(),
+			name: value.name.clone(),
+			nicknames: value.nicknames.clone(),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Disambiguation {
+	pub query: String,
+	pub candidates: Vec<Candidate>,
+	pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resolution {
+	Found(Contact),
+	Ambiguous(Disambiguation),
+	NotFound,
+}
+
+fn fold(c: char) -> Option<&'static str> {
+	Some(match c {
+		'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ă' | 'ą' => {
+			"a"
+		}
+		'æ' => "ae",
+		'ç' | 'ć' | 'č' => "c",
+		'ď' | 'đ' | 'ð' => "d",
+		'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ė' | 'ę' | 'ě' => "e",
+		'ğ' => "g",
+		'ì' | 'í' | 'î' | 'ï' | 'ī' | 'į' | 'ı' => "i",
+		'ł' => "l",
+		'ñ' | 'ń' | 'ň' => "n",
+		'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => "o",
+		'œ' => "oe",
+		'ř' => "r",
+		'ś' | 'š' | 'ş' => "s",
+		'ß' => "ss",
+		'ť' | 'ţ' => "t",
+		'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' | 'ų' => "u",
+		'ý' | 'ÿ' => "y",
+		'ź' | 'ż' | 'ž' => "z",
+		_ => return None,
+	})
+}
+
+// lowercase, diacritic-free tokens of a name. Apostrophes and periods are dropped so "O'Brien"
+// and "J.R." match "obrien" and "jr".
+pub(crate) fn tokenize(name: &str) -> Vec<String> {
+	let mut folded = String::new();
+
+	for c in name.chars().flat_map(char::to_lowercase) {
+		if let Some(s) = fold(c) {
+			folded.push_str(s)
+		} else if c.is_alphanumeric() {
+			folded.push(c)
+		} else if !matches!(c, '\'' | '’' | '.') {
+			folded.push(' ')
+		}
+	}
+
+	folded.split_whitespace().map(|x| x.to_string()).collect()
+}
+
+fn diminutives(a: &str, b: &str) -> bool {
+	DIMINUTIVES
+		.iter()
+		.any(|group| group.contains(&a) && group.contains(&b))
+}
+
+fn max_edits(token: &str) -> usize {
+	match token.chars().count() {
+		0..=3 => 0,
+		4..=6 => 1,
+		_ => 2,
+	}
+}
+
+fn token_score(query: &str, name: &str) -> f32 {
+	if query == name {
+		return EXACT_SCORE;
+	}
+
+	if diminutives(query, name) {
+		return DIMINUTIVE_SCORE;
+	}
+
+	if name.starts_with(query) {
+		return PREFIX_SCORE;
+	}
+
+	let distance = strsim::levenshtein(query, name);
+	if distance <= max_edits(query) {
+		EDIT_SCORE - 0.1 * (distance - 1) as f32
+	} else {
+		0.0
+	}
+}
+
+// scores the query against one spelling of a name; every query token has to match a different
+// token of the name.
+fn name_score(query: &[String], name: &[String]) -> f32 {
+	if query.is_empty() || name.is_empty() {
+		return 0.0;
+	}
+
+	let mut used = vec![false; name.len()];
+	let mut total = 0.0;
+
+	for q in query {
+		let best = name
+			.iter()
+			.enumerate()
+			.filter(|(i, _)| !used[*i])
+			.map(|(i, n)| (i, token_score(q, n)))
+			.max_by(|a, b| a.1.total_cmp(&b.1));
+
+		match best {
+			Some((i, score)) if score > 0.0 => {
+				used[i] = true;
+				total += score;
+			}
+			_ => return 0.0,
+		}
+	}
+
+	total / query.len() as f32
+		+ COVERAGE_BONUS * query.len() as f32 / name.len() as f32
+}
+
+pub(crate) fn contact_score(
+	query: &[String], contact: &Contact,
+) -> f32 {
+	std::iter::once(&contact.name)
+		.chain(contact.nicknames.iter())
+		.map(|x| name_score(query, &tokenize(x)))
+		.fold(0.0, f32::max)
+}
+
+pub fn resolve_contact(contacts: &[Contact], name: &str) -> Resolution {
+	let query = tokenize(name);
+
+	let mut scored: Vec<(f32, &Contact)> = contacts
+		.iter()
+		.map(|x| (contact_score(&query, x), x))
+		.filter(|(score, _)| *score > 0.0)
+		.collect();
+	scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+	let Some((best, _)) = scored.first().cloned() else {
+		return Resolution::NotFound;
+	};
+
+	let tied: Vec<&Contact> = scored
+		.iter()
+		.take_while(|(score, _)| best - score < AMBIGUITY_MARGIN)
+		.map(|(_, x)| *x)
+		.collect();
+
+	if tied.len() == 1 {
+		Resolution::Found(tied[0].clone())
+	} else {
+		Resolution::Ambiguous(Disambiguation {
+			query: name.to_string(),
+			candidates: tied
+				.into_iter()
+				.take(MAX_CANDIDATES)
+				.map(Into::into)
+				.collect(),
+			message: format!(
+				"more than one contact matches '{}'; ask the user which one they mean, then call the tool again with the full name",
+				name
+			),
+		})
+	}
+}
+
+// the contact whose name or a nickname is name, up to case and accents; the ones that only come
+// close are offered as candidates instead
+pub fn resolve_exact(contacts: &[Contact], name: &str) -> Resolution {
+	let query = tokenize(name);
+	let exact: Vec<&Contact> = contacts
+		.iter()
+		.filter(|x| {
+			std::iter::once(&x.name)
+				.chain(x.nicknames.iter())
+				.any(|x| tokenize(x) == query)
+		})
+		.collect();
+
+	let candidates: Vec<Candidate> = match exact.as_slice() {
+		[contact] => return Resolution::Found((*contact).clone()),
+		[] => match resolve_contact(contacts, name) {
+			Resolution::Found(contact) => vec![(&contact).into()],
+			Resolution::Ambiguous(x) => x.candidates,
+			Resolution::NotFound => return Resolution::NotFound,
+		},
+		_ => exact
+			.into_iter()
+			.take(MAX_CANDIDATES)
+			.map(Into::into)
+			.collect(),
+	};
+	Resolution::Ambiguous(Disambiguation {
+		query: name.to_string(),
+		candidates,
+		message: format!(
+			"no one contact is called '{}'; ask the user which one they mean, then call the tool again with their full name",
+			name
+		),
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn contact(id: &str, name: &str, nicknames: &[&str]) -> Contact {
+		Contact {
+			id: id.into(),
+			name: name.into(),
+			nicknames: nicknames
+				.iter()
+				.map(|x| x.to_string())
+				.collect(),
+			..Default::default()
+		}
+	}
+
+	fn contacts() -> Vec<Contact> {
+		vec![
+			contact("1", "Jonathan Smith", &[]),
+			contact("2", "Jonathan Sanders", &[]),
+			contact("3", "Margaret Hall", &["Mom"]),
+			contact("4", "Zoë Ångström", &[]),
+			contact("5", "Samuel O'Brien", &[]),
+			contact("6", "Samantha Reyes", &[]),
+		]
+	}
+
+	fn found(name: &str) -> String {
+		match resolve_contact(&contacts(), name) {
+			Resolution::Found(x) => x.id,
+			x => panic!("'{}' did not resolve: {:?}", name, x),
+		}
+	}
+
+	#[test]
+	fn test_tokenize() {
+		assert_eq!(tokenize("Zoë  Ångström"), vec!["zoe", "angstrom"]);
+		assert_eq!(
+			tokenize("Samuel O'Brien"),
+			vec!["samuel", "obrien"]
+		);
+		assert_eq!(tokenize("anne-marie"), vec!["anne", "marie"]);
+	}
+
+	#[test]
+	fn test_resolve_contact() {
+		assert_eq!(found("jonathan smith"), "1");
+		assert_eq!(found("Jonathan Smyth"), "1");
+		assert_eq!(found("jon sanders"), "2");
+		assert_eq!(found("mom"), "3");
+		assert_eq!(found("maggie"), "3");
+		assert_eq!(found("zoe"), "4");
+		assert_eq!(found("obrien"), "5");
+		assert_eq!(found("reyes"), "6");
+		assert_eq!(
+			resolve_contact(&contacts(), "bartholomew"),
+			Resolution::NotFound
+		);
+	}
+
+	#[test]
+	fn test_resolve_exact() {
+		for (name, id) in [("Jonathan Smith", "1"), ("mom", "3")] {
+			match resolve_exact(&contacts(), name) {
+				Resolution::Found(x) => assert_eq!(x.id, id),
+				x => panic!("'{}' did not resolve: {:?}", name, x),
+			}
+		}
+		// close isn't enough to act on
+		for (name, count) in
+			[("Jonathan Smyth", 1), ("maggie", 1), ("jon", 2)]
+		{
+			match resolve_exact(&contacts(), name) {
+				Resolution::Ambiguous(d) => {
+					assert_eq!(d.candidates.len(), count, "{:?}", d)
+				}
+				x => panic!("'{}' resolved: {:?}", name, x),
+			}
+		}
+		assert_eq!(
+			resolve_exact(&contacts(), "bartholomew"),
+			Resolution::NotFound
+		);
+	}
+
+	#[test]
+	fn test_resolve_ambiguous() {
+		for name in ["jon", "jonathan s", "sam"] {
+			match resolve_contact(&contacts(), name) {
+				Resolution::Ambiguous(d) => {
+					assert_eq!(d.query, name);
+					assert_eq!(d.candidates.len(), 2, "{:?}", d);
+				}
+				x => panic!("'{}' was not ambiguous: {:?}", name, x),
+			}
+		}
+	}
+}
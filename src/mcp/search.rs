@@ -0,0 +1,681 @@
+use super::store::{ChatMessage, Conversation, DataStore};
+use chrono::{DateTime, Utc};
+use rmcp::schemars::{self, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+pub(crate) const DEFAULT_SEARCH_LIMIT: usize = 10;
+pub(crate) const MAX_SEARCH_LIMIT: usize = 50;
+// how much of the text is shown around the first match
+const SNIPPET_CHARS: usize = 120;
+// BM25 parameters
+const K1: f32 = 1.2;
+const B: f32 = 0.75;
+// terms this long also match longer terms starting with them, at a discount
+const MIN_PREFIX_LEN: usize = 3;
+const PREFIX_WEIGHT: f32 = 0.5;
+// documents less similar to the query than this aren't semantic matches at all
+const MIN_SIMILARITY: f32 = 0.3;
+// reciprocal rank fusion constant
+const RRF_K: f32 = 60.0;
+
+// NOTE: search is hybrid. Every document goes into an inverted index and is scored with BM25; when
+// the documents and the query also have embeddings, documents are ranked by similarity too and the
+// two rankings are merged with reciprocal rank fusion, so a message about "dinner" can be found
+// with "restaurant". Embeddings are optional throughout: the phone indexes its own store without
+// them, and the server only has them if the configured LLM backend produces them.
+
+#[derive(
+	Debug,
+	Clone,
+	Copy,
+	PartialEq,
+	Eq,
+	Hash,
+	Serialize,
+	Deserialize,
+	JsonSchema,
+)]
+pub enum DocumentKind {
+	#[serde(rename = "contact")]
+	Contact,
+	#[serde(rename = "group")]
+	Group,
+	// a message in a chat with a contact
+	#[serde(rename = "message")]
+	Message,
+	// a message in a group chat
+	#[serde(rename = "group_message")]
+	GroupMessage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Document {
+	pub kind: DocumentKind,
+	// id of the contact, group or message
+	pub id: String,
+	// id of the contact or group a message was exchanged with
+	#[serde(default)]
+	pub conversation: Option<String>,
+	// name of the contact or group, or of the sender of a message
+	pub title: String,
+	pub text: String,
+	#[serde(default)]
+	pub sent_at: Option<DateTime<Utc>>,
+}
+
+impl Document {
+	// what an embedding is computed from
+	pub fn embedding_text(&self) -> String {
+		format!("{}: {}", self.title, self.text)
+	}
+}
+
+// everything in the store, as documents
+pub fn documents(store: &dyn DataStore) -> Vec<Document> {
+	let contacts = store.contacts();
+	let names: HashMap<_, _> = contacts
+		.iter()
+		.map(|x| (x.id.as_str(), x.name.as_str()))
+		.collect();
+	let sender = |msg: &ChatMessage| {
+		names
+			.get(msg.sender.as_str())
+			.map(|x| x.to_string())
+			.unwrap_or_else(|| msg.sender.clone())
+	};
+
+	let mut documents = Vec::new();
+
+	for contact in &contacts {
+		let mut text = contact.nicknames.join(", ");
+		if let Some(status) = &contact.status {
+			if !text.is_empty() {
+				text.push_str(". ");
+			}
+			text.push_str(status);
+		}
+
+		documents.push(Document {
+			kind: DocumentKind::Contact,
+			id: contact.id.clone(),
+			conversation: None,
+			title: contact.name.clone(),
+			text,
+			sent_at: None,
+		});
+
+		for msg in
+			store.messages(&Conversation::Contact(contact.id.clone()))
+		{
+			documents.push(Document {
+				kind: DocumentKind::Message,
+				id: msg.id.clone(),
+				conversation: Some(contact.id.clone()),
+				title: sender(&msg),
+				text: msg.body,
+				sent_at: Some(msg.sent_at),
+			});
+		}
+	}
+
+	for group in store.groups() {
+		documents.push(Document {
+			kind: DocumentKind::Group,
+			id: group.id.clone(),
+			conversation: None,
+			title: group.name.clone(),
+			text: group
+				.members
+				.iter()
+				.filter_map(|x| names.get(x.as_str()).copied())
+				.collect::<Vec<_>>()
+				.join(", "),
+			sent_at: None,
+		});
+
+		for msg in
+			store.messages(&Conversation::Group(group.id.clone()))
+		{
+			documents.push(Document {
+				kind: DocumentKind::GroupMessage,
+				id: msg.id.clone(),
+				conversation: Some(group.id.clone()),
+				title: sender(&msg),
+				text: msg.body,
+				sent_at: Some(msg.sent_at),
+			});
+		}
+	}
+
+	documents
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct SearchQuery {
+	#[schemars(
+		description = "Words to look for in contacts, groups and chat messages"
+	)]
+	pub query: String,
+	#[schemars(
+		description = "Only results of these kinds: contact, group, message or group_message"
+	)]
+	#[serde(default)]
+	pub kinds: Vec<DocumentKind>,
+	#[schemars(
+		description = "The maximum number of results to return, 10 by default and at most 50"
+	)]
+	#[serde(default)]
+	pub limit: Option<usize>,
+}
+
+impl SearchQuery {
+	pub(crate) fn limit(&self) -> usize {
+		self.limit
+			.unwrap_or(DEFAULT_SEARCH_LIMIT)
+			.clamp(1, MAX_SEARCH_LIMIT)
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SearchHit {
+	pub kind: DocumentKind,
+	pub id: String,
+	#[serde(default)]
+	pub conversation: Option<String>,
+	pub title: String,
+	pub snippet: String,
+	#[serde(default)]
+	pub sent_at: Option<DateTime<Utc>>,
+	// higher is better; only comparable within one search
+	pub score: f32,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+	document: Document,
+	length: usize,
+	embedding: Option<Vec<f32>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchIndex {
+	// replaced documents leave a hole, so positions in postings stay valid
+	entries: Vec<Option<Entry>>,
+	// term -> (position, term frequency)
+	postings: HashMap<String, Vec<(usize, u32)>>,
+	positions: HashMap<(DocumentKind, String), usize>,
+	total_length: usize,
+}
+
+impl SearchIndex {
+	pub fn from_store(store: &dyn DataStore) -> Self {
+		let mut index = Self::default();
+		for document in documents(store) {
+			index.insert(document, None);
+		}
+		index
+	}
+
+	pub fn len(&self) -> usize {
+		self.positions.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.positions.is_empty()
+	}
+
+	// adds a document, replacing one of the same kind and id
+	pub fn insert(
+		&mut self, document: Document, embedding: Option<Vec<f32>>,
+	) {
+		let key = (document.kind, document.id.clone());
+		if let Some(old) = self.positions.remove(&key)
+			&& let Some(entry) = self.entries[old].take()
+		{
+			self.total_length -= entry.length;
+			for term in frequencies(&entry.document).0.into_keys() {
+				if let Some(postings) = self.postings.get_mut(&term) {
+					postings.retain(|x| x.0 != old);
+					if postings.is_empty() {
+						self.postings.remove(&term);
+					}
+				}
+			}
+		}
+
+		let position = self.entries.len();
+		let (frequencies, length) = frequencies(&document);
+		for (term, frequency) in frequencies {
+			self.postings
+				.entry(term)
+				.or_default()
+				.push((position, frequency));
+		}
+
+		self.total_length += length;
+		self.positions.insert(key, position);
+		self.entries.push(Some(Entry {
+			document,
+			length,
+			embedding,
+		}));
+	}
+
+	// the best matches for the query, best first. Without an embedding for the query, or
+	// documents that have one, this is a plain lexical search.
+	pub fn search(
+		&self, query: &SearchQuery, embedding: Option<&[f32]>,
+	) -> Vec<SearchHit> {
+		let terms: Vec<_> = tokens(&query.query).map(|x| x.1).collect();
+		let wanted = |entry: &Entry| {
+			query.kinds.is_empty()
+				|| query.kinds.contains(&entry.document.kind)
+		};
+
+		let lexical: Vec<_> = self
+			.lexical(&terms)
+			.into_iter()
+			.filter(|x| self.entry(x.0).is_some_and(wanted))
+			.collect();
+
+		let semantic: Vec<_> = embedding
+			.map(|embedding| self.semantic(embedding))
+			.unwrap_or_default()
+			.into_iter()
+			.filter(|x| self.entry(x.0).is_some_and(wanted))
+			.collect();
+
+		let ranked = if semantic.is_empty() {
+			lexical
+		} else {
+			let mut fused: HashMap<usize, f32> = HashMap::new();
+			for ranking in [&lexical, &semantic] {
+				for (rank, (position, _)) in ranking.iter().enumerate()
+				{
+					*fused.entry(*position).or_default() +=
+						1.0 / (RRF_K + rank as f32 + 1.0);
+				}
+			}
+			sorted(fused.into_iter().collect())
+		};
+
+		ranked
+			.into_iter()
+			.take(query.limit())
+			.filter_map(|(position, score)| {
+				let document = &self.entry(position)?.document;
+				Some(SearchHit {
+					kind: document.kind,
+					id: document.id.clone(),
+					conversation: document.conversation.clone(),
+					title: document.title.clone(),
+					snippet: snippet(&document.text, &terms),
+					sent_at: document.sent_at,
+					score,
+				})
+			})
+			.collect()
+	}
+
+	fn entry(&self, position: usize) -> Option<&Entry> {
+		self.entries.get(position)?.as_ref()
+	}
+
+	fn lexical(&self, terms: &[String]) -> Vec<(usize, f32)> {
+		if self.is_empty() {
+			return Vec::new();
+		}
+
+		let count = self.len() as f32;
+		let average = self.total_length as f32 / count;
+		let mut scores: HashMap<usize, f32> = HashMap::new();
+
+		for term in terms {
+			let matches =
+				self.postings.iter().filter_map(|(x, postings)| {
+					if x == term {
+						Some((1.0, postings))
+					} else if term.chars().count() >= MIN_PREFIX_LEN
+						&& x.starts_with(term.as_str())
+					{
+						Some((PREFIX_WEIGHT, postings))
+					} else {
+						None
+					}
+				});
+
+			for (weight, postings) in matches {
+				let live: Vec<_> = postings
+					.iter()
+					.filter_map(|(position, frequency)| {
+						Some((
+							self.entry(*position)?,
+							*position,
+							*frequency,
+						))
+					})
+					.collect();
+				let idf = (1.0
+					+ (count - live.len() as f32 + 0.5)
+						/ (live.len() as f32 + 0.5))
+					.ln();
+
+				for (entry, position, frequency) in live {
+					let frequency = frequency as f32;
+					let norm = K1
+						* (1.0 - B + B * entry.length as f32 / average);
+					*scores.entry(position).or_default() +=
+						weight * idf * frequency * (K1 + 1.0)
+							/ (frequency + norm);
+				}
+			}
+		}
+
+		sorted(scores.into_iter().collect())
+	}
+
+	fn semantic(&self, embedding: &[f32]) -> Vec<(usize, f32)> {
+		sorted(
+			self.entries
+				.iter()
+				.enumerate()
+				.filter_map(|(position, entry)| {
+					let similarity = cosine(
+						entry.as_ref()?.embedding.as_deref()?,
+						embedding,
+					)?;
+					(similarity >= MIN_SIMILARITY)
+						.then_some((position, similarity))
+				})
+				.collect(),
+		)
+	}
+}
+
+// best first, ties in the order documents were added
+fn sorted(mut scores: Vec<(usize, f32)>) -> Vec<(usize, f32)> {
+	scores.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+	scores
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> Option<f32> {
+	if a.len() != b.len() {
+		return None;
+	}
+
+	let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+	let norm = a.iter().map(|x| x * x).sum::<f32>().sqrt()
+		* b.iter().map(|x| x * x).sum::<f32>().sqrt();
+	(norm > 0.0).then(|| dot / norm)
+}
+
+// how often each term is in the document, and how many terms it has
+fn frequencies(document: &Document) -> (HashMap<String, u32>, usize) {
+	let mut frequencies: HashMap<String, u32> = HashMap::new();
+	let mut length = 0;
+	for (_, term) in
+		tokens(&document.title).chain(tokens(&document.text))
+	{
+		*frequencies.entry(term).or_default() += 1;
+		length += 1;
+	}
+	(frequencies, length)
+}
+
+// lowercased words, with the byte offset each starts at
+fn tokens(text: &str) -> impl Iterator<Item = (usize, String)> + '_ {
+	text.split(|c: char| !c.is_alphanumeric())
+		.filter(|x| !x.is_empty())
+		.map(move |x| {
+			(
+				x.as_ptr() as usize - text.as_ptr() as usize,
+				x.to_lowercase(),
+			)
+		})
+}
+
+// the text around the first word matching a term, or its start
+fn snippet(text: &str, terms: &[String]) -> String {
+	let start = tokens(text)
+		.find(|(_, token)| {
+			terms.iter().any(|term| {
+				token == term
+					|| (term.chars().count() >= MIN_PREFIX_LEN
+						&& token.starts_with(term.as_str()))
+			})
+		})
+		.map(|x| x.0)
+		.unwrap_or_default();
+
+	let before = text[..start].chars().count();
+	let skip = before.saturating_sub(SNIPPET_CHARS / 4);
+	let mut snippet: String =
+		text.chars().skip(skip).take(SNIPPET_CHARS).collect();
+
+	if skip > 0 {
+		snippet.insert(0, '…');
+	}
+	if text.chars().count() > skip + SNIPPET_CHARS {
+		snippet.push('…');
+	}
+	snippet
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mcp::store::{Contact, Group, MemoryStore, StoreData};
+
+	fn message(id: &str, sender: &str, body: &str) -> ChatMessage {
+		ChatMessage {
+			id: id.into(),
+			sender: sender.into(),
+			body: body.into(),
+			sent_at: Utc::now(),
+			reactions: Vec::new(),
+		}
+	}
+
+	fn store() -> MemoryStore {
+		StoreData {
+			contacts: vec![
+				Contact {
+					id: "1".into(),
+					name: "Sam Smith".into(),
+					nicknames: vec!["Sammy".into()],
+					..Default::default()
+				},
+				Contact {
+					id: "2".into(),
+					name: "Alex Jones".into(),
+					status: Some("hiking this weekend".into()),
+					..Default::default()
+				},
+			],
+			groups: vec![Group {
+				id: "g".into(),
+				name: "Book club".into(),
+				members: vec!["1".into(), "2".into()],
+			}],
+			chats: [(
+				"1".into(),
+				vec![
+					message("m1", "1", "are we still on for dinner?"),
+					message("m2", "me", "yes, the pizza place at 7"),
+				],
+			)]
+			.into(),
+			group_chats: [(
+				"g".into(),
+				vec![message(
+					"m3",
+					"2",
+					"next book: a history of pizza and dinners",
+				)],
+			)]
+			.into(),
+		}
+		.into()
+	}
+
+	fn query(text: &str) -> SearchQuery {
+		SearchQuery {
+			query: text.into(),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_documents() {
+		let documents = documents(&store());
+		assert_eq!(documents.len(), 6);
+
+		let m1 = documents.iter().find(|x| x.id == "m1").unwrap();
+		assert_eq!(m1.kind, DocumentKind::Message);
+		assert_eq!(m1.title, "Sam Smith");
+		assert_eq!(m1.conversation.as_deref(), Some("1"));
+
+		let group = documents.iter().find(|x| x.id == "g").unwrap();
+		assert_eq!(group.text, "Sam Smith, Alex Jones");
+	}
+
+	#[test]
+	fn test_lexical_search() {
+		let index = SearchIndex::from_store(&store());
+
+		let hits = index.search(&query("pizza"), None);
+		assert_eq!(hits.len(), 2);
+		// the shorter message is the better match
+		assert_eq!(hits[0].id, "m2");
+		assert_eq!(hits[1].kind, DocumentKind::GroupMessage);
+		assert_eq!(hits[1].conversation.as_deref(), Some("g"));
+
+		// by nickname, and by prefix
+		assert_eq!(index.search(&query("sammy"), None)[0].id, "1");
+		let hits = index.search(&query("dinner"), None);
+		assert_eq!(hits.len(), 2);
+		assert_eq!(hits[0].id, "m1");
+
+		let hits = index.search(
+			&SearchQuery {
+				query: "pizza".into(),
+				kinds: vec![DocumentKind::GroupMessage],
+				limit: Some(5),
+			},
+			None,
+		);
+		assert_eq!(hits.len(), 1);
+		assert_eq!(hits[0].id, "m3");
+
+		assert!(index.search(&query("karaoke"), None).is_empty());
+	}
+
+	#[test]
+	fn test_replace() {
+		let mut index = SearchIndex::from_store(&store());
+		let count = index.len();
+
+		index.insert(
+			Document {
+				kind: DocumentKind::Message,
+				id: "m2".into(),
+				conversation: Some("1".into()),
+				title: "me".into(),
+				text: "actually, let's get sushi".into(),
+				sent_at: None,
+			},
+			None,
+		);
+
+		assert_eq!(index.len(), count);
+		assert_eq!(index.search(&query("pizza"), None).len(), 1);
+		assert_eq!(index.search(&query("sushi"), None)[0].id, "m2");
+		// nothing is left of the old text
+		assert!(
+			index
+				.postings
+				.values()
+				.flatten()
+				.all(|x| index.entry(x.0).is_some())
+		);
+	}
+
+	#[test]
+	fn test_hybrid_search() {
+		fn add(
+			index: &mut SearchIndex, id: &str, text: &str,
+			embedding: Vec<f32>,
+		) {
+			index.insert(
+				Document {
+					kind: DocumentKind::Message,
+					id: id.into(),
+					conversation: Some("1".into()),
+					title: "Sam".into(),
+					text: text.into(),
+					sent_at: None,
+				},
+				Some(embedding),
+			)
+		}
+
+		let mut index = SearchIndex::default();
+		add(
+			&mut index,
+			"sport",
+			"restaurant league, restaurant game",
+			vec![0.0, 1.0],
+		);
+		add(
+			&mut index,
+			"food",
+			"dinner at the trattoria by the restaurant",
+			vec![1.0, 0.0],
+		);
+		add(
+			&mut index,
+			"pizza",
+			"a margherita from the wood oven",
+			vec![0.8, 0.2],
+		);
+
+		// sport says restaurant the most, but food means it
+		let lexical = index.search(&query("restaurant"), None);
+		let ids: Vec<_> =
+			lexical.iter().map(|x| x.id.as_str()).collect();
+		assert_eq!(ids, ["sport", "food"]);
+		let hits =
+			index.search(&query("restaurant"), Some(&[0.9, 0.1]));
+		let ids: Vec<_> = hits.iter().map(|x| x.id.as_str()).collect();
+		// and nothing about pizza says restaurant
+		assert_eq!(ids, ["food", "sport", "pizza"]);
+
+		// nothing is found by what a document said before it was replaced
+		add(&mut index, "sport", "pool party tonight", vec![0.0, 1.0]);
+		assert!(index.search(&query("league"), None).is_empty());
+		assert!(!index.postings.contains_key("league"));
+		let hits =
+			index.search(&query("restaurant"), Some(&[0.0, 1.0]));
+		let ids: Vec<_> = hits.iter().map(|x| x.id.as_str()).collect();
+		assert_eq!(ids, ["food", "sport"]);
+		assert_eq!(hits[1].snippet, "pool party tonight");
+	}
+
+	#[test]
+	fn test_snippet() {
+		let long = format!(
+			"{} the pizza was great {}",
+			"a ".repeat(100),
+			"b ".repeat(100)
+		);
+		let x = snippet(&long, &["pizza".into()]);
+		assert!(x.starts_with('…') && x.ends_with('…'));
+		assert!(x.contains("the pizza was great"));
+
+		assert_eq!(
+			snippet("héllo wörld", &["wörld".into()]),
+			"héllo wörld"
+		);
+	}
+}
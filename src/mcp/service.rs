@@ -1,76 +1,372 @@
+use super::{
+	action::{CreateGroup, ReactToMessage, SendMessage},
+	history::{ChatHistoryQuery, GroupHistoryQuery, HistoryWindow},
+	policy::{
+		ConsentHandler, ConsentLog, DenyConsent, Policy,
+		PolicyDecision, target,
+	},
+	resolve::{
+		ContactQuery, Resolution, resolve_contact, resolve_exact,
+	},
+	search::{SearchIndex, SearchQuery},
+	store::{Contact, Conversation, DataStore, Group, MemoryStore},
+};
 use rmcp::{
 	RoleServer, ServerHandler,
-	handler::server::{router::tool::ToolRouter, tool::Parameters},
+	handler::server::{
+		router::tool::ToolRouter,
+		tool::{Parameters, ToolCallContext},
+	},
 	model::*,
 	service::RequestContext,
-	tool, tool_handler, tool_router,
+	tool, tool_router,
 };
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone)]
 pub struct Service {
 	tool_router: ToolRouter<Self>,
+	store: Arc<dyn DataStore>,
+	policy: Arc<Policy>,
+	consent: Arc<dyn ConsentHandler>,
+	// one Service is served per MCP session, so this is the session's record
+	log: Arc<Mutex<ConsentLog>>,
+}
+
+impl Default for Service {
+	fn default() -> Self {
+		Self::new(Arc::new(MemoryStore::default()))
+	}
+}
+
+impl Service {
+	pub fn new(store: Arc<dyn DataStore>) -> Self {
+		Self {
+			tool_router: Self::tool_router(),
+			store,
+			policy: Default::default(),
+			consent: Arc::new(DenyConsent),
+			log: Default::default(),
+		}
+	}
+
+	pub fn with_policy(
+		mut self, policy: Policy, consent: Arc<dyn ConsentHandler>,
+	) -> Self {
+		self.policy = Arc::new(policy);
+		self.consent = consent;
+		self
+	}
+
+	// decisions made about tool calls in this session, oldest first
+	pub fn decisions(&self) -> Vec<PolicyDecision> {
+		self.log.lock().unwrap().decisions().to_vec()
+	}
+
+	// names the policy may know the target of a call by
+	fn aliases(&self, target: Option<&str>) -> Vec<String> {
+		let Some(target) = target else {
+			return Vec::new();
+		};
+
+		let mut aliases = vec![target.to_string()];
+		if let Resolution::Found(contact) =
+			resolve_contact(&self.store.contacts(), target)
+		{
+			aliases.push(contact.name);
+			aliases.extend(contact.nicknames);
+		}
+
+		aliases
+	}
+
+	async fn authorize(
+		&self, tool: &str, arguments: &serde_json::Value,
+	) -> Result<(), String> {
+		let target = target(arguments);
+		let consent =
+			self.policy.consent(tool, &self.aliases(target.as_deref()));
+		let decided = self.log.lock().unwrap().decided(
+			consent,
+			tool,
+			target.as_deref(),
+		);
+
+		let allowed = match decided {
+			Some(allowed) => allowed,
+			None => {
+				self.consent
+					.ask(tool, target.as_deref(), arguments)
+					.await
+			}
+		};
+
+		self.log.lock().unwrap().record(PolicyDecision {
+			tool: tool.to_string(),
+			target,
+			consent,
+			allowed,
+			decided_at: chrono::Utc::now(),
+		});
+
+		if allowed {
+			Ok(())
+		} else {
+			Err(format!(
+				"{} is not allowed by the user's privacy settings",
+				tool
+			))
+		}
+	}
+
+	// every tool that takes a contact's name goes through here, so they all resolve names the
+	// same way and hand back the same disambiguation result.
+	fn with_contact(
+		&self, name: &str,
+		f: impl FnOnce(Contact) -> Result<String, String>,
+	) -> Result<String, String> {
+		match resolve_contact(&self.store.contacts(), name) {
+			Resolution::Found(contact) => f(contact),
+			Resolution::Ambiguous(disambiguation) => {
+				to_json(&disambiguation)
+			}
+			Resolution::NotFound => {
+				Err(format!("no contact matches '{}'", name))
+			}
+		}
+	}
+
+	fn find_group(&self, name: &str) -> Result<Group, String> {
+		self.store
+			.groups()
+			.into_iter()
+			.find(|x| x.name.eq_ignore_ascii_case(name))
+			.ok_or_else(|| format!("no group matches '{}'", name))
+	}
+
+	fn history(
+		&self, conversation: Conversation, window: &HistoryWindow,
+	) -> Result<String, String> {
+		to_json(
+			&window
+				.apply(self.store.messages(&conversation))
+				.map_err(|e| e.to_string())?,
+		)
+	}
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, String> {
+	serde_json::to_string(value).map_err(|e| e.to_string())
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ContactSummary {
+	id: String,
+	name: String,
+}
+
+impl From<&Contact> for ContactSummary {
+	fn from(value: &Contact) -> Self {
+		Self {
+			id: value.id.clone(),
+			name: value.name.clone(),
+		}
+	}
 }
 
 // NOTE: these must mirror the tool list in super::tool for any effect
 #[tool_router]
 impl Service {
 	#[tool(description = "list of all contacts or friends")]
-	pub(crate) fn all_contacts(&self) -> String {
-		String::new()
+	pub(crate) fn all_contacts(&self) -> Result<String, String> {
+		to_json(
+			&self
+				.store
+				.contacts()
+				.iter()
+				.map(ContactSummary::from)
+				.collect::<Vec<_>>(),
+		)
 	}
 
 	#[tool(description = "information on a specific contact or friend")]
 	pub(crate) fn contact_info(
-		&self, Parameters(_name): Parameters<String>,
-	) -> String {
-		String::new()
+		&self, Parameters(query): Parameters<ContactQuery>,
+	) -> Result<String, String> {
+		self.with_contact(&query.name, |contact| to_json(&contact))
 	}
 
 	#[tool(
 		description = "information about the friends or contacts of another contact or friend"
 	)]
 	pub(crate) fn contact_network(
-		&self, Parameters(_name): Parameters<String>,
-	) -> String {
-		String::new()
+		&self, Parameters(query): Parameters<ContactQuery>,
+	) -> Result<String, String> {
+		self.with_contact(&query.name, |contact| {
+			to_json(
+				&self
+					.store
+					.contacts()
+					.iter()
+					.filter(|x| contact.friends.contains(&x.id))
+					.map(ContactSummary::from)
+					.collect::<Vec<_>>(),
+			)
+		})
 	}
 
 	#[tool(
-		description = "recent chat messages with a friend or contact"
+		description = "chat messages with a friend or contact, newest first. Pass next_cursor back as cursor to page into older messages"
 	)]
 	pub(crate) fn chat_messages(
-		&self, Parameters(_name): Parameters<String>,
-	) -> String {
-		String::new()
+		&self, Parameters(query): Parameters<ChatHistoryQuery>,
+	) -> Result<String, String> {
+		self.with_contact(&query.name, |contact| {
+			self.history(
+				Conversation::Contact(contact.id),
+				&query.window,
+			)
+		})
 	}
 
-	#[tool(description = "recent messages inside a group chat")]
+	#[tool(
+		description = "messages inside a group chat, newest first. Pass next_cursor back as cursor to page into older messages"
+	)]
 	pub(crate) fn group_chat(
-		&self, Parameters(_name): Parameters<String>,
-	) -> String {
-		String::new()
+		&self, Parameters(query): Parameters<GroupHistoryQuery>,
+	) -> Result<String, String> {
+		let group = self
+			.store
+			.groups()
+			.into_iter()
+			.find(|x| x.name.eq_ignore_ascii_case(&query.name))
+			.ok_or_else(|| {
+				format!("no group matches '{}'", query.name)
+			})?;
+		self.history(Conversation::Group(group.id), &query.window)
+	}
+
+	#[tool(
+		description = "search contacts, groups and chat messages for words or topics, best matches first"
+	)]
+	pub(crate) fn search_messages(
+		&self, Parameters(query): Parameters<SearchQuery>,
+	) -> Result<String, String> {
+		// NOTE: stores on a phone are small enough to index on every call, and the index is
+		// never out of date this way
+		to_json(
+			&SearchIndex::from_store(self.store.as_ref())
+				.search(&query, None),
+		)
 	}
 
 	#[tool(
 		description = "online activity information about a friend or contact"
 	)]
 	pub(crate) fn contact_activity(
-		&self, Parameters(_name): Parameters<String>,
-	) -> String {
-		String::new()
+		&self, Parameters(query): Parameters<ContactQuery>,
+	) -> Result<String, String> {
+		self.with_contact(&query.name, |contact| {
+			to_json(&serde_json::json!({
+				"name": contact.name,
+				"last_active": contact.last_active,
+			}))
+		})
 	}
 
 	#[tool(
 		description = "status information about a friend or contact"
 	)]
 	pub(crate) fn contact_status(
-		&self, Parameters(_name): Parameters<String>,
-	) -> String {
-		String::new()
+		&self, Parameters(query): Parameters<ContactQuery>,
+	) -> Result<String, String> {
+		self.with_contact(&query.name, |contact| {
+			to_json(&serde_json::json!({
+				"name": contact.name,
+				"status": contact.status,
+			}))
+		})
+	}
+
+	#[tool(
+		description = "send a chat message to a friend, contact or group. The user is asked to approve the message first"
+	)]
+	pub(crate) fn send_message(
+		&self, Parameters(action): Parameters<SendMessage>,
+	) -> Result<String, String> {
+		let conversation =
+			match resolve_exact(&self.store.contacts(), &action.name) {
+				Resolution::Found(contact) => {
+					Conversation::Contact(contact.id)
+				}
+				// a group called that is what was meant
+				Resolution::Ambiguous(disambiguation) => {
+					match self.find_group(&action.name) {
+						Ok(group) => Conversation::Group(group.id),
+						Err(_) => return to_json(&disambiguation),
+					}
+				}
+				Resolution::NotFound => Conversation::Group(
+					self.find_group(&action.name)?.id,
+				),
+			};
+
+		to_json(
+			&self
+				.store
+				.send_message(&conversation, &action.body)
+				.map_err(|e| e.to_string())?,
+		)
+	}
+
+	#[tool(
+		description = "react to a chat message. The user is asked to approve the reaction first"
+	)]
+	pub(crate) fn react_to_message(
+		&self, Parameters(action): Parameters<ReactToMessage>,
+	) -> Result<String, String> {
+		to_json(
+			&self
+				.store
+				.react_to_message(&action.message_id, &action.reaction)
+				.map_err(|e| e.to_string())?,
+		)
+	}
+
+	#[tool(
+		description = "create a group chat with friends or contacts. The user is asked to approve the group first"
+	)]
+	pub(crate) fn create_group(
+		&self, Parameters(action): Parameters<CreateGroup>,
+	) -> Result<String, String> {
+		let contacts = self.store.contacts();
+		let mut members = Vec::new();
+
+		for name in &action.members {
+			match resolve_exact(&contacts, name) {
+				Resolution::Found(contact) => members.push(contact.id),
+				Resolution::Ambiguous(disambiguation) => {
+					return to_json(&disambiguation);
+				}
+				Resolution::NotFound => {
+					return Err(format!(
+						"no contact matches '{}'",
+						name
+					));
+				}
+			}
+		}
+
+		to_json(
+			&self
+				.store
+				.create_group(&action.name, &members)
+				.map_err(|e| e.to_string())?,
+		)
 	}
 }
 
-#[tool_handler]
 impl ServerHandler for Service {
 	fn get_info(&self) -> ServerInfo {
 		ServerInfo {
@@ -91,4 +387,32 @@ impl ServerHandler for Service {
 	) -> Result<ListPromptsResult, rmcp::ErrorData> {
 		Ok(super::tool::tool_list().into())
 	}
+
+	async fn list_tools(
+		&self, _request: Option<PaginatedRequestParam>,
+		_: RequestContext<RoleServer>,
+	) -> Result<ListToolsResult, rmcp::ErrorData> {
+		Ok(ListToolsResult::with_all_items(self.tool_router.list_all()))
+	}
+
+	// NOTE: this is what #[tool_handler] would generate, with the privacy policy in front of it.
+	async fn call_tool(
+		&self, request: CallToolRequestParam,
+		context: RequestContext<RoleServer>,
+	) -> Result<CallToolResult, rmcp::ErrorData> {
+		let arguments = request
+			.arguments
+			.clone()
+			.map(serde_json::Value::Object)
+			.unwrap_or_default();
+
+		if let Err(e) = self.authorize(&request.name, &arguments).await
+		{
+			return Ok(CallToolResult::error(vec![Content::text(e)]));
+		}
+
+		self.tool_router
+			.call(ToolCallContext::new(self, request, context))
+			.await
+	}
 }
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::RwLock};
+
+// NOTE: this is the phone's view of the social graph. The app provides its own implementation
+// backed by whatever database it keeps; MemoryStore exists for tests and for hosting the MCP
+// service outside of the app.
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct Contact {
+	pub id: String,
+	pub name: String,
+	#[serde(default)]
+	pub nicknames: Vec<String>,
+	// ids of other contacts
+	#[serde(default)]
+	pub friends: Vec<String>,
+	#[serde(default)]
+	pub status: Option<String>,
+	#[serde(default)]
+	pub last_active: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct Group {
+	pub id: String,
+	pub name: String,
+	// ids of contacts
+	#[serde(default)]
+	pub members: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatMessage {
+	pub id: String,
+	// contact id of the sender, or "me" for the owner of the phone
+	pub sender: String,
+	pub body: String,
+	pub sent_at: DateTime<Utc>,
+	// reactions left by the owner of the phone
+	#[serde(default)]
+	pub reactions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+	Contact(String),
+	Group(String),
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum StoreError {
+	#[error("this data store is read-only")]
+	ReadOnly,
+	#[error("no conversation found for '{0}'")]
+	NoConversation(String),
+	#[error("no message with id '{0}'")]
+	NoMessage(String),
+	#[error("no contact with id '{0}'")]
+	NoContact(String),
+}
+
+pub trait DataStore: std::fmt::Debug + Send + Sync {
+	fn contacts(&self) -> Vec<Contact>;
+	fn groups(&self) -> Vec<Group>;
+	// messages may be returned in any order, callers sort them
+	fn messages(&self, conversation: &Conversation)
+	-> Vec<ChatMessage>;
+
+	// NOTE: writes are only ever reached after the user approved the action, see
+	// crate::mcp::tool::action_list. Stores that can't write keep the defaults.
+
+	fn send_message(
+		&self, _conversation: &Conversation, _body: &str,
+	) -> Result<ChatMessage, StoreError> {
+		Err(StoreError::ReadOnly)
+	}
+
+	fn react_to_message(
+		&self, _message_id: &str, _reaction: &str,
+	) -> Result<ChatMessage, StoreError> {
+		Err(StoreError::ReadOnly)
+	}
+
+	fn create_group(
+		&self, _name: &str, _members: &[String],
+	) -> Result<Group, StoreError> {
+		Err(StoreError::ReadOnly)
+	}
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StoreData {
+	#[serde(default)]
+	pub contacts: Vec<Contact>,
+	#[serde(default)]
+	pub groups: Vec<Group>,
+	// keyed by contact id
+	#[serde(default)]
+	pub chats: HashMap<String, Vec<ChatMessage>>,
+	// keyed by group id
+	#[serde(default)]
+	pub group_chats: HashMap<String, Vec<ChatMessage>>,
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore(RwLock<StoreData>);
+
+impl From<StoreData> for MemoryStore {
+	fn from(value: StoreData) -> Self {
+		Self(RwLock::new(value))
+	}
+}
+
+impl MemoryStore {
+	pub fn data(&self) -> StoreData {
+		self.0.read().unwrap().clone()
+	}
+}
+
+impl DataStore for MemoryStore {
+	fn contacts(&self) -> Vec<Contact> {
+		self.0.read().unwrap().contacts.clone()
+	}
+
+	fn groups(&self) -> Vec<Group> {
+		self.0.read().unwrap().groups.clone()
+	}
+
+	fn messages(
+		&self, conversation: &Conversation,
+	) -> Vec<ChatMessage> {
+		let data = self.0.read().unwrap();
+		match conversation {
+			Conversation::Contact(id) => data.chats.get(id),
+			Conversation::Group(id) => data.group_chats.get(id),
+		}
+		.cloned()
+		.unwrap_or_default()
+	}
+
+	fn send_message(
+		&self, conversation: &Conversation, body: &str,
+	) -> Result<ChatMessage, StoreError> {
+		let mut data = self.0.write().unwrap();
+		let messages = match conversation {
+			Conversation::Contact(id) => {
+				if !data.contacts.iter().any(|x| &x.id == id) {
+					return Err(StoreError::NoConversation(id.clone()));
+				}
+				data.chats.entry(id.clone()).or_default()
+			}
+			Conversation::Group(id) => {
+				if !data.groups.iter().any(|x| &x.id == id) {
+					return Err(StoreError::NoConversation(id.clone()));
+				}
+				data.group_chats.entry(id.clone()).or_default()
+			}
+		};
+
+		let msg = ChatMessage {
+			id: uuid::Uuid::new_v4().to_string(),
+			sender: "me".into(),
+			body: body.into(),
+			sent_at: Utc::now(),
+			reactions: Vec::new(),
+		};
+		messages.push(msg.clone());
+		Ok(msg)
+	}
+
+	fn react_to_message(
+		&self, message_id: &str, reaction: &str,
+	) -> Result<ChatMessage, StoreError> {
+		let mut data = self.0.write().unwrap();
+		let StoreData {
+			chats, group_chats, ..
+		} = &mut *data;
+
+		let msg = chats
+			.values_mut()
+			.chain(group_chats.values_mut())
+			.flat_map(|x| x.iter_mut())
+			.find(|x| x.id == message_id)
+			.ok_or_else(|| StoreError::NoMessage(message_id.into()))?;
+		msg.reactions.push(reaction.into());
+		Ok(msg.clone())
+	}
+
+	fn create_group(
+		&self, name: &str, members: &[String],
+	) -> Result<Group, StoreError> {
+		let mut data = self.0.write().unwrap();
+		if let Some(missing) = members
+			.iter()
+			.find(|id| !data.contacts.iter().any(|x| &x.id == *id))
+		{
+			return Err(StoreError::NoContact(missing.clone()));
+		}
+
+		let group = Group {
+			id: uuid::Uuid::new_v4().to_string(),
+			name: name.into(),
+			members: members.to_vec(),
+		};
+		data.groups.push(group.clone());
+		Ok(group)
+	}
+}
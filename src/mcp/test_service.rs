@@ -8,7 +8,9 @@ use rmcp::{
 	tool, tool_handler, tool_router,
 };
 
-use crate::mcp::tool::{ToolArgument, ToolFunction, ToolList};
+use crate::mcp::tool::{
+	ArgumentKind, ToolArgument, ToolFunction, ToolList,
+};
 
 #[derive(Debug, Clone, Default)]
 pub struct TestService {
@@ -68,6 +70,7 @@ pub(crate) fn test_tool_list() -> ToolList {
 				description: "The name of the contact or friend"
 					.to_string(),
 				required: true,
+				kind: ArgumentKind::String,
 			}],
 		},
 	])
@@ -40,6 +40,32 @@ pub(crate) struct ToolArgument {
 	pub(crate) name: String,
 	pub(crate) description: String,
 	pub(crate) required: bool,
+	pub(crate) kind: ArgumentKind,
+}
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub(crate) enum ArgumentKind {
+	#[default]
+	String,
+	Integer,
+	StringList,
+}
+
+impl ArgumentKind {
+	pub(crate) fn schema(&self) -> serde_json::Value {
+		match self {
+			ArgumentKind::String => {
+				serde_json::json!({ "type": "string" })
+			}
+			ArgumentKind::Integer => {
+				serde_json::json!({ "type": "integer" })
+			}
+			ArgumentKind::StringList => serde_json::json!({
+				"type": "array",
+				"items": { "type": "string" },
+			}),
+		}
+	}
 }
 
 impl ToolFunction {
@@ -55,6 +81,21 @@ impl ToolFunction {
 		v
 	}
 
+	// JSON schema of the arguments, as expected by the LLM's tool definitions
+	pub(crate) fn parameters(&self) -> serde_json::Value {
+		let properties: serde_json::Map<String, serde_json::Value> =
+			self.args
+				.iter()
+				.map(|x| (x.name.clone(), x.clone().into()))
+				.collect();
+
+		serde_json::json!({
+			"type": "object",
+			"properties": properties,
+			"required": self.required_arguments(),
+		})
+	}
+
 	pub(crate) fn into_rmcp_arguments(
 		&self,
 	) -> Option<Vec<PromptArgument>> {
@@ -80,22 +121,20 @@ impl Into<Tool> for ToolFunction {
 	fn into(self) -> Tool {
 		Tool {
 			function: FunctionTool {
+				parameters: self.parameters(),
 				name: self.name,
 				description: self.description,
-				parameters: self
-					.args
-					.iter()
-					.map(|x| Into::<serde_json::Value>::into(x.clone()))
-					.collect(),
 			},
-			tool_type: "".into(),
+			tool_type: "function".into(),
 		}
 	}
 }
 
 impl Into<serde_json::Value> for ToolArgument {
 	fn into(self) -> serde_json::Value {
-		serde_json::to_value(self).unwrap()
+		let mut schema = self.kind.schema();
+		schema["description"] = self.description.into();
+		schema
 	}
 }
 
@@ -129,8 +168,50 @@ impl Into<PromptArgument> for ToolArgument {
 	}
 }
 
+// arguments shared by the paginated history tools, see super::history::HistoryWindow
+fn history_arguments(name: &str) -> Vec<ToolArgument> {
+	vec![
+		ToolArgument {
+			name: "name".to_string(),
+			description: name.to_string(),
+			required: true,
+			kind: ArgumentKind::String,
+		},
+		ToolArgument {
+			name: "cursor".to_string(),
+			description: "The next_cursor value of a previous call, to continue paging into older messages".to_string(),
+			required: false,
+			kind: ArgumentKind::String,
+		},
+		ToolArgument {
+			name: "since".to_string(),
+			description: "Only messages sent at or after this RFC 3339 timestamp".to_string(),
+			required: false,
+			kind: ArgumentKind::String,
+		},
+		ToolArgument {
+			name: "until".to_string(),
+			description: "Only messages sent before this RFC 3339 timestamp".to_string(),
+			required: false,
+			kind: ArgumentKind::String,
+		},
+		ToolArgument {
+			name: "limit".to_string(),
+			description: "The maximum number of messages to return, 20 by default and at most 100".to_string(),
+			required: false,
+			kind: ArgumentKind::Integer,
+		},
+		ToolArgument {
+			name: "keywords".to_string(),
+			description: "Only messages containing at least one of these keywords".to_string(),
+			required: false,
+			kind: ArgumentKind::StringList,
+		},
+	]
+}
+
 pub(crate) fn tool_list() -> ToolList {
-	ToolList(vec![
+	let mut list = ToolList(vec![
         ToolFunction {
             name: "all_contacts".into(),
             description: "list of all contacts or friends".into(),
@@ -141,8 +222,9 @@ pub(crate) fn tool_list() -> ToolList {
             description: "information on a specific contact or friend".into(),
             args: vec![ToolArgument {
                 name: "name".to_string(),
-                description: "The name of the contact or friend".to_string(),
+                description: "The name of the contact or friend, a nickname or part of a name".to_string(),
                 required: true,
+                kind: ArgumentKind::String,
             }],
         },
         ToolFunction {
@@ -151,35 +233,53 @@ pub(crate) fn tool_list() -> ToolList {
                 .into(),
             args: vec![ToolArgument {
                 name: "name".to_string(),
-                description: "The name of the contact or friend".to_string(),
+                description: "The name of the contact or friend, a nickname or part of a name".to_string(),
                 required: true,
+                kind: ArgumentKind::String,
             }],
         },
         ToolFunction {
             name: "chat_messages".into(),
-            description: "recent chat messages with a friend or contact".into(),
-            args: vec![ToolArgument {
-                name: "name".to_string(),
-                description: "The name of the contact or friend".to_string(),
-                required: true,
-            }],
+            description: "chat messages with a friend or contact, newest first. Pass next_cursor back as cursor to page into older messages".into(),
+            args: history_arguments("The name of the contact or friend, a nickname or part of a name"),
         },
         ToolFunction {
             name: "group_chat".into(),
-            description: "recent messages inside a group chat".into(),
-            args: vec![ToolArgument {
-                name: "name".to_string(),
-                description: "The name of the group".to_string(),
-                required: true,
-            }],
+            description: "messages inside a group chat, newest first. Pass next_cursor back as cursor to page into older messages".into(),
+            args: history_arguments("The name of the group"),
+        },
+        ToolFunction {
+            name: "search_messages".into(),
+            description: "search contacts, groups and chat messages for words or topics, best matches first".into(),
+            args: vec![
+                ToolArgument {
+                    name: "query".to_string(),
+                    description: "Words to look for in contacts, groups and chat messages".to_string(),
+                    required: true,
+                    kind: ArgumentKind::String,
+                },
+                ToolArgument {
+                    name: "kinds".to_string(),
+                    description: "Only results of these kinds: contact, group, message or group_message".to_string(),
+                    required: false,
+                    kind: ArgumentKind::StringList,
+                },
+                ToolArgument {
+                    name: "limit".to_string(),
+                    description: "The maximum number of results to return, 10 by default and at most 50".to_string(),
+                    required: false,
+                    kind: ArgumentKind::Integer,
+                },
+            ],
         },
         ToolFunction {
             name: "contact_activity".into(),
             description: "online activity information about a friend or contact".into(),
             args: vec![ToolArgument {
                 name: "name".to_string(),
-                description: "The name of the contact or friend".to_string(),
+                description: "The name of the contact or friend, a nickname or part of a name".to_string(),
                 required: true,
+                kind: ArgumentKind::String,
             }],
         },
         ToolFunction {
@@ -187,9 +287,83 @@ pub(crate) fn tool_list() -> ToolList {
             description: "status information about a friend or contact".into(),
             args: vec![ToolArgument {
                 name: "name".to_string(),
-                description: "The name of the contact or friend".to_string(),
+                description: "The name of the contact or friend, a nickname or part of a name".to_string(),
                 required: true,
+                kind: ArgumentKind::String,
             }],
         },
-    ])
+    ]);
+
+	list.0.extend(action_list().0);
+	list
+}
+
+// NOTE: these tools act on the user's behalf. The proxy never forwards a call to one of them
+// before the user approved it, see crate::api::server::ActionConfirmation.
+pub(crate) fn action_list() -> ToolList {
+	ToolList(vec![
+		ToolFunction {
+			name: "send_message".into(),
+			description: "send a chat message to a friend, contact or group. The user is asked to approve the message first".into(),
+			args: vec![
+				ToolArgument {
+					name: "name".to_string(),
+					description: "The name of the contact, friend or group to send the message to".to_string(),
+					required: true,
+					kind: ArgumentKind::String,
+				},
+				ToolArgument {
+					name: "body".to_string(),
+					description: "The text of the message".to_string(),
+					required: true,
+					kind: ArgumentKind::String,
+				},
+			],
+		},
+		ToolFunction {
+			name: "react_to_message".into(),
+			description: "react to a chat message. The user is asked to approve the reaction first".into(),
+			args: vec![
+				ToolArgument {
+					name: "message_id".to_string(),
+					description: "The id of the message, as returned by chat_messages or group_chat".to_string(),
+					required: true,
+					kind: ArgumentKind::String,
+				},
+				ToolArgument {
+					name: "reaction".to_string(),
+					description: "The reaction, usually a single emoji".to_string(),
+					required: true,
+					kind: ArgumentKind::String,
+				},
+			],
+		},
+		ToolFunction {
+			name: "create_group".into(),
+			description: "create a group chat with friends or contacts. The user is asked to approve the group first".into(),
+			args: vec![
+				ToolArgument {
+					name: "name".to_string(),
+					description: "The name of the new group".to_string(),
+					required: true,
+					kind: ArgumentKind::String,
+				},
+				ToolArgument {
+					name: "members".to_string(),
+					description: "The names of the contacts or friends to add to the group".to_string(),
+					required: true,
+					kind: ArgumentKind::StringList,
+				},
+			],
+		},
+	])
+}
+
+pub(crate) fn is_action(name: &str) -> bool {
+	action_list().0.iter().any(|x| x.name == name)
+}
+
+// one of the built-in tools, which the phone's registered ones normally are too
+pub(crate) fn is_tool(name: &str) -> bool {
+	tool_list().0.iter().any(|x| x.name == name)
 }
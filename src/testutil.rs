@@ -17,6 +17,8 @@ pub async fn start_api_server(
 	tokio::spawn(async move {
 		server.start_with_handle(handle).await.unwrap()
 	});
+	// requests made right away would find nothing listening yet
+	h.listening().await;
 	Ok(h)
 }
 
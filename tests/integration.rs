@@ -1,13 +1,24 @@
-use allelo_mcp::api::client::Client;
+use allelo_mcp::api::client::{
+	Client, McpServer, PromptEvent, PromptStream,
+};
 use allelo_mcp::api::llm::*;
 use allelo_mcp::api::server::{
 	Config, LogLevel, Prompt, PromptResponse,
 };
 use allelo_mcp::testutil::*;
-use reqwest_eventsource::Event;
 
 // NOTE: all server tests need a different port, because they are run in parallel
 
+// the next PromptResponse, skipping notices about the connection
+async fn next_response(r: &mut PromptStream) -> PromptResponse {
+	loop {
+		match r.next().await.unwrap().unwrap() {
+			PromptEvent::Response(x) => return x,
+			x => eprintln!("{:?}", x),
+		}
+	}
+}
+
 #[tokio::test]
 async fn test_server_tool_use() {
 	let handle = start_api_server(Config {
@@ -20,6 +31,7 @@ async fn test_server_tool_use() {
 			timeout: None,
 			force_tools: true,
 		}),
+		..Default::default()
 	})
 	.await
 	.unwrap();
@@ -33,74 +45,46 @@ async fn test_server_tool_use() {
 			.prompt(Prompt {
 				connection_id: Default::default(),
 				prompt: Some(prompt.into()),
+				..Default::default()
 			})
-			.await
 			.unwrap();
-		let x = r.recv().await.unwrap().unwrap();
-		assert!(matches!(x, Event::Open));
-
-		let x = r.recv().await.unwrap().unwrap();
-		assert!(matches!(x, Event::Message(_)));
 
-		let mut id: uuid::Uuid = Default::default();
-
-		if let Event::Message(m) = x {
-			let obj: PromptResponse =
-				serde_json::from_str(&m.data).unwrap();
-			assert!(matches!(obj, PromptResponse::Connection(_)));
-			if let PromptResponse::Connection(i) = obj {
-				id = i
-			}
-		}
+		let id = match next_response(&mut r).await {
+			PromptResponse::Connection(id) => id,
+			x => panic!("expected a connection, got {:?}", x),
+		};
 
 		let mut i = 0;
 
-		if let Event::Message(m) = r.recv().await.unwrap().unwrap() {
-			let obj: PromptResponse =
-				serde_json::from_str(&m.data).unwrap();
-			eprintln!(
-				"LLM response w/ tools for '{}': {}",
-				prompt, m.data
-			);
-			assert!(matches!(obj, PromptResponse::PromptResponse(_)));
-			i += 1;
-		}
+		let obj = next_response(&mut r).await;
+		eprintln!("LLM response w/ tools for '{}': {:?}", prompt, obj);
+		assert!(matches!(obj, PromptResponse::PromptResponse(_)));
+		i += 1;
 
 		assert!(i > 0);
 
-		r.close();
+		drop(r);
 
 		let mut r = client
 			.prompt(Prompt {
 				connection_id: Some(id),
 				prompt: Some(prompt.into()),
+				..Default::default()
 			})
-			.await
 			.unwrap();
 
-		let x = r.recv().await.unwrap().unwrap();
-		assert!(matches!(x, Event::Open));
-
-		let x = r.recv().await.unwrap().unwrap();
-		assert!(matches!(x, Event::Message(_)));
-
-		if let Event::Message(m) = x {
-			let obj: PromptResponse =
-				serde_json::from_str(&m.data).unwrap();
-			assert!(matches!(obj, PromptResponse::Connection(_)));
-			if let PromptResponse::Connection(conn_id) = obj {
-				assert_eq!(id, conn_id);
-			}
-		}
+		assert!(matches!(
+			next_response(&mut r).await,
+			PromptResponse::Connection(x) if x == id
+		));
 
 		let mut i = 0;
 
-		while let Some(Ok(Event::Message(m))) = r.recv().await {
-			let obj: PromptResponse =
-				serde_json::from_str(&m.data).unwrap();
+		while let Some(Ok(PromptEvent::Response(obj))) = r.next().await
+		{
 			eprintln!(
-				"LLM response w/ tools for '{}': {}",
-				prompt, m.data,
+				"LLM response w/ tools for '{}': {:?}",
+				prompt, obj,
 			);
 			assert!(matches!(obj, PromptResponse::PromptResponse(_)));
 			i += 1;
@@ -160,6 +144,7 @@ async fn test_real_server_prompt() {
 			timeout: None,
 			force_tools: false,
 		}),
+		..Default::default()
 	})
 	.await
 	.unwrap();
@@ -170,80 +155,47 @@ async fn test_real_server_prompt() {
 			.prompt(Prompt {
 				connection_id: Default::default(),
 				prompt: Some(prompt.into()),
+				..Default::default()
 			})
-			.await
 			.unwrap();
-		let x = r.recv().await.unwrap().unwrap();
-		assert!(matches!(x, Event::Open));
 
-		let x = r.recv().await.unwrap().unwrap();
-		assert!(matches!(x, Event::Message(_)));
-
-		let mut id: uuid::Uuid = Default::default();
-
-		if let Event::Message(m) = x {
-			let obj: PromptResponse =
-				serde_json::from_str(&m.data).unwrap();
-			assert!(matches!(obj, PromptResponse::Connection(_)));
-			if let PromptResponse::Connection(i) = obj {
-				id = i
-			}
-		}
+		let id = match next_response(&mut r).await {
+			PromptResponse::Connection(id) => id,
+			x => panic!("expected a connection, got {:?}", x),
+		};
 
 		let mut i = 0;
 
-		if let Event::Message(m) = r.recv().await.unwrap().unwrap() {
-			let obj: PromptResponse =
-				serde_json::from_str(&m.data).unwrap();
-			eprintln!(
-				"LLM response w/o tools for '{}': {}",
-				prompt, m.data
-			);
-			assert!(matches!(obj, PromptResponse::PromptResponse(_)));
-			i += 1;
-		}
+		let obj = next_response(&mut r).await;
+		eprintln!("LLM response w/o tools for '{}': {:?}", prompt, obj);
+		assert!(matches!(obj, PromptResponse::PromptResponse(_)));
+		i += 1;
 
 		assert!(i > 0);
 
-		r.close();
+		drop(r);
 
 		let mut r = client
 			.prompt(Prompt {
 				connection_id: Some(id),
 				prompt: Some(prompt.into()),
+				..Default::default()
 			})
-			.await
 			.unwrap();
 
-		let x = r.recv().await.unwrap().unwrap();
-		assert!(matches!(x, Event::Open));
-
-		let x = r.recv().await.unwrap().unwrap();
-		assert!(matches!(x, Event::Message(_)));
-
-		if let Event::Message(m) = x {
-			let obj: PromptResponse =
-				serde_json::from_str(&m.data).unwrap();
-			assert!(matches!(obj, PromptResponse::Connection(_)));
-			if let PromptResponse::Connection(conn_id) = obj {
-				assert_eq!(id, conn_id);
-			}
-		}
+		assert!(matches!(
+			next_response(&mut r).await,
+			PromptResponse::Connection(x) if x == id
+		));
 
 		let mut i = 0;
 
 		// NOTE: I guess this can fail if we only get one stream response from the LLM, but this is
 		// nearly impossible in my experience.
-		if let Event::Message(m) = r.recv().await.unwrap().unwrap() {
-			let obj: PromptResponse =
-				serde_json::from_str(&m.data).unwrap();
-			eprintln!(
-				"LLM response w/o tools for '{}': {}",
-				prompt, m.data
-			);
-			assert!(matches!(obj, PromptResponse::PromptResponse(_)));
-			i += 1;
-		}
+		let obj = next_response(&mut r).await;
+		eprintln!("LLM response w/o tools for '{}': {:?}", prompt, obj);
+		assert!(matches!(obj, PromptResponse::PromptResponse(_)));
+		i += 1;
 
 		assert!(i > 0);
 	}
@@ -254,3 +206,27 @@ async fn test_real_server_prompt() {
 
 	shutdown_handle(handle);
 }
+
+#[tokio::test]
+async fn test_client_stdio_server() {
+	let client = Client::with_mcp_server(
+		default_api_url(),
+		&McpServer::Stdio {
+			command: env!("CARGO_BIN_EXE_allelo-mcp").into(),
+			args: vec!["stdio".into()],
+			env: Default::default(),
+		},
+	)
+	.await
+	.unwrap();
+
+	assert!(client.tools().iter().any(|x| x.name == "contact_info"));
+
+	let response = client
+		.call_mcp(
+			r#"{"jsonrpc":"2.0","id":1,"method":"tools/call","params":{"name":"all_contacts","arguments":{}}}"#,
+		)
+		.await
+		.unwrap();
+	assert!(response.contains(r#""id":1"#));
+}